@@ -0,0 +1,103 @@
+use serde::Serialize;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Alert {
+    // The bus cannot reach its target by its deadline even at the maximum rate
+    InfeasibleDeadline {
+        charger_id:       String,
+        connector_id:     i32,
+        transaction_id:   i32,
+        id_tag:           String,
+        deadline:         DateTime<Utc>,
+        required_w:       Option<f32>,  // None once the deadline has passed
+        max_w:            f32,
+        shortfall_kwh:    f32,
+        shortfall_soc:    f32,          // percentage points short of the target at departure
+        expected_soc:     Option<f32>,  // SOC expected at departure, if the bus reports SOC
+    },
+    // The charger's composite schedule still differs from the profile we sent after every retry
+    ScheduleMismatch {
+        charger_id:       String,
+        connector_id:     i32,
+        transaction_id:   i32,
+        start_period:     i32,          // seconds into the profile where the schedules first differ
+        expected_w:       f32,
+        applied_w:        Option<f32>,  // None if the charger has no limit in effect there
+        attempts:         u32,
+    },
+    // Chargerhub stopped answering, the profiles already accepted are left in place until it is back
+    ChargerHubUnavailable {
+        since:                DateTime<Utc>,
+        consecutive_failures: u32,
+        last_error:           String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct AlertRecord<'a> {
+    raised_at: DateTime<Utc>,
+    #[serde(flatten)]
+    alert:     &'a Alert,
+}
+
+pub async fn raise_alert(client: &Client, webhook_url: Option<&str>, alert: &Alert) {
+    /*
+     * Log an alert as a single line of JSON so it can be picked out of the
+     * service's output, and forward it to the alert webhook if one is set.
+     */
+    let record = AlertRecord { raised_at: Utc::now(), alert };
+    let body = serde_json::to_string(&record).unwrap_or_else(|err| format!("{{\"error\": \"unable to serialize alert: {err}\"}}"));
+    eprintln!("ALERT {body}");
+
+    if let Some(webhook_url) = webhook_url {
+        if let Err(err) = client.post(webhook_url).json(&record).send().await {
+            eprintln!("Unable to forward alert to {webhook_url}: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http;
+
+    fn infeasible() -> Alert {
+        Alert::InfeasibleDeadline {
+            charger_id:     String::from("CH1"),
+            connector_id:   1,
+            transaction_id: 7,
+            id_tag:         String::from("BUS1"),
+            deadline:       "2026-10-19T05:00:00Z".parse().unwrap(),
+            required_w:     Some(180_000.0),
+            max_w:          150_000.0,
+            shortfall_kwh:  30.0,
+            shortfall_soc:  6.8,
+            expected_soc:   Some(93.2),
+        }
+    }
+
+    #[tokio::test]
+    async fn alerts_are_posted_to_the_webhook() {
+        let (webhook_url, received) = test_http::serve(|_| (204, String::new())).await;
+        raise_alert(&Client::new(), Some(&format!("{webhook_url}/alerts")), &infeasible()).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!((received[0].method.as_str(), received[0].target.as_str()), ("POST", "/alerts"));
+        let posted: serde_json::Value = serde_json::from_str(&received[0].body).unwrap();
+        assert_eq!(posted["kind"], "infeasible_deadline");
+        assert_eq!(posted["transaction_id"], 7);
+        assert_eq!(posted["shortfall_kwh"], 30.0);
+        assert!(posted["raised_at"].is_string());
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_webhook_only_loses_the_forward() {
+        // nothing listens on the discard port, the alert is still logged
+        raise_alert(&Client::new(), Some("http://127.0.0.1:9/alerts"), &infeasible()).await;
+        raise_alert(&Client::new(), None, &infeasible()).await;
+    }
+}
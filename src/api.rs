@@ -0,0 +1,234 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::{header::{AUTHORIZATION, WWW_AUTHENTICATE}, Method},
+    middleware::{from_fn, Next},
+    post, web, App, HttpResponse, HttpServer, Responder
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use crate::{
+    demand_response::{DrEvent, DrEventStore},
+    error::Error,
+    fleet_state::{FleetState, StatusNotification},
+    types::{MeterValue, Transaction}
+};
+
+// Where the API is served. Chargerhub reaches it from outside, so every request
+// which changes what the loop plans from must carry the token as a bearer token
+pub struct ApiConfig {
+    pub bind_addr: String,
+    pub token:     String,
+}
+
+pub struct ApiState {
+    pub dr_events: Arc<DrEventStore>,
+    pub fleet:     Arc<FleetState>,   // connector state pushed through the chargerhub webhooks
+    pub wake:      Arc<Notify>,       // wakes the run loop so it re-plans right away
+    pub token:     String,            // bearer token every POST must carry
+}
+
+async fn require_token(request: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    /*
+     * Turn away any request which could change what the loop plans from, IE
+     * every POST, unless it carries "Authorization: Bearer <API_TOKEN>". This
+     * runs before the handler reads the body.
+     */
+    let authorized = request.method() == Method::GET || request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .zip(request.app_data::<web::Data<ApiState>>())
+        .is_some_and(|(bearer, state)| bearer == state.token);
+
+    if authorized {
+        return next.call(request).await.map(ServiceResponse::map_into_left_body);
+    }
+    eprintln!("Rejected unauthorized {} {} from {}", request.method(), request.path(), request.peer_addr().map(|addr| addr.to_string()).unwrap_or_default());
+    let response = HttpResponse::Unauthorized().insert_header((WWW_AUTHENTICATE, "Bearer")).finish();
+    Ok(request.into_response(response).map_into_right_body())
+}
+
+#[post("/dr-events")]
+async fn add_dr_event(state: web::Data<ApiState>, event: web::Json<DrEvent>) -> impl Responder {
+    let event = event.into_inner();
+    if let Err(err) = event.validate() {
+        return HttpResponse::BadRequest().body(err.to_string());
+    }
+
+    match state.dr_events.add(event) {
+        Ok(()) => {
+            state.wake.notify_one();
+            HttpResponse::Accepted().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEventKind {
+    Started,
+    Stopped,
+}
+
+// Pushed by chargerhub (or anything else watching the chargers) when a bus
+// connects or disconnects, so the run loop does not wait for its next poll
+#[derive(Debug, Deserialize)]
+pub struct SessionEvent {
+    pub charger_id:   String,
+    pub connector_id: i32,
+    pub event:        SessionEventKind,
+}
+
+#[post("/session-events")]
+async fn add_session_event(state: web::Data<ApiState>, event: web::Json<SessionEvent>) -> impl Responder {
+    // the event carries no transaction or meter value for the fleet state, so the loop
+    // is asked to poll the location in full as soon as it wakes
+    println!("Session {:?} on {} - {} pushed through the API", event.event, event.charger_id, event.connector_id);
+    state.fleet.reconcile_now();
+    state.wake.notify_one();
+    HttpResponse::Accepted().finish()
+}
+
+// Chargerhub webhooks. Meter values only update the fleet state, the loop picks
+// them up on its next pass, unless one completes a new session. Statuses and
+// transactions can start or end a session so they wake the loop
+
+#[post("/webhooks/meter-values")]
+async fn push_meter_values(state: web::Data<ApiState>, meter_values: web::Json<Vec<MeterValue>>) -> impl Responder {
+    for meter_value in meter_values.into_inner() {
+        if state.fleet.record_meter_value(meter_value) {
+            state.wake.notify_one();
+        }
+    }
+    HttpResponse::Accepted().finish()
+}
+
+#[post("/webhooks/status-notifications")]
+async fn push_status_notification(state: web::Data<ApiState>, notification: web::Json<StatusNotification>) -> impl Responder {
+    println!("{} - {} reported {:?}{}{}",
+        notification.charger_id,
+        notification.connector_id,
+        notification.status,
+        notification.error_code.as_ref().map(|error_code| format!(" ({error_code})")).unwrap_or_default(),
+        notification.timestamp.map(|timestamp| format!(" at {timestamp}")).unwrap_or_default()
+    );
+    state.fleet.record_status(&notification);
+    state.wake.notify_one();
+    HttpResponse::Accepted().finish()
+}
+
+#[post("/webhooks/transactions")]
+async fn push_transaction(state: web::Data<ApiState>, transaction: web::Json<Transaction>) -> impl Responder {
+    match state.fleet.record_transaction(transaction.into_inner()) {
+        Ok(()) => {
+            state.wake.notify_one();
+            HttpResponse::Accepted().finish()
+        }
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[get("/dr-events")]
+async fn list_dr_events(state: web::Data<ApiState>) -> impl Responder {
+    HttpResponse::Ok().json(state.dr_events.all())
+}
+
+pub fn start_api(bind_addr: &str, state: ApiState) -> Result<(), Error> {
+    /*
+     * Start the HTTP API on its own task so it keeps serving while the run
+     * loop sleeps between recalculations.
+     */
+    let state = web::Data::new(state);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .wrap(from_fn(require_token))
+            .service(add_dr_event)
+            .service(list_dr_events)
+            .service(add_session_event)
+            .service(push_meter_values)
+            .service(push_status_notification)
+            .service(push_transaction)
+    })
+    .workers(1)
+    .bind(bind_addr)
+    .map_err(|err| Error::Config(format!("Unable to start the API on API_BIND_ADDR {bind_addr}: {err}")))?
+    .run();
+
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use super::*;
+    use crate::get_data::MeterValueFetch;
+
+    fn api_state() -> web::Data<ApiState> {
+        web::Data::new(ApiState {
+            dr_events: Arc::new(DrEventStore::new(None)),
+            fleet:     Arc::new(FleetState::new()),
+            wake:      Arc::new(Notify::new()),
+            token:     String::from("secret"),
+        })
+    }
+
+    #[actix_web::test]
+    async fn posts_need_the_api_token() {
+        let state = api_state();
+        let app = test::init_service(App::new()
+            .app_data(state.clone())
+            .wrap(from_fn(require_token))
+            .service(add_dr_event)
+            .service(list_dr_events)
+            .service(add_session_event)
+        ).await;
+        let event = serde_json::json!({
+            "id": "zero",
+            "start": chrono::Utc::now(),
+            "end": chrono::Utc::now() + chrono::Duration::hours(1),
+            "limit": { "kw": 0.0 },
+        });
+
+        let missing = test::TestRequest::post().uri("/dr-events").set_json(&event).to_request();
+        assert_eq!(test::call_service(&app, missing).await.status(), StatusCode::UNAUTHORIZED);
+        let wrong = test::TestRequest::post().uri("/dr-events").insert_header((AUTHORIZATION, "Bearer guess")).set_json(&event).to_request();
+        assert_eq!(test::call_service(&app, wrong).await.status(), StatusCode::UNAUTHORIZED);
+        let session = test::TestRequest::post().uri("/session-events").set_json(serde_json::json!({ "charger_id": "CH1", "connector_id": 1, "event": "started" })).to_request();
+        assert_eq!(test::call_service(&app, session).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(state.dr_events.all().is_empty());
+
+        let authorized = test::TestRequest::post().uri("/dr-events").insert_header((AUTHORIZATION, "Bearer secret")).set_json(&event).to_request();
+        assert_eq!(test::call_service(&app, authorized).await.status(), StatusCode::ACCEPTED);
+        let list = test::TestRequest::get().uri("/dr-events").to_request();
+        assert_eq!(test::call_service(&app, list).await.status(), StatusCode::OK);
+        assert_eq!(state.dr_events.all().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn session_events_wake_the_run_loop() {
+        let state = api_state();
+        let app = test::init_service(App::new()
+            .app_data(state.clone())
+            .wrap(from_fn(require_token))
+            .service(add_session_event)
+        ).await;
+        state.fleet.reconcile(Vec::new(), MeterValueFetch { sessions: Vec::new(), idle: Vec::new(), unavailable: Vec::new() });
+        assert!(!state.fleet.reconcile_due(std::time::Duration::from_secs(3600)));
+
+        let event = test::TestRequest::post()
+            .uri("/session-events")
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .set_json(serde_json::json!({ "charger_id": "CH1", "connector_id": 1, "event": "stopped" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, event).await.status(), StatusCode::ACCEPTED);
+        tokio::time::timeout(std::time::Duration::from_secs(1), state.wake.notified()).await.expect("the run loop was not woken");
+        assert!(state.fleet.reconcile_due(std::time::Duration::from_secs(3600)));
+    }
+}
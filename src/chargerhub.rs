@@ -0,0 +1,537 @@
+use reqwest::{Client, RequestBuilder, Response, header::{HeaderValue, CONTENT_TYPE, AUTHORIZATION}};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{collections::hash_map::RandomState, future::Future, hash::{BuildHasher, Hasher}, time::Duration};
+use tokio::time::{sleep, timeout};
+use crate::{
+    circuit_breaker::{CircuitBreaker, Degraded},
+    config::Config,
+    error::{ChargerStatus, Error},
+    profile_registry::OwnedProfile,
+    send_data::SEND_TIMEOUT,
+    types::{Charger, ChargeProfile, CompositeSchedule, MeterValue, Transaction}
+};
+
+// Everything the service asks of chargerhub. The run loop only talks to
+// chargerhub through this, so it can run against the in-memory fake as well
+// as a live server.
+pub trait ChargerHubApi: Send + Sync + 'static {
+    // every charger chargerhub knows of, at any location
+    fn chargers(&self) -> impl Future<Output = Result<Vec<Charger>, Error>> + Send;
+
+    // the newest meter value reported on a connector, None if it has never reported one
+    fn latest_meter_value(&self, charger_id: &str, connector_id: i32) -> impl Future<Output = Result<Option<MeterValue>, Error>> + Send;
+
+    // the newest transaction on a connector, whether or not it has ended
+    fn latest_transaction(&self, charger_id: &str, connector_id: i32) -> impl Future<Output = Result<Option<Transaction>, Error>> + Send;
+
+    // one attempt at installing a profile, retries are left to the caller
+    fn set_charge_profile(&self, profile: &ChargeProfile, valid_to: DateTime<Utc>) -> impl Future<Output = Result<(), Error>> + Send;
+
+    // clear the profile at the owned profile's purpose and stack level only
+    fn clear_charge_profile(&self, profile: &OwnedProfile) -> impl Future<Output = Result<(), Error>> + Send;
+
+    // the schedule a connector will follow over the next duration seconds, in watts
+    fn composite_schedule(&self, charger_id: &str, connector_id: i32, duration: i32) -> impl Future<Output = Result<CompositeSchedule, Error>> + Send;
+
+    // Some while chargerhub has stopped answering and requests are not being sent
+    fn degraded(&self) -> Option<Degraded>;
+}
+
+// Where a chargerhub deployment expects the filters of a read. Older
+// deployments take a JSON body on GET, which many proxies drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestEncoding {
+    JsonBody,
+    QueryParams,
+}
+
+// How a chargerhub deployment pages its list endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pagination {
+    None,                         // the whole list in one response
+    Cursor { page_size: usize },  // {"data": [...], "next_cursor": ...}, the cursor is sent back as `cursor`
+    Offset { page_size: usize },  // plain lists asked for with `offset` and `limit`, until a short page
+}
+
+// A page of a cursor paginated list
+#[derive(Debug, Deserialize)]
+struct CursorPage<T> {
+    data:        Vec<T>,
+    next_cursor: Option<String>,
+}
+
+// Guards against a deployment which keeps handing out cursors
+const MAX_PAGES: usize = 100;
+
+// Chargerhub's REST API. Reads are retried with jittered backoff, and every
+// request goes through a circuit breaker so an outage fails fast.
+pub struct ChargerHubClient {
+    client:           Client,
+    req_url:          String,
+    auth_key:         String,
+    verbose_mode:     bool,
+    request_timeout:  Duration,   // for the meter value and transaction reads
+    read_retries:     u32,
+    retry_backoff:    Duration,   // doubled after every failed read, before jitter
+    breaker:          CircuitBreaker,
+    request_encoding: RequestEncoding,
+    pagination:       Pagination,
+}
+
+impl ChargerHubClient {
+    pub fn new(client: Client, url: &str, authorization_header: &str, config: &Config) -> ChargerHubClient {
+        ChargerHubClient {
+            client,
+            req_url:          url.to_owned(),
+            auth_key:         authorization_header.to_owned(),
+            verbose_mode:     config.verbose_mode,
+            request_timeout:  config.request_timeout,
+            read_retries:     config.read_retries,
+            retry_backoff:    config.retry_backoff,
+            breaker:          CircuitBreaker::new(config.breaker_failures, config.breaker_reset),
+            request_encoding: config.request_encoding,
+            pagination:       config.pagination,
+        }
+    }
+
+    async fn read(&self, context: &str, request: impl Fn() -> RequestBuilder) -> Result<String, Error> {
+        /*
+         * Send an idempotent read and return its body, retrying failures which
+         * chargerhub might recover from (timeouts, dropped connections, 429 and
+         * 5xx) with exponential backoff. The backoff is jittered so connectors
+         * fetched together do not all retry at the same moment.
+         */
+        let mut attempt = 0;
+        loop {
+            self.breaker.allow()?;
+            let err = match response_body(context, request().send().await).await {
+                Ok(body) => {
+                    self.breaker.record_success();
+                    return Ok(body);
+                }
+                Err(err) => err,
+            };
+
+            if !err.is_retryable() {
+                // chargerhub answered, it just did not like the request
+                self.breaker.record_success();
+                return Err(err);
+            }
+            self.breaker.record_failure(&err);
+            if attempt >= self.read_retries {
+                return Err(err);
+            }
+
+            let backoff = (self.retry_backoff * 2u32.pow(attempt)).mul_f64(0.5 + jitter() / 2.0);
+            if self.verbose_mode {
+                println!("{err}, retrying in {}ms", backoff.as_millis());
+            }
+            sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn list<T: DeserializeOwned>(&self, context: &str, url: &str, filters: Value, limit: Option<usize>) -> Result<Vec<T>, Error> {
+        /*
+         * Read a list endpoint, encoding the filters the way the deployment
+         * expects and following its pagination until the list, or the first
+         * limit items of it, has been read.
+         */
+        let mut items: Vec<T> = Vec::new();
+        let mut cursor: Option<String> = None;
+
+        for _ in 0..MAX_PAGES {
+            let remaining = limit.map(|limit| limit - items.len());
+            let mut params = filters.clone();
+            match self.pagination {
+                Pagination::None => {
+                    if let Some(remaining) = remaining {
+                        params["limit"] = json!(remaining);
+                    }
+                }
+                Pagination::Cursor { page_size } => {
+                    params["limit"] = json!(remaining.map_or(page_size, |remaining| remaining.min(page_size)));
+                    if let Some(cursor) = &cursor {
+                        params["cursor"] = json!(cursor);
+                    }
+                }
+                Pagination::Offset { page_size } => {
+                    params["limit"] = json!(remaining.map_or(page_size, |remaining| remaining.min(page_size)));
+                    params["offset"] = json!(items.len());
+                }
+            }
+
+            let body = self.read(context, || self.list_request(url, &params)).await?;
+            let (page, next_cursor) = match self.pagination {
+                Pagination::Cursor { .. } => {
+                    let page: CursorPage<T> = serde_json::from_str(&body).map_err(|err| Error::decode(context, &body, err))?;
+                    (page.data, page.next_cursor)
+                }
+                _ => (serde_json::from_str::<Vec<T>>(&body).map_err(|err| Error::decode(context, &body, err))?, None),
+            };
+
+            let page_len = page.len();
+            items.extend(page);
+            if let Some(limit) = limit {
+                if items.len() >= limit {
+                    items.truncate(limit);
+                    return Ok(items);
+                }
+            }
+            match self.pagination {
+                Pagination::None => return Ok(items),
+                Pagination::Cursor { .. } => match next_cursor {
+                    Some(next_cursor) if page_len > 0 => cursor = Some(next_cursor),
+                    _ => return Ok(items),
+                },
+                Pagination::Offset { page_size } => {
+                    if page_len < page_size {
+                        return Ok(items);
+                    }
+                }
+            }
+        }
+        Err(Error::Decode { context: context.to_owned(), message: format!("list did not end after {MAX_PAGES} pages") })
+    }
+
+    fn list_request(&self, url: &str, params: &Value) -> RequestBuilder {
+        // a GET with the filters in a JSON body, or in the query string for deployments behind a proxy
+        let request = self.client
+            .get(url)
+            .timeout(self.request_timeout)
+            .header(AUTHORIZATION, format!("Bearer {}", self.auth_key));
+        match self.request_encoding {
+            RequestEncoding::JsonBody => request
+                .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+                .body(params.to_string()),
+            RequestEncoding::QueryParams => request.query(&query_pairs(params)),
+        }
+    }
+
+    fn record_outcome<T>(&self, result: &Result<T, Error>) {
+        // the charger refusing a command still means chargerhub is up
+        match result {
+            Err(err) if err.is_retryable() => self.breaker.record_failure(err),
+            _ => self.breaker.record_success(),
+        }
+    }
+
+    async fn send_command(&self, context: &str, url: String, command: &serde_json::Value, accepted: &[&str]) -> Result<(), Error> {
+        /*
+         * POST a command to chargerhub and turn the charger's OCPP status, IE
+         * {"status": "Accepted"}, into a result. Statuses in accepted count as
+         * the command having taken effect.
+         */
+        let response = timeout(SEND_TIMEOUT, self.client
+            .post(url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.auth_key))
+            .json(command)
+            .send())
+            .await
+            .map_err(|_| Error::Transport {
+                context:   context.to_owned(),
+                message:   format!("no answer after {}s", SEND_TIMEOUT.as_secs()),
+                timed_out: true,
+            })?;
+        let body = response_body(context, response).await?;
+
+        let ocpp_status = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| body.get("status").and_then(|status| status.as_str()).map(str::to_owned))
+            .unwrap_or(body);
+        match ocpp_status.as_str() {
+            status if accepted.contains(&status) => Ok(()),
+            other => Err(Error::ChargerRejected(ChargerStatus::from_ocpp(other))),
+        }
+    }
+}
+
+async fn response_body(context: &str, response: reqwest::Result<Response>) -> Result<String, Error> {
+    /*
+     * The body of a successful response, or the error status along with the
+     * body chargerhub explained it in.
+     */
+    let response = response.map_err(|err| Error::from_reqwest(context, err))?;
+    let status = response.status();
+    let body = response.text().await.map_err(|err| Error::from_reqwest(context, err))?;
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(Error::Http { context: context.to_owned(), status: status.as_u16(), body })
+    }
+}
+
+fn query_pairs(params: &Value) -> Vec<(String, String)> {
+    /*
+     * Flatten a JSON object of filters into query parameters, leaving strings
+     * unquoted, IE {"charger_id": "CH1", "limit": 1} -> charger_id=CH1&limit=1.
+     */
+    params
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(name, value)| match value {
+            Value::String(value) => (name.clone(), value.clone()),
+            other => (name.clone(), other.to_string()),
+        })
+        .collect()
+}
+
+fn jitter() -> f64 {
+    /*
+     * A number in [0, 1) for spreading out retries. Every RandomState is seeded
+     * differently, which is random enough for this without pulling in a crate.
+     */
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+impl ChargerHubApi for ChargerHubClient {
+    async fn chargers(&self) -> Result<Vec<Charger>, Error> {
+        let charger_url_path = format!("{}/data/chargers", self.req_url);
+
+        // Print headers before sending the request, leaving out the authorization header
+        if self.verbose_mode {
+            println!("Headers Sent:");
+            println!("{}: {:?}", CONTENT_TYPE, "application/json");
+        }
+        self.list("unable to fetch chargers", &charger_url_path, json!({}), None).await
+    }
+
+    async fn latest_meter_value(&self, charger_id: &str, connector_id: i32) -> Result<Option<MeterValue>, Error> {
+        let filters = json!({
+            "charger_id": charger_id,
+            "descending": true,
+            "connector_id": connector_id
+        });
+        // one meter val for each connector
+        let mut meter_val: Vec<MeterValue> = self.list("unable to fetch meter values", &format!("{}/data/meter-values", self.req_url), filters, Some(1)).await?;
+
+        if self.verbose_mode {
+            println!("{:#?}", meter_val);
+        }
+        Ok((!meter_val.is_empty()).then(|| meter_val.swap_remove(0)))
+    }
+
+    async fn latest_transaction(&self, charger_id: &str, connector_id: i32) -> Result<Option<Transaction>, Error> {
+        let filters = json!({
+            "connector_id": connector_id
+        });
+        let mut transaction_data: Vec<Transaction> = self.list("unable to fetch transactions", &format!("{}/data/{}/transactions", self.req_url, charger_id), filters, Some(1)).await?;
+
+        if self.verbose_mode {
+            println!("{:#?}", transaction_data);
+        }
+        Ok((!transaction_data.is_empty()).then(|| transaction_data.swap_remove(0)))
+    }
+
+    async fn set_charge_profile(&self, profile: &ChargeProfile, valid_to: DateTime<Utc>) -> Result<(), Error> {
+        let url = format!("{}/command/{}/set-charge-profile", self.req_url, profile.charger_id);
+
+        // bound to the transaction so it never outlives the session or touches another system's default profile
+        let charge_profile = json!({
+            "connector_id": profile.connector_id,
+            "transaction_id": profile.transaction_id,
+            "start_periods": profile.start_periods,
+            "stack_level": profile.stack_level,
+            "charge_rates": profile.charge_rates,
+            "purpose": profile.purpose,
+            "valid_to": valid_to,
+            "start_schedule": profile.start_schedule,
+        });
+
+        if self.verbose_mode {
+            println!("charge profile created: {}", charge_profile);
+        }
+
+        self.breaker.allow()?;
+        let result = self.send_command("unable to send the charge profile", url, &charge_profile, &["Accepted"]).await;
+        self.record_outcome(&result);
+        result
+    }
+
+    async fn clear_charge_profile(&self, profile: &OwnedProfile) -> Result<(), Error> {
+        let url = format!("{}/command/{}/clear-charge-profile", self.req_url, profile.charger_id);
+
+        let clear_request = json!({
+            "connector_id": profile.connector_id,
+            "purpose": "TxProfile",
+            "stack_level": profile.stack_level,
+        });
+
+        if self.verbose_mode {
+            println!("clearing charge profile: {}", clear_request);
+        }
+
+        // Unknown means there was no such profile left to clear
+        self.breaker.allow()?;
+        let result = self.send_command("unable to clear the charge profile", url, &clear_request, &["Accepted", "Unknown"]).await;
+        self.record_outcome(&result);
+        result
+    }
+
+    async fn composite_schedule(&self, charger_id: &str, connector_id: i32, duration: i32) -> Result<CompositeSchedule, Error> {
+        let url = format!("{}/command/{}/get-composite-schedule", self.req_url, charger_id);
+
+        // a POST, but only reads the schedule so it is as safe to retry as the GETs
+        let body = self.read("unable to read the composite schedule", || self.client
+            .post(&url)
+            .timeout(self.request_timeout)
+            .header(AUTHORIZATION, format!("Bearer {}", self.auth_key))
+            .json(&json!({
+                "connector_id": connector_id,
+                "duration": duration,
+                "charging_rate_unit": "W",
+            }))
+        ).await?;
+        let composite_schedule: CompositeSchedule = serde_json::from_str(&body)
+            .map_err(|err| Error::decode("unable to parse the composite schedule", &body, err))?;
+
+        if self.verbose_mode {
+            println!("composite schedule for {} - {}: {:?}", charger_id, connector_id, composite_schedule);
+        }
+        Ok(composite_schedule)
+    }
+
+    fn degraded(&self) -> Option<Degraded> {
+        self.breaker.degraded()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::test_http;
+
+    const CHARGERS: &str = r#"[{"id": "CH1", "charger_name": "Bay 1", "location_id": 7, "communicate_through": "RustDirectOcpp", "latitude": null, "longitude": null, "created_at": "2026-10-18T20:00:00Z"}]"#;
+
+    fn chargerhub(url: &str) -> ChargerHubClient {
+        ChargerHubClient {
+            client:           Client::new(),
+            req_url:          url.to_owned(),
+            auth_key:         String::from("secret"),
+            verbose_mode:     false,
+            request_timeout:  Duration::from_secs(5),
+            read_retries:     2,
+            retry_backoff:    Duration::from_millis(1),
+            breaker:          CircuitBreaker::new(2, Duration::from_secs(3600)),
+            request_encoding: RequestEncoding::JsonBody,
+            pagination:       Pagination::None,
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_reads_are_retried_until_chargerhub_answers() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let (url, received) = test_http::serve(|_| match ATTEMPTS.fetch_add(1, Ordering::SeqCst) {
+            0 => (503, String::from("restarting")),
+            _ => (200, String::from(CHARGERS)),
+        }).await;
+        let chargerhub = chargerhub(&url);
+
+        let chargers = chargerhub.chargers().await.unwrap();
+        assert_eq!(chargers[0].id, "CH1");
+        assert_eq!(received.lock().unwrap().len(), 2);
+        // the read got through, so the failure before it no longer counts towards an outage
+        chargerhub.breaker.record_failure(&Error::Http { context: String::from("later"), status: 503, body: String::new() });
+        assert!(chargerhub.degraded().is_none());
+    }
+
+    #[tokio::test]
+    async fn requests_chargerhub_refuses_are_not_retried() {
+        let (url, received) = test_http::serve(|_| (404, String::from("no such location"))).await;
+        let chargerhub = chargerhub(&url);
+
+        assert!(matches!(chargerhub.chargers().await, Err(Error::Http { status: 404, .. })));
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert!(chargerhub.degraded().is_none());
+    }
+
+    #[tokio::test]
+    async fn an_outage_opens_the_breaker_and_stops_sending_requests() {
+        let (url, received) = test_http::serve(|_| (503, String::from("down"))).await;
+        let chargerhub = chargerhub(&url);
+
+        assert!(matches!(chargerhub.chargers().await, Err(Error::Unavailable(_))));
+        assert_eq!(received.lock().unwrap().len(), 2);
+        let degraded = chargerhub.degraded().unwrap();
+        assert_eq!(degraded.consecutive_failures, 2);
+        assert_eq!(degraded.last_error, "unable to fetch chargers: answered 503: down");
+
+        assert!(matches!(chargerhub.latest_meter_value("CH1", 1).await, Err(Error::Unavailable(_))));
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    fn charger_list(ids: &[&str]) -> String {
+        let chargers: Vec<Value> = ids
+            .iter()
+            .map(|id| json!({"id": id, "charger_name": id, "location_id": 7, "communicate_through": "RustDirectOcpp", "latitude": null, "longitude": null, "created_at": "2026-10-18T20:00:00Z"}))
+            .collect();
+        Value::from(chargers).to_string()
+    }
+
+    #[tokio::test]
+    async fn filters_go_in_the_body_or_the_query_string() {
+        let (url, received) = test_http::serve(|_| (200, String::from("[]"))).await;
+        let mut chargerhub = chargerhub(&url);
+
+        assert!(chargerhub.latest_meter_value("CH1", 2).await.unwrap().is_none());
+        chargerhub.request_encoding = RequestEncoding::QueryParams;
+        assert!(chargerhub.latest_transaction("CH1", 2).await.unwrap().is_none());
+
+        let received = received.lock().unwrap();
+        assert_eq!((received[0].method.as_str(), received[0].target.as_str()), ("GET", "/data/meter-values"));
+        assert_eq!(serde_json::from_str::<Value>(&received[0].body).unwrap(), json!({"charger_id": "CH1", "connector_id": 2, "descending": true, "limit": 1}));
+        assert_eq!((received[1].method.as_str(), received[1].target.as_str()), ("GET", "/data/CH1/transactions?connector_id=2&limit=1"));
+        assert_eq!(received[1].body, "");
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_are_followed_until_there_is_no_next_cursor() {
+        let (url, received) = test_http::serve(|request| match request.target.contains("cursor=") {
+            false => (200, format!(r#"{{"data": {}, "next_cursor": "page2"}}"#, charger_list(&["CH1", "CH2"]))),
+            true => (200, format!(r#"{{"data": {}, "next_cursor": null}}"#, charger_list(&["CH3"]))),
+        }).await;
+        let mut chargerhub = chargerhub(&url);
+        chargerhub.request_encoding = RequestEncoding::QueryParams;
+        chargerhub.pagination = Pagination::Cursor { page_size: 2 };
+
+        let chargers = chargerhub.chargers().await.unwrap();
+        assert_eq!(chargers.iter().map(|charger| charger.id.as_str()).collect::<Vec<_>>(), ["CH1", "CH2", "CH3"]);
+        let targets: Vec<String> = received.lock().unwrap().iter().map(|request| request.target.clone()).collect();
+        assert_eq!(targets, ["/data/chargers?limit=2", "/data/chargers?cursor=page2&limit=2"]);
+    }
+
+    #[tokio::test]
+    async fn offset_pages_are_read_until_a_short_page() {
+        let (url, received) = test_http::serve(|request| match request.target.as_str() {
+            "/data/chargers?limit=2&offset=0" => (200, charger_list(&["CH1", "CH2"])),
+            "/data/chargers?limit=2&offset=2" => (200, charger_list(&["CH3"])),
+            _ => (400, String::from("unexpected page")),
+        }).await;
+        let mut chargerhub = chargerhub(&url);
+        chargerhub.request_encoding = RequestEncoding::QueryParams;
+        chargerhub.pagination = Pagination::Offset { page_size: 2 };
+
+        assert_eq!(chargerhub.chargers().await.unwrap().len(), 3);
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn limited_reads_stop_at_the_first_page_holding_enough() {
+        let (url, received) = test_http::serve(|_| (200, format!(r#"{{"data": {}, "next_cursor": "more"}}"#, charger_list(&["CH1", "CH2"])))).await;
+        let mut chargerhub = chargerhub(&url);
+        chargerhub.request_encoding = RequestEncoding::QueryParams;
+        chargerhub.pagination = Pagination::Cursor { page_size: 50 };
+
+        let chargers: Vec<Charger> = chargerhub.list("unable to fetch chargers", &format!("{url}/data/chargers"), json!({}), Some(1)).await.unwrap();
+        assert_eq!(chargers.len(), 1);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].target, "/data/chargers?limit=1");
+    }
+}
@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use std::{sync::Mutex, time::{Duration, Instant}};
+use crate::error::Error;
+
+// Why and since when chargerhub is considered down
+#[derive(Debug, Clone)]
+pub struct Degraded {
+    pub since:                DateTime<Utc>,
+    pub consecutive_failures: u32,
+    pub last_error:           String,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    last_error:           Option<String>,
+    opened:               Option<(Instant, DateTime<Utc>)>,   // when the breaker tripped, None while closed
+    probing:              bool,                               // a request is testing whether chargerhub is back
+}
+
+// Stops calling chargerhub once enough requests in a row have failed, so a
+// chargerhub outage is reported as one degraded state rather than a request
+// timing out per connector every loop. After reset_after a single request is
+// let through, closing the breaker again if it succeeds.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    reset_after:       Duration,
+    state:             Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, reset_after: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            reset_after,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn allow(&self) -> Result<(), Error> {
+        /*
+         * Whether a request may be sent. While open, requests fail straight away
+         * until reset_after has passed, then one probe request is let through.
+         */
+        let mut state = self.state.lock().unwrap();
+        match state.opened {
+            None => Ok(()),
+            Some((opened_at, _)) if opened_at.elapsed() >= self.reset_after && !state.probing => {
+                state.probing = true;
+                Ok(())
+            }
+            Some(_) => Err(Error::Unavailable(format!("chargerhub is unavailable ({} failed requests in a row, last: {})",
+                state.consecutive_failures,
+                state.last_error.as_deref().unwrap_or("unknown")))),
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    pub fn record_failure(&self, err: &Error) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        state.last_error = Some(err.to_string());
+        if state.probing {
+            // the probe failed, stay open for another reset period
+            state.probing = false;
+            state.opened = state.opened.map(|(_, since)| (Instant::now(), since));
+        }
+        else if state.opened.is_none() && state.consecutive_failures >= self.failure_threshold {
+            state.opened = Some((Instant::now(), Utc::now()));
+        }
+    }
+
+    pub fn degraded(&self) -> Option<Degraded> {
+        let state = self.state.lock().unwrap();
+        state.opened.map(|(_, since)| Degraded {
+            since,
+            consecutive_failures: state.consecutive_failures,
+            last_error:           state.last_error.clone().unwrap_or_default(),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeout() -> Error {
+        Error::Transport { context: String::from("unable to fetch chargers"), message: String::new(), timed_out: true }
+    }
+
+    #[test]
+    fn the_breaker_opens_after_enough_failures_in_a_row() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(3600));
+        breaker.record_failure(&timeout());
+        breaker.record_failure(&timeout());
+        breaker.record_success();
+        breaker.record_failure(&timeout());
+        breaker.record_failure(&timeout());
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.degraded().is_none());
+
+        breaker.record_failure(&timeout());
+        assert!(matches!(breaker.allow(), Err(Error::Unavailable(_))));
+        let degraded = breaker.degraded().unwrap();
+        assert_eq!(degraded.consecutive_failures, 3);
+        assert_eq!(degraded.last_error, "unable to fetch chargers: request timed out");
+    }
+
+    #[test]
+    fn one_probe_is_let_through_once_the_reset_period_has_passed() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record_failure(&timeout());
+        let since = breaker.degraded().unwrap().since;
+
+        // only one request probes at a time, and a failed probe keeps the outage going
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_err());
+        breaker.record_failure(&timeout());
+        let degraded = breaker.degraded().unwrap();
+        assert_eq!((degraded.since, degraded.consecutive_failures), (since, 2));
+
+        assert!(breaker.allow().is_ok());
+        breaker.record_success();
+        assert!(breaker.degraded().is_none());
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_ok());
+    }
+}
@@ -0,0 +1,391 @@
+use reqwest::Client;
+use std::{collections::HashMap, ops::RangeInclusive, str::FromStr, time::Duration};
+use crate::{
+    api::ApiConfig, chargerhub::{Pagination, RequestEncoding}, error::Error, ocpp_central_system::OcppCentralSystem, openadr_ven::OpenAdrConfig, phases::PhaseBalancing, roster::Roster, site_meter::{SiteMeterConfig, SiteMeterReader}, topology::Topology,
+    types::{ChargingBounds, ChargingPolicy}, verification::ScheduleVerification
+};
+
+// Where sessions are read from and charging profiles sent to
+pub enum HubSource {
+    ChargerHub {
+        url:                  String,
+        authorization_header: String,
+    },
+    FakeFile(String),              // serve chargerhub from this file in memory instead of CHARGERHUB_URL
+    OcppCentralSystem {            // chargers connect straight to the service, there is no chargerhub
+        bind_addr:           String,
+        charge_points:       HashMap<String, Option<String>>,   // ids allowed to connect and their Basic auth passwords
+        transaction_id_file: String,                             // last transaction id handed out
+    },
+}
+
+pub struct Config {
+    pub hub:                  HubSource,
+    pub request_encoding:     RequestEncoding,
+    pub pagination:           Pagination,
+    pub battery_capacity:     i32,
+    pub verbose_mode:         bool,
+    pub location_id:          i32,
+    pub default_charge_rate:  f32,
+    pub overnight:            ChargingPolicy,
+    pub layover:              Option<ChargingPolicy>,
+    pub site_limit_w:         Option<f32>,
+    pub roster:               Roster,
+    pub topology:             Option<Topology>,
+    pub site_meter:           Option<SiteMeterConfig>,
+    pub phase_balancing:      Option<PhaseBalancing>,
+    pub soc_efficiency:       f32,
+    pub soc_drift_per_hour:   f32,
+    pub soc_max_uncertainty:  f32,
+    pub dr_events_file:       Option<String>,
+    pub api:                  Option<ApiConfig>,
+    pub ocpp_heartbeat_interval: Duration,
+    pub openadr:              Option<OpenAdrConfig>,   // take part in the utility's OpenADR program as a VEN
+    pub alert_webhook_url:    Option<String>,
+    pub stack_levels:         RangeInclusive<i32>,
+    pub schedule_verification: Option<ScheduleVerification>,
+    pub fetch_concurrency:    usize,
+    pub request_timeout:      Duration,
+    pub connect_timeout:      Duration,
+    pub read_timeout:         Duration,
+    pub read_retries:         u32,
+    pub retry_backoff:        Duration,
+    pub breaker_failures:     u32,
+    pub breaker_reset:        Duration,
+    pub meter_value_max_age:  chrono::Duration,
+    pub stale_charge_rate:    Option<f32>,
+    pub session_poll_interval: Duration,
+    pub reconcile_interval:   Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, Error> {
+        /*
+         * Read every setting the service needs out of the .env file. A missing or
+         * invalid required setting is an Error naming the variable, optional
+         * settings fall back to a default or disable their feature.
+         */
+
+        // chargerhub, an in-memory fake of it, or chargers connecting straight to the
+        // service over OCPP 1.6J. Only chargerhub needs its URL and authorization header
+        let hub = match (dotenv::var("CHARGERHUB_FAKE_FILE").ok(), dotenv::var("OCPP_BIND_ADDR").ok()) {
+            (Some(_), Some(_)) => return Err(Error::Config(String::from("CHARGERHUB_FAKE_FILE and OCPP_BIND_ADDR cannot both be set, pick one"))),
+            (Some(fake_file), None) => HubSource::FakeFile(fake_file),
+            (None, Some(bind_addr)) => HubSource::OcppCentralSystem {
+                bind_addr,
+                charge_points:       OcppCentralSystem::parse_charge_points(&required_var::<String>("OCPP_CHARGE_POINTS")?)?,
+                transaction_id_file: dotenv::var("OCPP_TRANSACTION_ID_FILE").unwrap_or_else(|_| String::from("ocppTransactionId.txt")),
+            },
+            (None, None) => HubSource::ChargerHub {
+                url:                  required_var("CHARGERHUB_URL")?,
+                authorization_header: required_var("AUTHORIZATION_HEADER")?,
+            },
+        };
+
+        // how the chargerhub deployment expects reads to be sent and pages its lists
+        let request_encoding = match optional_var::<String>("CHARGERHUB_REQUEST_ENCODING")?.as_deref() {
+            None | Some("body") => RequestEncoding::JsonBody,
+            Some("query") => RequestEncoding::QueryParams,
+            Some(other) => return Err(Error::Config(format!("Unknown CHARGERHUB_REQUEST_ENCODING {other}, expected body or query"))),
+        };
+        let page_size = optional_var("CHARGERHUB_PAGE_SIZE")?.unwrap_or(100).max(1);
+        let pagination = match optional_var::<String>("CHARGERHUB_PAGINATION")?.as_deref() {
+            None | Some("none") => Pagination::None,
+            Some("cursor") => Pagination::Cursor { page_size },
+            Some("offset") => Pagination::Offset { page_size },
+            Some(other) => return Err(Error::Config(format!("Unknown CHARGERHUB_PAGINATION {other}, expected none, cursor or offset"))),
+        };
+
+        let battery_capacity: i32 = required_var("BATTERY_CAPACITY")?;
+        let desired_soc: i8 = required_var("DESIRED_SOC")?;
+
+        let verbose_mode = required_var::<String>("VERBOSE_MODE")?
+            .parse::<bool>()
+            .unwrap_or(false);
+
+        let charge_clamp_lower: i32 = required_var("CHARGE_CLAMP_LOWER")?;
+        let charge_clamp_upper: i32 = required_var("CHARGE_CLAMP_UPPER")?;
+        let location_id: i32 = required_var("LOCATION_ID")?;
+        let default_charge_rate: f32 = required_var("CHARGE_RATE_DEFAULT")?;
+        let curtailment_start_hour = hour_of_day("CURTAILMENT_START_HOUR", required_var("CURTAILMENT_START_HOUR")?)?;
+        let curtailment_stop_hour = hour_of_day("CURTAILMENT_STOP_HOUR", required_var("CURTAILMENT_STOP_HOUR")?)?;
+        if curtailment_start_hour == curtailment_stop_hour {
+            return Err(Error::Config(String::from("CURTAILMENT_START_HOUR and CURTAILMENT_STOP_HOUR must differ")));
+        }
+
+        let overnight = ChargingPolicy {
+            name:           String::from("overnight"),
+            start_hour:     curtailment_start_hour,
+            stop_hour:      curtailment_stop_hour,
+            desired_soc,
+            bounds:         ChargingBounds { lower_bnd: charge_clamp_lower, upper_bnd: charge_clamp_upper },
+            typical_kwh:    optional_var("TYPICAL_NIGHTLY_KWH")?,
+            roster_targets: true,
+        };
+
+        // The layover policy is only enabled when both of its hours are given
+        let layover = match (optional_var::<u32>("LAYOVER_START_HOUR")?, optional_var::<u32>("LAYOVER_STOP_HOUR")?) {
+            (Some(start_hour), Some(stop_hour)) => Some(ChargingPolicy {
+                name: String::from("layover"),
+                start_hour: hour_of_day("LAYOVER_START_HOUR", start_hour)?,
+                stop_hour: hour_of_day("LAYOVER_STOP_HOUR", stop_hour)?,
+                desired_soc: required_var("LAYOVER_DESIRED_SOC")?,
+                bounds: ChargingBounds {
+                    lower_bnd: optional_var("LAYOVER_CHARGE_CLAMP_LOWER")?.unwrap_or(charge_clamp_lower),
+                    upper_bnd: optional_var("LAYOVER_CHARGE_CLAMP_UPPER")?.unwrap_or(charge_clamp_upper),
+                },
+                // the roster's targets are nightly needs, layovers only top up for the afternoon
+                typical_kwh: optional_var("LAYOVER_TYPICAL_KWH")?,
+                roster_targets: false,
+            }),
+            (None, None) => None,
+            _ => return Err(Error::Config(String::from("LAYOVER_START_HOUR and LAYOVER_STOP_HOUR must be specified together in .env"))),
+        };
+        if let Some(layover) = &layover {
+            if layover.start_hour == layover.stop_hour {
+                return Err(Error::Config(String::from("LAYOVER_START_HOUR and LAYOVER_STOP_HOUR must differ")));
+            }
+            // a loop in both windows at once would plan under whichever came first
+            let overnight_hours = window_hours(&overnight);
+            if window_hours(layover).iter().any(|hour| overnight_hours.contains(hour)) {
+                return Err(Error::Config(format!("The layover window ({} - {}) overlaps the curtailment window ({} - {})",
+                    layover.start_hour, layover.stop_hour, overnight.start_hour, overnight.stop_hour)));
+            }
+        }
+
+        // aggregated upper bound for all chargers, given in Kw
+        let site_limit_w = optional_var::<f32>("PEAK_UPPER_BOUND")?.map(|limit_kw| limit_kw * 1000.0);
+
+        // vehicles, their types and departure times. Without a roster every bus
+        // is treated as having BATTERY_CAPACITY and no pre-conditioning
+        let roster = match dotenv::var("ROSTER_FILE") {
+            Ok(path) => Roster::load(&path)?,
+            Err(_) => Roster::default(),
+        };
+
+        // transformers, panels and chargers with their ratings
+        let topology = dotenv::var("TOPOLOGY_FILE").ok().map(|path| Topology::load(&path)).transpose()?;
+
+        // timeout for connecting to chargerhub and the site meter
+        let connect_timeout = Duration::from_secs(optional_var("CONNECT_TIMEOUT_SECONDS")?.unwrap_or(5));
+
+        // site main meter, charging only gets what the building leaves of the service
+        let site_meter = match dotenv::var("SITE_METER_SOURCE").ok() {
+            Some(source) => {
+                let reader = match source.as_str() {
+                    "http" => SiteMeterReader::HttpJson {
+                        client:  Client::builder()
+                            .connect_timeout(connect_timeout)
+                            .build()
+                            .map_err(|err| Error::Config(format!("Unable to build the site meter client: {err}")))?,
+                        url:     required_var("SITE_METER_URL")?,
+                        pointer: optional_var("SITE_METER_JSON_POINTER")?.unwrap_or_else(|| String::from("/power_w")),
+                        scale:   optional_var("SITE_METER_SCALE")?.unwrap_or(1.0),
+                    },
+                    "modbus" => SiteMeterReader::ModbusTcp {
+                        addr:     required_var("SITE_METER_MODBUS_ADDR")?,
+                        unit_id:  optional_var("SITE_METER_MODBUS_UNIT")?.unwrap_or(1),
+                        register: required_var("SITE_METER_MODBUS_REGISTER")?,
+                        count:    optional_var("SITE_METER_MODBUS_COUNT")?.unwrap_or(2),
+                        scale:    optional_var("SITE_METER_SCALE")?.unwrap_or(1.0),
+                    },
+                    other => return Err(Error::Config(format!("Unknown SITE_METER_SOURCE {other}, expected http or modbus"))),
+                };
+                Some(SiteMeterConfig {
+                    reader,
+                    service_limit_w:   required_var::<f32>("SERVICE_LIMIT_KW")? * 1000.0,
+                    margin_w:          optional_var::<f32>("SITE_METER_MARGIN_KW")?.unwrap_or(0.0) * 1000.0,
+                    includes_chargers: optional_var("SITE_METER_INCLUDES_CHARGERS")?.unwrap_or(false),
+                    poll_interval:     Duration::from_secs(optional_var("SITE_METER_POLL_SECONDS")?.unwrap_or(30)),
+                    spike_w:           optional_var::<f32>("SITE_METER_SPIKE_KW")?.unwrap_or(20.0) * 1000.0,
+                })
+            }
+            None => None,
+        };
+
+        // phase assignment of single phase AC chargers, an empty list relies on
+        // the phases reported in meter values
+        let phase_balancing = match dotenv::var("CHARGER_PHASES").ok() {
+            Some(assignments) => Some(PhaseBalancing {
+                assignments:           PhaseBalancing::parse_assignments(&assignments)?,
+                voltage:               optional_var("PHASE_VOLTAGE")?.unwrap_or(230.0),
+                current_limit_a:       optional_var("PHASE_CURRENT_LIMIT_AMPS")?,
+                max_imbalance_percent: optional_var("PHASE_IMBALANCE_MAX_PERCENT")?,
+            }),
+            None => None,
+        };
+
+        // estimating SOC from the energy register between sparse SOC readings
+        let soc_efficiency = optional_var("SOC_CHARGE_EFFICIENCY")?.unwrap_or(0.92);
+        let soc_drift_per_hour = optional_var("SOC_UNCERTAINTY_PER_HOUR")?.unwrap_or(2.0);
+        let soc_max_uncertainty = optional_var("SOC_MAX_UNCERTAINTY")?.unwrap_or(15.0);
+
+        // demand response events injected through the file, command line or API
+        let dr_events_file = dotenv::var("DR_EVENTS_FILE").ok();
+        let api = match dotenv::var("API_BIND_ADDR").ok() {
+            Some(bind_addr) => Some(ApiConfig { bind_addr, token: required_var("API_TOKEN")? }),
+            None => None,
+        };
+        let alert_webhook_url = dotenv::var("ALERT_WEBHOOK_URL").ok();
+
+        // chargers connecting straight to the service over OCPP 1.6J
+        let ocpp_heartbeat_interval = Duration::from_secs(optional_var("OCPP_HEARTBEAT_SECONDS")?.unwrap_or(300));
+
+        // the utility's OpenADR 2.0b VTN, sending load shed events and prices
+        let openadr = match dotenv::var("OPENADR_VTN_URL").ok() {
+            Some(vtn_url) => Some(OpenAdrConfig {
+                vtn_url,
+                ven_name:          required_var("OPENADR_VEN_NAME")?,
+                poll_interval:     optional_var("OPENADR_POLL_SECONDS")?.map(Duration::from_secs),
+                report_interval:   Duration::from_secs(optional_var("OPENADR_REPORT_SECONDS")?.unwrap_or(300)),
+                simple_reductions: parse_reductions(&optional_var::<String>("OPENADR_SIMPLE_REDUCTIONS")?.unwrap_or_else(|| String::from("25,50,100")))?,
+                opt_in:            optional_var("OPENADR_OPT_IN")?.unwrap_or(true),
+            }),
+            None => None,
+        };
+
+        // charging profile stack levels reserved for this service
+        let stack_level_min = optional_var("PROFILE_STACK_LEVEL_MIN")?.unwrap_or(1);
+        let stack_level_max = optional_var("PROFILE_STACK_LEVEL_MAX")?.unwrap_or(stack_level_min + 2);
+        if stack_level_min < 0 || stack_level_max < stack_level_min {
+            return Err(Error::Config(format!("PROFILE_STACK_LEVEL_MIN ({stack_level_min}) and PROFILE_STACK_LEVEL_MAX ({stack_level_max}) must form a non-negative range")));
+        }
+
+        // fetching meter values and transactions from chargerhub
+        let fetch_concurrency = optional_var("FETCH_CONCURRENCY")?.unwrap_or(8);
+        let request_timeout = Duration::from_secs(optional_var("REQUEST_TIMEOUT_SECONDS")?.unwrap_or(10));
+
+        // riding out network hiccups, and how long chargerhub may fail before the service degrades
+        let read_timeout = Duration::from_secs(optional_var("READ_TIMEOUT_SECONDS")?.unwrap_or(30));
+        let read_retries = optional_var("READ_RETRIES")?.unwrap_or(2);
+        let retry_backoff = Duration::from_millis(optional_var("RETRY_BACKOFF_MS")?.unwrap_or(500));
+        let breaker_failures = optional_var("CIRCUIT_BREAKER_FAILURES")?.unwrap_or(5);
+        let breaker_reset = Duration::from_secs(optional_var("CIRCUIT_BREAKER_RESET_SECONDS")?.unwrap_or(120));
+
+        // how often sessions are polled during a window to catch buses connecting and disconnecting
+        let session_poll_interval = Duration::from_secs(optional_var("SESSION_POLL_SECONDS")?.unwrap_or(60));
+
+        // with the API taking chargerhub's webhooks, or chargers pushing to the central system,
+        // how often the pushed state is reconciled against a full poll. Without either every
+        // connector is polled every loop
+        let reconcile_interval = match (&api, &hub) {
+            (None, HubSource::ChargerHub { .. } | HubSource::FakeFile(_)) => Duration::ZERO,
+            _ => Duration::from_secs(optional_var("RECONCILE_SECONDS")?.unwrap_or(900)),
+        };
+
+        // meter values older than this are not planned from
+        let meter_value_max_age = chrono::Duration::seconds(optional_var("METER_VALUE_MAX_AGE_SECONDS")?.unwrap_or(900));
+        let stale_charge_rate = optional_var("STALE_CHARGE_RATE")?;
+
+        // reading the composite schedule back after sending each profile
+        let schedule_verification = match optional_var("VERIFY_SCHEDULES")?.unwrap_or(false) {
+            true => Some(ScheduleVerification {
+                tolerance_w: optional_var("VERIFY_TOLERANCE_W")?.unwrap_or(500.0),
+                retries:     optional_var("VERIFY_RETRIES")?.unwrap_or(1),
+            }),
+            false => None,
+        };
+
+        Ok(Config {
+            hub,
+            request_encoding,
+            pagination,
+            battery_capacity,
+            verbose_mode,
+            location_id,
+            default_charge_rate,
+            overnight,
+            layover,
+            site_limit_w,
+            roster,
+            topology,
+            site_meter,
+            phase_balancing,
+            soc_efficiency,
+            soc_drift_per_hour,
+            soc_max_uncertainty,
+            dr_events_file,
+            api,
+            ocpp_heartbeat_interval,
+            openadr,
+            alert_webhook_url,
+            stack_levels: stack_level_min..=stack_level_max,
+            schedule_verification,
+            fetch_concurrency,
+            request_timeout,
+            connect_timeout,
+            read_timeout,
+            read_retries,
+            retry_backoff,
+            breaker_failures,
+            breaker_reset,
+            meter_value_max_age,
+            stale_charge_rate,
+            session_poll_interval,
+            reconcile_interval,
+        })
+    }
+
+    pub fn policies(&self) -> Vec<&ChargingPolicy> {
+        /*
+         * All enabled charging policies, the overnight policy first. from_env
+         * makes sure their windows do not overlap.
+         */
+        let mut policies = vec![&self.overnight];
+        if let Some(layover) = &self.layover {
+            policies.push(layover);
+        }
+        policies
+    }
+}
+
+
+fn required_var<T: FromStr>(name: &str) -> Result<T, Error> {
+    /*
+     * Read a variable which an enabled feature cannot run without.
+     */
+    let value = dotenv::var(name).map_err(|_| Error::Config(format!("{name} was not specified in .env")))?;
+    parse_var(name, value)
+}
+
+fn optional_var<T: FromStr>(name: &str) -> Result<Option<T>, Error> {
+    /*
+     * Read an optional variable from the .env. Missing variables return None,
+     * present but unparsable variables are treated as a configuration mistake.
+     */
+    dotenv::var(name).ok().map(|value| parse_var(name, value)).transpose()
+}
+
+fn parse_var<T: FromStr>(name: &str, value: String) -> Result<T, Error> {
+    value
+        .parse::<T>()
+        .map_err(|_| Error::Config(format!("Something went wrong reading in {name}. Please verify the value \"{value}\"")))
+}
+
+fn hour_of_day(name: &str, hour: u32) -> Result<u32, Error> {
+    // window hours are local hours of the day, 0 - 23
+    match hour {
+        0..=23 => Ok(hour),
+        _ => Err(Error::Config(format!("{name} must be an hour between 0 and 23, got {hour}"))),
+    }
+}
+
+fn window_hours(policy: &ChargingPolicy) -> Vec<u32> {
+    // the hours of the day a policy's window covers, wrapping past midnight when it stops before it starts
+    (0..24)
+        .filter(|hour| match policy.start_hour < policy.stop_hour {
+            true => (policy.start_hour..policy.stop_hour).contains(hour),
+            false => *hour >= policy.start_hour || *hour < policy.stop_hour,
+        })
+        .collect()
+}
+
+fn parse_reductions(reductions: &str) -> Result<Vec<f32>, Error> {
+    // percent reductions for SIMPLE levels 1 and up, IE "25,50,100"
+    let reductions = reductions
+        .split(',')
+        .map(|reduction| reduction.trim().parse::<f32>().ok().filter(|reduction| (0.0..=100.0).contains(reduction)))
+        .collect::<Option<Vec<f32>>>()
+        .filter(|reductions| !reductions.is_empty());
+    reductions.ok_or(Error::Config(String::from("OPENADR_SIMPLE_REDUCTIONS must be a comma separated list of percentages, IE \"25,50,100\"")))
+}
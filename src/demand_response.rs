@@ -0,0 +1,307 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+use crate::error::Error;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DrLimit {
+    Kw(f32),                // depot must stay under a fixed limit
+    PercentReduction(f32),  // depot must drop this percentage below its normal limit
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DrEvent {
+    pub id:    String,
+    pub start: DateTime<Utc>,
+    pub end:   DateTime<Utc>,
+    pub limit: DrLimit,
+}
+
+impl DrEvent {
+    pub fn validate(&self) -> Result<(), Error> {
+        /*
+         * Check an event given through the command line or the API makes sense
+         * before it is stored, IE it ends after it starts and it does not limit
+         * the depot to negative kW or reduce it by more than 100%.
+         */
+        if self.end <= self.start {
+            return Err(Error::Config(format!("demand response event {} must end after it starts", self.id)));
+        }
+        match self.limit {
+            DrLimit::Kw(limit_kw) if !(0.0..).contains(&limit_kw) => {
+                Err(Error::Config(format!("demand response event {} has a limit of {limit_kw}kW, expected 0 or more", self.id)))
+            }
+            DrLimit::PercentReduction(percent) if !(0.0..=100.0).contains(&percent) => {
+                Err(Error::Config(format!("demand response event {} reduces by {percent}%, expected 0 - 100", self.id)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_active(&self, right_now: DateTime<Utc>) -> bool {
+        right_now >= self.start && right_now < self.end
+    }
+
+    pub fn limit_w(&self, baseline_w: f32) -> f32 {
+        /*
+         * The depot wide limit in watts while the event is active. Percentage
+         * reductions are taken off of the baseline the depot would otherwise be
+         * held to.
+         */
+        match self.limit {
+            DrLimit::Kw(limit_kw) => limit_kw * 1000.0,
+            DrLimit::PercentReduction(percent) => baseline_w * (1.0 - percent.clamp(0.0, 100.0) / 100.0),
+        }
+    }
+}
+
+// Demand response events known to the service. Events can come from the events
+// file, the command line (which writes to the file) or the API, so the file is
+// re-read every loop and written back whenever an event is added.
+pub struct DrEventStore {
+    path:   Option<String>,
+    events: Mutex<Vec<DrEvent>>,
+}
+
+impl DrEventStore {
+    pub fn new(path: Option<String>) -> DrEventStore {
+        let store = DrEventStore { path, events: Mutex::new(Vec::new()) };
+        store.reload();
+        store
+    }
+
+    pub fn reload(&self) {
+        /*
+         * Replace the in memory events with the contents of the events file. A
+         * missing file means there are no events, an unreadable one keeps the
+         * events we already had rather than dropping an event in progress.
+         */
+        let Some(path) = &self.path else { return };
+
+        let events = match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<Vec<DrEvent>>(&contents) {
+                Ok(events) => events,
+                Err(err) => {
+                    eprintln!("Unable to parse demand response events in {path}: {err}");
+                    return;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        *self.events.lock().unwrap() = events;
+    }
+
+    pub fn add(&self, event: DrEvent) -> Result<(), Error> {
+        /*
+         * Add or replace (by id) an event, dropping events which have already
+         * ended, and persist the result to the events file if there is one. The
+         * events in memory are only changed once the file has been written, so
+         * an event which could not be stored is never enforced.
+         */
+        let mut events = self.events.lock().unwrap();
+        let right_now = Utc::now();
+        let mut updated: Vec<DrEvent> = events
+            .iter()
+            .filter(|existing| existing.id != event.id && existing.end > right_now)
+            .cloned()
+            .collect();
+        updated.push(event);
+        updated.sort_by_key(|existing| existing.start);
+
+        self.write(&updated)?;
+        *events = updated;
+        Ok(())
+    }
+
+    pub fn replace_source(&self, id_prefix: &str, mut replacements: Vec<DrEvent>) -> Result<(), Error> {
+        /*
+         * Replace every event whose id starts with id_prefix, IE all of the events
+         * a VTN has sent, with the events it holds now. The file is only written
+         * when they actually changed, and as with add the events in memory are
+         * only replaced once it has been.
+         */
+        let mut events = self.events.lock().unwrap();
+        let right_now = Utc::now();
+        let mut updated: Vec<DrEvent> = events
+            .iter()
+            .filter(|existing| !existing.id.starts_with(id_prefix) && existing.end > right_now)
+            .cloned()
+            .collect();
+        replacements.retain(|event| event.end > right_now);
+        updated.append(&mut replacements);
+        updated.sort_by(|a, b| a.start.cmp(&b.start).then(a.id.cmp(&b.id)));
+        if *events == updated {
+            return Ok(());
+        }
+
+        self.write(&updated)?;
+        *events = updated;
+        Ok(())
+    }
+
+    fn write(&self, events: &[DrEvent]) -> Result<(), Error> {
+        // persist the events to the events file if there is one
+        let Some(path) = &self.path else { return Ok(()) };
+
+        let contents = serde_json::to_string_pretty(events)
+            .map_err(|err| Error::Config(format!("Unable to serialize demand response events: {err}")))?;
+        std::fs::write(path, contents)
+            .map_err(|err| Error::Config(format!("Unable to write demand response events to {path}: {err}")))
+    }
+
+    pub fn all(&self) -> Vec<DrEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn active(&self, right_now: DateTime<Utc>) -> Vec<DrEvent> {
+        /*
+         * Every event in effect right now. Overlapping events are resolved with
+         * strictest once the baseline their percentage reductions apply to is known.
+         */
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.is_active(right_now))
+            .cloned()
+            .collect()
+    }
+
+    pub fn next_boundary(&self, right_now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        /*
+         * The next time an event starts or ends, so the loop can wake up and
+         * re-plan exactly when it needs to.
+         */
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|event| [event.start, event.end])
+            .filter(|boundary| *boundary > right_now)
+            .min()
+    }
+}
+
+
+pub fn strictest(events: &[DrEvent], baseline_w: f32) -> Option<&DrEvent> {
+    // the event holding the depot to the lowest limit, comparing kW limits and percentage reductions alike
+    events.iter().min_by(|a, b| a.limit_w(baseline_w).total_cmp(&b.limit_w(baseline_w)))
+}
+
+
+pub fn log_compliance(event: &DrEvent, limit_w: f32, planned_w: f32, measured_w: f32) {
+    /*
+     * Record how the depot stands against a demand response event's limit. The
+     * planned load is what the new profiles allow, the measured load is what the
+     * chargers last reported drawing before the profiles were sent.
+     */
+    println!("DR event {} compliance: limit {:.0}W, planned {:.0}W ({}), measured {:.0}W ({})",
+        event.id,
+        limit_w,
+        planned_w,
+        if planned_w <= limit_w { "compliant" } else { "NOT compliant, charge rate floors exceed the limit" },
+        measured_w,
+        if measured_w <= limit_w { "compliant" } else { "over limit" }
+    );
+}
+
+
+pub fn parse_cli(args: &[String], store: &DrEventStore) -> Result<bool, Error> {
+    /*
+     * Handle the `dr-event` command line commands:
+     *
+     *   dr-event add --start <rfc3339> --end <rfc3339> (--kw <limit> | --percent <reduction>) [--id <id>]
+     *   dr-event list
+     *
+     * Events are written to DR_EVENTS_FILE where the running service picks them up.
+     *
+     * @Output: true if a dr-event command was handled and the program should exit
+     */
+    if args.first().map(String::as_str) != Some("dr-event") {
+        return Ok(false);
+    }
+
+    match args.get(1).map(String::as_str) {
+        Some("add") => {
+            let flag = |name: &str| {
+                args.iter()
+                    .position(|arg| arg == name)
+                    .and_then(|index| args.get(index + 1))
+            };
+            let parse_time = |name: &str| {
+                flag(name)
+                    .ok_or_else(|| Error::Config(format!("dr-event add requires {name}")))?
+                    .parse::<DateTime<Utc>>()
+                    .map_err(|err| Error::Config(format!("Unable to parse {name} as an RFC 3339 timestamp: {err}")))
+            };
+            let parse_number = |value: &String| {
+                value
+                    .parse::<f32>()
+                    .map_err(|err| Error::Config(format!("Unable to parse demand response limit {value}: {err}")))
+            };
+
+            let start = parse_time("--start")?;
+            let end = parse_time("--end")?;
+            let limit = match (flag("--kw"), flag("--percent")) {
+                (Some(limit_kw), None) => DrLimit::Kw(parse_number(limit_kw)?),
+                (None, Some(percent)) => DrLimit::PercentReduction(parse_number(percent)?),
+                _ => return Err(Error::Config(String::from("dr-event add requires exactly one of --kw or --percent"))),
+            };
+            let id = flag("--id")
+                .cloned()
+                .unwrap_or_else(|| format!("cli-{}", start.timestamp()));
+
+            let event = DrEvent { id, start, end, limit };
+            event.validate()?;
+            store.add(event)?;
+        }
+        Some("list") => {
+            for event in store.all() {
+                println!("{}: {} - {} {:?}", event.id, event.start, event.end, event.limit);
+            }
+        }
+        _ => eprintln!("usage: dr-event add --start <rfc3339> --end <rfc3339> (--kw <limit> | --percent <reduction>) [--id <id>]\n       dr-event list"),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn event(id: &str, limit: DrLimit) -> DrEvent {
+        let start = Utc::now();
+        DrEvent { id: id.to_owned(), start, end: start + Duration::hours(1), limit }
+    }
+
+    #[test]
+    fn invalid_events_are_rejected() {
+        let mut backwards = event("backwards", DrLimit::Kw(100.0));
+        backwards.end = backwards.start - Duration::hours(1);
+
+        assert!(backwards.validate().is_err());
+        assert!(event("negative", DrLimit::Kw(-5.0)).validate().is_err());
+        assert!(event("over", DrLimit::PercentReduction(150.0)).validate().is_err());
+        assert!(event("valid", DrLimit::PercentReduction(100.0)).validate().is_ok());
+    }
+
+    #[test]
+    fn events_which_cannot_be_stored_are_not_enforced() {
+        let store = DrEventStore::new(Some(String::from("/nonexistent/drEvents.json")));
+
+        assert!(store.add(event("unstored", DrLimit::Kw(0.0))).is_err());
+        assert!(store.replace_source("vtn-", vec![event("vtn-1", DrLimit::Kw(0.0))]).is_err());
+        assert!(store.active(Utc::now()).is_empty());
+    }
+
+    #[test]
+    fn overlapping_events_follow_the_lowest_limit() {
+        // 50% off of a 300kW baseline is stricter than a 200kW limit
+        let events = vec![event("kw", DrLimit::Kw(200.0)), event("percent", DrLimit::PercentReduction(50.0))];
+
+        assert_eq!(strictest(&events, 300_000.0).map(|event| event.id.as_str()), Some("percent"));
+        assert_eq!(strictest(&events, 500_000.0).map(|event| event.id.as_str()), Some("kw"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+
+// Everything that can go wrong talking to chargerhub, the chargers or the site
+// meter, or reading the service's configuration
+#[derive(Debug, Clone)]
+pub enum Error {
+    Transport { context: String, message: String, timed_out: bool },   // the request never got an answer
+    Http { context: String, status: u16, body: String },                // answered with an error status
+    Decode { context: String, message: String },                        // answered with something we could not parse
+    Config(String),                                                    // a setting or file the service was started with is invalid
+    NoData(String),                                                    // answered, but without what was asked for
+    ChargerRejected(ChargerStatus),                                    // the charger answered a command with anything but Accepted
+    Unavailable(String),                                               // chargerhub is down, the request was not sent
+}
+
+// The OCPP status a charger answered a command with, when it was not Accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChargerStatus {
+    Rejected,               // the charger refused the command
+    NotSupported,           // the charger does not support the command, IE no smart charging
+    Unknown,                // the charger had nothing matching the command, IE no profile to clear
+    Unrecognised(String),   // a status OCPP 1.6 does not define
+}
+
+impl ChargerStatus {
+    pub fn from_ocpp(status: &str) -> ChargerStatus {
+        match status {
+            "Rejected" => ChargerStatus::Rejected,
+            "NotSupported" | "NotImplemented" => ChargerStatus::NotSupported,
+            "Unknown" => ChargerStatus::Unknown,
+            other => ChargerStatus::Unrecognised(other.to_owned()),
+        }
+    }
+}
+
+impl Error {
+    pub fn from_reqwest(context: &str, err: reqwest::Error) -> Error {
+        /*
+         * Sort a reqwest error by where it went wrong. reqwest reports a timed
+         * out request as a generic send error, so timeouts are called out.
+         */
+        if let Some(status) = err.status() {
+            Error::Http { context: context.to_owned(), status: status.as_u16(), body: String::new() }
+        }
+        else if err.is_decode() {
+            Error::Decode { context: context.to_owned(), message: err.to_string() }
+        }
+        else {
+            Error::Transport { context: context.to_owned(), message: err.to_string(), timed_out: err.is_timeout() }
+        }
+    }
+
+    pub fn decode(context: &str, body: &str, err: impl fmt::Display) -> Error {
+        Error::Decode { context: context.to_owned(), message: format!("{err} in {body}") }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        /*
+         * Whether the same request might succeed if sent again, IE chargerhub was
+         * busy or unreachable. A charger's own answer will not change on a resend.
+         */
+        match self {
+            Error::Http { status, .. } => *status == 429 || *status >= 500,
+            Error::Transport { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Transport { context, timed_out: true, .. } => write!(f, "{context}: request timed out"),
+            Error::Transport { context, message, .. } => write!(f, "{context}: {message}"),
+            Error::Http { context, status, body } if body.is_empty() => write!(f, "{context}: answered {status}"),
+            Error::Http { context, status, body } => write!(f, "{context}: answered {status}: {body}"),
+            Error::Decode { context, message } => write!(f, "{context}: {message}"),
+            Error::Config(message) | Error::NoData(message) | Error::Unavailable(message) => write!(f, "{message}"),
+            Error::ChargerRejected(ChargerStatus::Rejected) => write!(f, "charger rejected the request"),
+            Error::ChargerRejected(ChargerStatus::NotSupported) => write!(f, "charger does not support the request"),
+            Error::ChargerRejected(ChargerStatus::Unknown) => write!(f, "charger has nothing matching the request"),
+            Error::ChargerRejected(ChargerStatus::Unrecognised(status)) => write!(f, "charger answered with unrecognised status {status}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
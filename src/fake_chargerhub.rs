@@ -0,0 +1,185 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{fs, sync::Mutex};
+use crate::{
+    chargerhub::ChargerHubApi,
+    circuit_breaker::Degraded,
+    error::Error,
+    profile_registry::OwnedProfile,
+    types::{Charger, ChargeProfile, ChargingSchedule, ChargingSchedulePeriod, CompositeSchedule, MeterValue, Transaction}
+};
+
+// The depot the fake serves, as read from CHARGERHUB_FAKE_FILE
+#[derive(Debug, Deserialize)]
+struct FakeDepot {
+    chargers:     Vec<Charger>,
+    #[serde(default)]
+    meter_values: Vec<MeterValue>,
+    #[serde(default)]
+    transactions: Vec<Transaction>,
+}
+
+// An in-memory stand in for chargerhub, for running the service without a
+// live server. Every profile is accepted and the composite schedule a
+// connector reports is the highest stack level profile installed on it.
+pub struct FakeChargerHub {
+    depot:    FakeDepot,
+    profiles: Mutex<Vec<(ChargeProfile, DateTime<Utc>)>>,   // installed profiles and when each expires
+}
+
+impl FakeChargerHub {
+    pub fn from_file(path: &str) -> Result<FakeChargerHub, Error> {
+        let contents = fs::read_to_string(path).map_err(|err| Error::Config(format!("Unable to read CHARGERHUB_FAKE_FILE {path}: {err}")))?;
+        let depot = serde_json::from_str(&contents).map_err(|err| Error::Config(format!("Unable to parse CHARGERHUB_FAKE_FILE {path}: {err}")))?;
+        Ok(FakeChargerHub {
+            depot,
+            profiles: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl ChargerHubApi for FakeChargerHub {
+    async fn chargers(&self) -> Result<Vec<Charger>, Error> {
+        Ok(self.depot.chargers.clone())
+    }
+
+    async fn latest_meter_value(&self, charger_id: &str, connector_id: i32) -> Result<Option<MeterValue>, Error> {
+        // the file's meter values never change, so they are served as just reported rather than going stale
+        Ok(self.depot.meter_values
+            .iter()
+            .filter(|meter_value| meter_value.charger_id == charger_id && meter_value.connector_id == connector_id)
+            .max_by_key(|meter_value| meter_value.time_stamp)
+            .map(|meter_value| MeterValue { time_stamp: Utc::now(), ..meter_value.clone() }))
+    }
+
+    async fn latest_transaction(&self, charger_id: &str, connector_id: i32) -> Result<Option<Transaction>, Error> {
+        Ok(self.depot.transactions
+            .iter()
+            .filter(|transaction| transaction.charger_id.as_deref() == Some(charger_id) && transaction.connector_id == connector_id)
+            .max_by_key(|transaction| transaction.timestamp_start)
+            .cloned())
+    }
+
+    async fn set_charge_profile(&self, profile: &ChargeProfile, valid_to: DateTime<Utc>) -> Result<(), Error> {
+        let mut profiles = self.profiles.lock().unwrap();
+        profiles.retain(|(installed, _)| !(installed.charger_id == profile.charger_id
+            && installed.connector_id == profile.connector_id
+            && installed.stack_level == profile.stack_level));
+        profiles.push((profile.clone(), valid_to));
+        Ok(())
+    }
+
+    async fn clear_charge_profile(&self, profile: &OwnedProfile) -> Result<(), Error> {
+        self.profiles.lock().unwrap().retain(|(installed, _)| !(installed.charger_id == profile.charger_id
+            && installed.connector_id == profile.connector_id
+            && installed.stack_level == profile.stack_level));
+        Ok(())
+    }
+
+    async fn composite_schedule(&self, charger_id: &str, connector_id: i32, duration: i32) -> Result<CompositeSchedule, Error> {
+        let right_now = Utc::now();
+        let profiles = self.profiles.lock().unwrap();
+        let in_effect = profiles
+            .iter()
+            .filter(|(profile, valid_to)| profile.charger_id == charger_id && profile.connector_id == connector_id && *valid_to > right_now)
+            .max_by_key(|(profile, _)| profile.stack_level);
+
+        let charging_schedule = in_effect.map(|(profile, _)| ChargingSchedule {
+            charging_schedule_period: profile.start_periods
+                .iter()
+                .zip(&profile.charge_rates)
+                .filter(|(start_period, _)| **start_period < duration)
+                .map(|(start_period, limit)| ChargingSchedulePeriod { start_period: *start_period, limit: *limit })
+                .collect(),
+        });
+        Ok(CompositeSchedule {
+            status: String::from("Accepted"),
+            charging_schedule,
+        })
+    }
+
+    fn degraded(&self) -> Option<Degraded> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Local};
+    use std::time::Instant;
+    use super::*;
+    use crate::{
+        get_data::get_chargers,
+        planner::{clamp_plans, fit_to_site_limit, ChargePlan},
+        profile_registry::ProfileRegistry,
+        send_data::create_charge_profile,
+        types::ChargingBounds,
+        verification::{verify_profile, Verification}
+    };
+
+    const BOUNDS: ChargingBounds = ChargingBounds { lower_bnd: 800, upper_bnd: 100_000 };
+
+    fn example_hub() -> FakeChargerHub {
+        FakeChargerHub::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/exampleFakeChargerhub.json")).unwrap()
+    }
+
+    async fn plan_sessions(hub: &FakeChargerHub) -> Vec<ChargePlan> {
+        // one plan per connector with an active session, asking for what it last drew
+        let mut plans = Vec::new();
+        for charger in get_chargers(hub, 7, &false).await.unwrap() {
+            let meter_value = hub.latest_meter_value(&charger.id, 1).await.unwrap().unwrap();
+            let transaction = hub.latest_transaction(&charger.id, 1).await.unwrap().unwrap();
+            assert_eq!(transaction.transaction_id, Some(meter_value.transaction_id));
+            plans.push(ChargePlan {
+                charger_id:     charger.id,
+                connector_id:   1,
+                transaction_id: meter_value.transaction_id,
+                charge_rate:    meter_value.active_power_w().unwrap(),
+                calculated:     true,
+                ..Default::default()
+            });
+        }
+        plans
+    }
+
+    #[tokio::test]
+    async fn plans_fit_under_the_site_limit_are_sent_and_followed() {
+        let hub = example_hub();
+        let right_now = Local::now();
+        let stop_time = right_now + Duration::hours(6);
+
+        let mut plans = plan_sessions(&hub).await;
+        assert_eq!(plans.len(), 2);
+        clamp_plans(&mut plans, BOUNDS);
+        fit_to_site_limit(&mut plans, 150_000.0, right_now, stop_time, BOUNDS, &false);
+        let planned_w: f32 = plans.iter().map(|plan| plan.charge_rate).sum();
+        assert!(planned_w <= 150_000.0 + 1.0, "planned {planned_w}W over the 150kW site limit");
+
+        let mut profile_registry = ProfileRegistry::new(1..=3);
+        for plan in &plans {
+            let owned_profile = profile_registry.profile_for(&plan.charger_id, plan.connector_id, plan.transaction_id).unwrap();
+            let (start_periods, charge_rates) = plan.schedule(right_now, stop_time, BOUNDS);
+            let sent = create_charge_profile(&hub, &owned_profile, start_periods, charge_rates, stop_time.with_timezone(&Utc), BOUNDS, Instant::now())
+                .await
+                .unwrap();
+            let verification = verify_profile(&hub, &owned_profile, &sent, 3600, 1.0).await;
+            assert!(matches!(verification, Verification::Matches), "{} - {}: {verification:?}", plan.charger_id, plan.connector_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn cleared_profiles_leave_no_schedule() {
+        let hub = example_hub();
+        let right_now = Local::now();
+        let stop_time = right_now + Duration::hours(6);
+
+        let plan = plan_sessions(&hub).await.remove(0);
+        let owned_profile = ProfileRegistry::new(1..=3).profile_for(&plan.charger_id, plan.connector_id, plan.transaction_id).unwrap();
+        let (start_periods, charge_rates) = plan.schedule(right_now, stop_time, BOUNDS);
+        create_charge_profile(&hub, &owned_profile, start_periods, charge_rates, stop_time.with_timezone(&Utc), BOUNDS, Instant::now()).await.unwrap();
+        hub.clear_charge_profile(&owned_profile).await.unwrap();
+
+        let composite = hub.composite_schedule(&plan.charger_id, plan.connector_id, 3600).await.unwrap();
+        assert!(composite.charging_schedule.is_none());
+    }
+}
@@ -0,0 +1,222 @@
+use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, sync::Mutex, time::{Duration, Instant}};
+use crate::{
+    error::Error,
+    get_data::{MeterValueFetch, UnavailableConnector},
+    roster::Roster,
+    session::Session,
+    types::{MeterValue, Transaction}
+};
+
+// OCPP 1.6 ChargePointStatus of a connector
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectorStatus {
+    Available,
+    Preparing,
+    Charging,
+    #[serde(rename = "SuspendedEVSE")]
+    SuspendedEvse,
+    #[serde(rename = "SuspendedEV")]
+    SuspendedEv,
+    Finishing,
+    Reserved,
+    Unavailable,
+    Faulted,
+    #[serde(other)]
+    Unknown,
+}
+
+// StatusNotification pushed by chargerhub when a connector changes status
+#[derive(Debug, Deserialize)]
+pub struct StatusNotification {
+    pub charger_id:   String,
+    pub connector_id: i32,
+    pub status:       ConnectorStatus,
+    pub error_code:   Option<String>,
+    pub timestamp:    Option<DateTime<Utc>>,
+}
+
+// What is known about one connector, from pushes and the reconciliation polls
+#[derive(Debug, Default)]
+struct ConnectorState {
+    status:      Option<ConnectorStatus>,
+    transaction: Option<Transaction>,   // active transaction, None once it ends
+    meter_value: Option<MeterValue>,    // newest meter value reported on the connector
+    unavailable: Option<Error>,         // why the last poll could not read the connector, cleared by a push
+}
+
+#[derive(Default)]
+struct Fleet {
+    chargers:      Vec<String>,   // chargers at the location as of the last poll
+    connectors:    BTreeMap<(String, i32), ConnectorState>,
+    reconciled_at: Option<Instant>,
+}
+
+// The depot's connectors as pushed by chargerhub's webhooks, reconciled with a
+// full poll now and then to recover anything a missed push left behind. The
+// planner reads its sessions from here rather than polling every connector.
+#[derive(Default)]
+pub struct FleetState {
+    fleet: Mutex<Fleet>,
+}
+
+// Sessions the planner can work from along with the connectors it cannot
+#[derive(Default)]
+pub struct FleetSnapshot {
+    pub sessions:    Vec<Session>,
+    pub unavailable: Vec<UnavailableConnector>,
+}
+
+impl FleetState {
+    pub fn new() -> FleetState {
+        FleetState::default()
+    }
+
+    pub fn record_meter_value(&self, meter_value: MeterValue) -> bool {
+        /*
+         * Keep a pushed meter value if it is newer than the one already held for its connector.
+         *
+         * @Output: true if it is the first meter value of the connector's transaction,
+         *          completing a session the planner has not seen yet
+         */
+        let mut fleet = self.fleet.lock().unwrap();
+        let connector = fleet.connectors.entry((meter_value.charger_id.clone(), meter_value.connector_id)).or_default();
+        connector.unavailable = None;
+        let new_session = connector.transaction.as_ref().is_some_and(|transaction| transaction.transaction_id == Some(meter_value.transaction_id))
+            && connector.meter_value.as_ref().is_none_or(|held| held.transaction_id != meter_value.transaction_id);
+        if connector.meter_value.as_ref().is_none_or(|held| held.time_stamp <= meter_value.time_stamp) {
+            connector.meter_value = Some(meter_value);
+        }
+        new_session
+    }
+
+    pub fn record_status(&self, notification: &StatusNotification) {
+        let mut fleet = self.fleet.lock().unwrap();
+        let connector = fleet.connectors.entry((notification.charger_id.clone(), notification.connector_id)).or_default();
+        connector.unavailable = None;
+        connector.status = Some(notification.status);
+    }
+
+    pub fn record_transaction(&self, transaction: Transaction) -> Result<(), Error> {
+        /*
+         * Record a pushed transaction start or stop. A stop only clears the
+         * connector's transaction if it is the one being held.
+         */
+        let charger_id = transaction.charger_id.clone().ok_or(Error::NoData(String::from("transaction has no charger_id")))?;
+        let transaction_id = transaction.transaction_id.ok_or(Error::NoData(String::from("transaction has no transaction_id")))?;
+
+        let mut fleet = self.fleet.lock().unwrap();
+        let connector = fleet.connectors.entry((charger_id, transaction.connector_id)).or_default();
+        connector.unavailable = None;
+        if transaction.is_active() {
+            connector.transaction = Some(transaction);
+        }
+        else if connector.transaction.as_ref().is_some_and(|held| held.transaction_id == Some(transaction_id)) {
+            connector.transaction = None;
+        }
+        Ok(())
+    }
+
+    pub fn reconcile_due(&self, interval: Duration) -> bool {
+        self.fleet
+            .lock()
+            .unwrap()
+            .reconciled_at
+            .is_none_or(|reconciled_at| reconciled_at.elapsed() >= interval)
+    }
+
+    pub fn reconcile_now(&self) {
+        // a charger came or went, poll the location on the loop's next pass
+        self.fleet.lock().unwrap().reconciled_at = None;
+    }
+
+    pub fn reconciled(&self) -> bool {
+        self.fleet.lock().unwrap().reconciled_at.is_some()
+    }
+
+    pub fn reconcile(&self, chargers: Vec<String>, fetch: MeterValueFetch) {
+        /*
+         * Overwrite the pushed state with a full poll of the location. The poll is
+         * what chargerhub holds, so it wins over pushed transactions and statuses,
+         * though a meter value pushed since the poll read the connector is kept.
+         */
+        let mut fleet = self.fleet.lock().unwrap();
+        fleet.chargers = chargers;
+        fleet.reconciled_at = Some(Instant::now());
+
+        for (meter_value, transaction) in fetch.sessions {
+            let connector = fleet.connectors.entry((meter_value.charger_id.clone(), meter_value.connector_id)).or_default();
+            connector.status = None;
+            connector.unavailable = None;
+            connector.transaction = Some(transaction);
+            if connector.meter_value.as_ref().is_none_or(|held| held.time_stamp <= meter_value.time_stamp) {
+                connector.meter_value = Some(meter_value);
+            }
+        }
+        for (charger_id, connector_id) in fetch.idle {
+            let connector = fleet.connectors.entry((charger_id, connector_id)).or_default();
+            connector.unavailable = None;
+            connector.transaction = None;
+        }
+        for unavailable in fetch.unavailable {
+            let connector = fleet.connectors.entry((unavailable.charger_id, unavailable.connector_id)).or_default();
+            connector.unavailable = Some(unavailable.reason);
+        }
+    }
+
+    pub fn charging_loads(&self) -> Vec<(String, DateTime<Utc>, f32)> {
+        /*
+         * What each connector with an active transaction last reported drawing,
+         * along with its charger and when it was measured, for reporting the
+         * depot's load to the grid operator.
+         */
+        let fleet = self.fleet.lock().unwrap();
+        fleet.connectors
+            .iter()
+            .filter(|(_, connector)| connector.status != Some(ConnectorStatus::Available))
+            .filter_map(|((charger_id, _), connector)| match (&connector.transaction, &connector.meter_value) {
+                (Some(transaction), Some(meter_value)) if transaction.transaction_id == Some(meter_value.transaction_id) => meter_value
+                    .active_power_w()
+                    .map(|power_w| (charger_id.clone(), meter_value.time_stamp, power_w)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn snapshot(&self, roster: &Roster) -> FleetSnapshot {
+        /*
+         * The active session on each connector of the location's chargers, joining
+         * the transaction held for it with the newest meter value reported for that
+         * transaction. Connectors the last poll could not read, or which report
+         * themselves faulted or unavailable, are returned as unavailable.
+         */
+        let fleet = self.fleet.lock().unwrap();
+        let mut snapshot = FleetSnapshot::default();
+
+        for ((charger_id, connector_id), connector) in &fleet.connectors {
+            if !fleet.chargers.contains(charger_id) {
+                continue;
+            }
+            let unavailable = match (&connector.unavailable, connector.status) {
+                (Some(reason), _) => Some(reason.clone()),
+                (None, Some(status @ (ConnectorStatus::Faulted | ConnectorStatus::Unavailable))) => Some(Error::NoData(format!("connector reported {status:?}"))),
+                _ => None,
+            };
+            if let Some(reason) = unavailable {
+                snapshot.unavailable.push(UnavailableConnector { charger_id: charger_id.clone(), connector_id: *connector_id, reason });
+                continue;
+            }
+            // a connector back to Available has been unplugged even if the stop has not arrived yet
+            if connector.status == Some(ConnectorStatus::Available) {
+                continue;
+            }
+            if let (Some(transaction), Some(meter_value)) = (&connector.transaction, &connector.meter_value) {
+                if transaction.transaction_id == Some(meter_value.transaction_id) {
+                    snapshot.sessions.push(Session::new(meter_value.clone(), transaction.clone(), roster));
+                }
+            }
+        }
+        snapshot
+    }
+}
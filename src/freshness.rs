@@ -0,0 +1,107 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use crate::types::ChargingBounds;
+
+// How current a charger's meter values have been over the life of the service
+#[derive(Debug, Default)]
+pub struct ChargerFreshness {
+    pub fresh_readings: u32,
+    pub stale_readings: u32,
+    pub newest_reading: Option<DateTime<Utc>>,
+    pub max_age:        Duration,
+}
+
+// Sorts meter values into fresh and stale by the age of their time stamp and
+// keeps per charger statistics on how often each one reports late
+pub struct FreshnessTracker {
+    max_age:  Duration,
+    chargers: HashMap<String, ChargerFreshness>,
+}
+
+impl FreshnessTracker {
+    pub fn new(max_age: Duration) -> FreshnessTracker {
+        FreshnessTracker {
+            max_age,
+            chargers: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, charger_id: &str, time_stamp: DateTime<Utc>, right_now: DateTime<Utc>) -> Option<Duration> {
+        /*
+         * Record a meter value's age against its charger.
+         *
+         * @Output: The age of the meter value if it is older than the threshold,
+         *          None if it is fresh
+         */
+        let age = (right_now - time_stamp).max(Duration::zero());
+        let stats = self.chargers.entry(charger_id.to_owned()).or_default();
+        stats.newest_reading = stats.newest_reading.max(Some(time_stamp));
+        stats.max_age = stats.max_age.max(age);
+
+        if age > self.max_age {
+            stats.stale_readings += 1;
+            Some(age)
+        } else {
+            stats.fresh_readings += 1;
+            None
+        }
+    }
+
+    pub fn report(&self, right_now: DateTime<Utc>) {
+        /*
+         * Print each charger's freshness statistics.
+         */
+        let mut charger_ids: Vec<&String> = self.chargers.keys().collect();
+        charger_ids.sort();
+        for charger_id in charger_ids {
+            let stats = &self.chargers[charger_id];
+            let total = stats.fresh_readings + stats.stale_readings;
+            println!("  {}: newest reading {}s old, {}/{} readings fresh, oldest seen {}s",
+                charger_id,
+                stats.newest_reading.map(|newest| (right_now - newest).num_seconds()).unwrap_or_default(),
+                stats.fresh_readings,
+                total,
+                stats.max_age.num_seconds()
+            );
+        }
+    }
+}
+
+pub fn stale_charge_rate(configured: Option<f32>, bounds: &ChargingBounds) -> f32 {
+    /*
+     * The rate to plan a connector at while its meter values are stale. Without
+     * a current reading neither the SOC nor the load is known, so it charges at
+     * STALE_CHARGE_RATE, or the window's lower bound when that is not set.
+     */
+    configured.unwrap_or(bounds.lower_bnd as f32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readings_older_than_the_threshold_are_stale() {
+        let right_now = Utc::now();
+        let mut freshness = FreshnessTracker::new(Duration::minutes(15));
+
+        assert_eq!(freshness.check("CH1", right_now - Duration::minutes(15), right_now), None);
+        assert_eq!(freshness.check("CH1", right_now - Duration::minutes(20), right_now), Some(Duration::minutes(20)));
+        // a charger clock running ahead does not make a reading stale
+        assert_eq!(freshness.check("CH2", right_now + Duration::minutes(5), right_now), None);
+
+        let ch1 = &freshness.chargers["CH1"];
+        assert_eq!((ch1.fresh_readings, ch1.stale_readings), (1, 1));
+        assert_eq!(ch1.newest_reading, Some(right_now - Duration::minutes(15)));
+        assert_eq!(ch1.max_age, Duration::minutes(20));
+        assert_eq!(freshness.chargers["CH2"].max_age, Duration::zero());
+    }
+
+    #[test]
+    fn stale_connectors_charge_at_the_configured_rate_or_the_lower_bound() {
+        let bounds = ChargingBounds { lower_bnd: 6_000, upper_bnd: 60_000 };
+        assert_eq!(stale_charge_rate(Some(10_000.0), &bounds), 10_000.0);
+        assert_eq!(stale_charge_rate(None, &bounds), 6_000.0);
+    }
+}
@@ -1,48 +1,19 @@
-use reqwest::{Error, Client, header::{HeaderValue, CONTENT_TYPE, AUTHORIZATION}};
 use chrono::Duration;
-use serde_json::json;
+use std::sync::Arc;
+use tokio::{sync::Semaphore, task::JoinSet};
 use crate::{
-    types::{Charger, MeterValue},
+    chargerhub::ChargerHubApi,
+    error::Error,
+    types::{Charger, MeterValue, Transaction},
     util::is_meterval_active
 };
 
-pub async fn get_chargers(client: &Client, req_url: &str, location_id: i32, verbose_mode: &bool, auth_key: &String) -> Result<Vec<Charger>, Error> {
+pub async fn get_chargers(hub: &impl ChargerHubApi, location_id: i32, verbose_mode: &bool) -> Result<Vec<Charger>, Error> {
     /*
      * Get all chargers and parse their output to find chargers with the desired location id and an
      * active transaction
      **/
-    let mut charger_url_path: String = req_url.to_owned();
-    charger_url_path.push_str("/data/chargers");
-
-    // Create a HeaderValue for the Authorization header
-    let auth_header_value = HeaderValue::from_str(&format!("Bearer {}", auth_key))
-        .map_err(|err| {
-            eprintln!("Invalid header value: {}", err);
-        }).unwrap();
-
-
-    // Build the request
-    let request = client
-        .get(&charger_url_path)
-        .header(AUTHORIZATION, auth_header_value)
-        .header(CONTENT_TYPE, "application/json")
-        .body("{}")
-        .build()?;
-    // Print headers before sending the request
-    if *verbose_mode {
-        println!("Headers Sent:");
-        for (key, value) in request.headers().iter() {
-            println!("{}: {:?}", key, value);
-        }
-    }
-    // Send the request
-    let res = client.execute(request).await?;
-    // let res = client.get(&charger_url_path).send().await?;
-    let body = res.text().await?;
-    let chargers: Vec<Charger> = serde_json::from_str(&body).unwrap_or_else(|err|{
-        eprintln!("unable to unwrap, {}\nError: {}", &body, err);
-        Vec::new()
-    });
+    let chargers = hub.chargers().await?;
     let only_relevant_chargers: Vec<Charger> = chargers
         .into_iter()
         .filter(|charger| {
@@ -61,60 +32,78 @@ pub async fn get_chargers(client: &Client, req_url: &str, location_id: i32, verb
 }
 
 
-pub async fn get_meter_values(client: &Client, req_url: &String, chargers: Vec<Charger>, verbose_mode: &bool, auth_key: &String) -> Result<Vec<MeterValue>, Error>{
-    /*
-     * given a list of chargers, return the most recent meter values for all connectors from the charger
-     */
-
-    let mut meter_values: Vec<MeterValue> = Vec::new();
-
-    let mut metervalues_url_path: String = req_url.to_owned();
-    metervalues_url_path.push_str("/data/meter-values");
-    for charger in chargers {
-        for connector in 1..3{ // for each connector
-            println!("Checking connector {}", connector);
-
-            let auth_header_value = HeaderValue::from_str(&format!("Bearer {}", auth_key))
-                .map_err(|err| {
-                    eprintln!("Invalid header value: {}", err);
-                }).unwrap();
-
-
-
-
-            let res = client
-                    .get(&metervalues_url_path)
-                    .header(AUTHORIZATION, auth_header_value)
-                    .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-                    .body(
-                        json!({
-                            "charger_id": charger.id,
-                            "descending": true,
-                            "limit": 1, // one meter val for each connector
-                            "connector_id": connector
-                        }).to_string()
-                    )
-                    .send()
-                    .await?;
+// A connector whose meter values or transaction could not be read this cycle
+#[derive(Debug)]
+pub struct UnavailableConnector {
+    pub charger_id:   String,
+    pub connector_id: i32,
+    pub reason:       Error,
+}
 
+pub struct MeterValueFetch {
+    pub sessions:    Vec<(MeterValue, Transaction)>,   // newest meter value of each active transaction
+    pub idle:        Vec<(String, i32)>,               // connectors read without an active transaction
+    pub unavailable: Vec<UnavailableConnector>,
+}
 
-                
-                let res_body = res.text().await?;
+pub async fn get_meter_values<H: ChargerHubApi>(hub: &Arc<H>, chargers: Vec<Charger>, concurrency: usize, verbose_mode: &bool) -> MeterValueFetch {
+    /*
+     * given a list of chargers, return the active session on each of their connectors, joining the
+     * most recent meter value with the transaction it belongs to. Connectors are fetched
+     * concurrently, at most `concurrency` at a time, and a connector which fails or times out is
+     * reported as unavailable rather than failing the whole fetch.
+     */
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut requests = JoinSet::new();
 
-                let meter_val: Vec<MeterValue> = serde_json::from_str(&res_body).unwrap();
-                if is_meterval_active(req_url, client, &meter_val[0], verbose_mode, auth_key).await {
-                    meter_values.push(meter_val[0].clone());
-                }
+    for (index, charger) in chargers.iter().enumerate() {
+        for connector in 1..3 { // for each connector
+            let hub = hub.clone();
+            let charger_id = charger.id.clone();
+            let permits = permits.clone();
+            let verbose_mode = *verbose_mode;
 
-                if *verbose_mode {
-                    println!("{:#?}", meter_val);
+            requests.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                if verbose_mode {
+                    println!("Checking {} connector {}", charger_id, connector);
                 }
-            };
+                let result = get_connector_session(hub.as_ref(), &charger_id, connector, verbose_mode).await;
+                (index, connector, charger_id, result)
+            });
+        }
+    }
 
+    let mut results = requests.join_all().await;
+    results.sort_by_key(|(index, connector, _, _)| (*index, *connector));
 
+    let mut fetch = MeterValueFetch { sessions: Vec::new(), idle: Vec::new(), unavailable: Vec::new() };
+    for (_, connector_id, charger_id, result) in results {
+        match result {
+            Ok(Some(session)) => fetch.sessions.push(session),
+            Ok(None) => fetch.idle.push((charger_id, connector_id)),
+            Err(reason) => fetch.unavailable.push(UnavailableConnector { charger_id, connector_id, reason }),
         }
-        
-    Ok(meter_values)
+    }
+    fetch
+}
+
+
+async fn get_connector_session(
+    hub: &impl ChargerHubApi,
+    charger_id: &str,
+    connector: i32,
+    verbose_mode: bool) -> Result<Option<(MeterValue, Transaction)>, Error>
+{
+    /*
+     * Fetch a connector's latest meter value and, if it has one, the transaction
+     * it belongs to. None if the connector has no meter values or the transaction has ended.
+     */
+    let Some(meter_val) = hub.latest_meter_value(charger_id, connector).await? else {
+        return Ok(None);
+    };
+    let transaction = is_meterval_active(hub, &meter_val, &verbose_mode).await?;
+    Ok(transaction.map(|transaction| (meter_val, transaction)))
 }
 
 
@@ -134,18 +123,171 @@ pub async fn get_charge_rate(time_allotment: Duration, charge_amount: i8, batter
      *          battery_capacity - total capacity of the battery of the vehicle being passed in
      *          verbose_mode     - display debug statements if true
      *
-     * @Output: Needed charge rate in watts
+     * @Output: Needed charge rate in watts, infinite if there is no time left
+     *          and the battery still needs charge
      */
 
-    let charge_rate = ((charge_amount as f32 / 100.0) * (*battery_capacity as f32)) / 
-                           (time_allotment.num_minutes() as f32 / 60.0);
+    let hours = time_allotment.num_seconds() as f32 / 3600.0;
+    if hours <= 0.0 {
+        return if charge_amount > 0 { f32::INFINITY } else { 0.0 };
+    }
+
+    let charge_rate = ((charge_amount as f32 / 100.0) * (*battery_capacity as f32)) / hours;
 
     if *verbose_mode {
-        println!("charge rate {}Kw calculated for charging +{}% over {} hours", charge_rate, charge_amount, (time_allotment.num_minutes() as f32 / 60.0));
+        println!("charge rate {}Kw calculated for charging +{}% over {} hours", charge_rate, charge_amount, hours);
     }
     charge_rate * 1000.0
 }
 
 
+pub async fn get_energy_charge_rate(time_allotment: Duration, energy_needed: f32, verbose_mode: &bool) -> f32 {
+    /*
+     * Given the energy a bus still needs, determine the rate of charge needed
+     * to deliver it by the desired time. Used for buses which do not report SOC.
+     *
+     * charge_rate = energy_needed / time_allotment
+     *
+     * @Input:  time_allotment - amount of time vehicle will have to charge
+     *          energy_needed  - energy still to be delivered in watt hours
+     *          verbose_mode   - display debug statements if true
+     *
+     * @Output: Needed charge rate in watts, infinite if there is no time left
+     *          and energy is still needed
+     */
+
+    let hours = time_allotment.num_seconds() as f32 / 3600.0;
+    if hours <= 0.0 {
+        return if energy_needed > 0.0 { f32::INFINITY } else { 0.0 };
+    }
 
+    let charge_rate = energy_needed / hours;
 
+    if *verbose_mode {
+        println!("charge rate {}W calculated for delivering {}Wh over {} hours", charge_rate, energy_needed, hours);
+    }
+    charge_rate
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::sleep;
+    use crate::{
+        circuit_breaker::Degraded,
+        profile_registry::OwnedProfile,
+        types::{ChargeProfile, CommunicationType, CompositeSchedule}
+    };
+
+    // A depot where CH1 connector 1 is charging, CH1 connector 2's meter value is from an
+    // earlier session, CH2 connector 1 has never reported and CH2 connector 2 times out
+    #[derive(Default)]
+    struct Depot {
+        in_flight:      AtomicUsize,
+        most_in_flight: AtomicUsize,
+    }
+
+    impl ChargerHubApi for Depot {
+        async fn chargers(&self) -> Result<Vec<Charger>, Error> {
+            unreachable!()
+        }
+
+        async fn latest_meter_value(&self, charger_id: &str, connector_id: i32) -> Result<Option<MeterValue>, Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            sleep(std::time::Duration::from_millis(20)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            let meter_value = |transaction_id| Ok(Some(MeterValue {
+                connector_id,
+                charger_id:    charger_id.to_owned(),
+                transaction_id,
+                time_stamp:    Utc::now(),
+                sampled_value: Vec::new(),
+            }));
+            match (charger_id, connector_id) {
+                ("CH1", 1) => meter_value(11),
+                ("CH1", _) => meter_value(12),
+                ("CH2", 1) => Ok(None),
+                _ => Err(Error::Transport { context: String::from("unable to fetch meter values"), message: String::new(), timed_out: true }),
+            }
+        }
+
+        async fn latest_transaction(&self, charger_id: &str, connector_id: i32) -> Result<Option<Transaction>, Error> {
+            Ok(Some(Transaction {
+                connector_id,
+                id_tag:          String::from("BUS1"),
+                meter_start:     0,
+                timestamp_start: Utc::now(),
+                transaction_id:  Some(if connector_id == 1 { 11 } else { 13 }),
+                meter_stop:      None,
+                timestamp_stop:  None,
+                stop_reason:     None,
+                charger_id:      Some(charger_id.to_owned()),
+                voided:          None,
+            }))
+        }
+
+        async fn set_charge_profile(&self, _profile: &ChargeProfile, _valid_to: DateTime<Utc>) -> Result<(), Error> {
+            unreachable!()
+        }
+
+        async fn clear_charge_profile(&self, _profile: &OwnedProfile) -> Result<(), Error> {
+            unreachable!()
+        }
+
+        async fn composite_schedule(&self, _charger_id: &str, _connector_id: i32, _duration: i32) -> Result<CompositeSchedule, Error> {
+            unreachable!()
+        }
+
+        fn degraded(&self) -> Option<Degraded> {
+            None
+        }
+    }
+
+    fn charger(id: &str) -> Charger {
+        Charger {
+            id:                  id.to_owned(),
+            charger_name:        id.to_owned(),
+            location_id:         Some(7),
+            communicate_through: CommunicationType::RustDirectOcpp,
+            latitude:            None,
+            longitude:           None,
+            created_at:          Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn connectors_are_fetched_concurrently_and_failures_reported_as_unavailable() {
+        let depot = Arc::new(Depot::default());
+        let fetch = get_meter_values(&depot, vec![charger("CH1"), charger("CH2")], 2, &false).await;
+
+        assert_eq!(fetch.sessions.len(), 1);
+        assert_eq!((fetch.sessions[0].0.charger_id.as_str(), fetch.sessions[0].1.transaction_id), ("CH1", Some(11)));
+        assert_eq!(fetch.idle, vec![(String::from("CH1"), 2), (String::from("CH2"), 1)]);
+        assert_eq!(fetch.unavailable.len(), 1);
+        assert_eq!((fetch.unavailable[0].charger_id.as_str(), fetch.unavailable[0].connector_id), ("CH2", 2));
+        assert!(matches!(fetch.unavailable[0].reason, Error::Transport { timed_out: true, .. }));
+
+        // at most two connectors were read at a time, but they were not read one by one
+        assert_eq!(depot.most_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn energy_targets_are_spread_over_the_time_left() {
+        assert_eq!(get_energy_charge_rate(Duration::hours(4), 160_000.0, &false).await, 40_000.0);
+        assert_eq!(get_energy_charge_rate(Duration::minutes(30), 10_000.0, &false).await, 20_000.0);
+    }
+
+    #[tokio::test]
+    async fn targets_past_their_deadline_need_an_infinite_rate() {
+        assert_eq!(get_charge_rate(Duration::hours(2), 20, &400, &false).await, 40_000.0);
+        assert_eq!(get_charge_rate(Duration::zero(), 20, &400, &false).await, f32::INFINITY);
+        assert_eq!(get_charge_rate(Duration::minutes(-5), 0, &400, &false).await, 0.0);
+        assert_eq!(get_energy_charge_rate(Duration::zero(), 10_000.0, &false).await, f32::INFINITY);
+        assert_eq!(get_energy_charge_rate(Duration::zero(), 0.0, &false).await, 0.0);
+    }
+}
@@ -1,51 +1,122 @@
 mod run_loop;
 mod get_data;
 mod send_data;
+mod circuit_breaker;
+mod chargerhub;
+mod fake_chargerhub;
+mod ocpp_central_system;
 mod util;
+mod error;
 mod types;
+mod sampled_value;
+mod session;
+mod config;
+mod roster;
+mod planner;
+mod topology;
+mod site_meter;
+mod phases;
+mod soc_estimator;
+mod freshness;
+mod fleet_state;
+mod demand_response;
+mod price_curve;
+mod openadr_ven;
+mod api;
+mod alerts;
+mod profile_registry;
+mod verification;
+#[cfg(test)]
+mod test_http;
 
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use reqwest::{Error, Client};
+use reqwest::Client;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use crate::api::{start_api, ApiState};
+use crate::chargerhub::ChargerHubClient;
+use crate::config::{Config, HubSource};
+use crate::demand_response::{parse_cli, DrEventStore};
+use crate::error::Error;
+use crate::fake_chargerhub::FakeChargerHub;
+use crate::fleet_state::FleetState;
+use crate::ocpp_central_system::OcppCentralSystem;
+use crate::openadr_ven::OpenAdrVen;
+use crate::price_curve::PriceCurve;
 use crate::run_loop::runner_loop;
 
 #[tokio::main]
-async fn main() -> Result<(), Error>{
-    let chargerhub_url = dotenv::var("CHARGERHUB_URL")
-        .expect("CHARGERHUB_URL was not specified in .env")
-        .parse::<String>()
-        .expect("Something went catastrophically wrong with parsing the chargerhub URL.");
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
 
-    let battery_capacity = dotenv::var("BATTERY_CAPACITY")
-        .expect("BATTERY_CAPACITY was not specified in .env")
-        .parse::<i32>()
-        .expect("Something went wrong reading in the battery capacity. Please verify BATTERY_CAPACITY is of type i32");
+async fn run() -> Result<(), Error> {
+    // Command line commands only need the events file, not the full configuration
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        let dr_events_file = dotenv::var("DR_EVENTS_FILE")
+            .map_err(|_| Error::Config(String::from("DR_EVENTS_FILE must be specified in .env to manage demand response events from the command line")))?;
+        if !parse_cli(&args, &DrEventStore::new(Some(dr_events_file)))? {
+            eprintln!("Unknown command {}, the only command is dr-event", args[0]);
+        }
+        return Ok(());
+    }
 
-    let desired_soc = dotenv::var("DESIRED_SOC")
-        .expect("DESIRED_SOC was not specified in .env")
-        .parse::<i8>()
-        .expect("Something went wrong reading in the desired SOC. Please verify DESIRED_SOC is of type i8");
+    let config = Config::from_env()?;
 
-    let verbose_mode = dotenv::var("VERBOSE_MODE")
-        .expect("VERBOSE_MODE was not specified in .env")
-        .parse::<bool>()
-        .unwrap_or(false); 
+    // Alerts go to a third party webhook, so they get a client without chargerhub's authorization header
+    let client = Client::builder()
+        .connect_timeout(config.connect_timeout)
+        .read_timeout(config.read_timeout)
+        .build()
+        .map_err(|err| Error::Config(format!("Unable to build the alert client: {err}")))?;
 
-    let authorization_header = dotenv::var("AUTHORIZATION_HEADER")
-        .expect("Must define AUTHORIZATION_HEADER in the .env");
+    let dr_events = Arc::new(DrEventStore::new(config.dr_events_file.clone()));
+    let fleet = Arc::new(FleetState::new());
+    let wake = Arc::new(Notify::new());
+    let prices = Arc::new(PriceCurve::new());
 
-    println!("{authorization_header}");
+    if let Some(api) = &config.api {
+        start_api(&api.bind_addr, ApiState { dr_events: dr_events.clone(), fleet: fleet.clone(), wake: wake.clone(), token: api.token.clone() })?;
+    }
 
+    if let Some(openadr) = &config.openadr {
+        println!("Running as an OpenADR VEN against {}", openadr.vtn_url);
+        OpenAdrVen::start(&config, openadr, dr_events.clone(), prices.clone(), fleet.clone(), wake.clone())?;
+    }
 
-    let client = Client::builder()
-        .default_headers({
-            let mut headers = HeaderMap::new();
-            headers.insert(AUTHORIZATION, HeaderValue::from_str(&authorization_header.to_owned()).unwrap());
-            headers
-        })
-        .build().unwrap();
-    
+    match &config.hub {
+        HubSource::FakeFile(fake_file) => {
+            println!("Running against the fake chargerhub in {fake_file}");
+            let hub = Arc::new(FakeChargerHub::from_file(fake_file)?);
+            runner_loop(&client, &hub, &config, &dr_events, &prices, &fleet, &wake).await;
+        }
+        HubSource::OcppCentralSystem { bind_addr, charge_points, transaction_id_file } => {
+            println!("Running as the OCPP central system on {bind_addr}");
+            let hub = OcppCentralSystem::start(bind_addr, charge_points, transaction_id_file, &config, fleet.clone(), wake.clone()).await?;
+            runner_loop(&client, &hub, &config, &dr_events, &prices, &fleet, &wake).await;
+        }
+        HubSource::ChargerHub { url, authorization_header } => {
+            let header_value = HeaderValue::from_str(authorization_header)
+                .map_err(|err| Error::Config(format!("AUTHORIZATION_HEADER is not a valid header value: {err}")))?;
+            let chargerhub_client = Client::builder()
+                .default_headers({
+                    let mut headers = HeaderMap::new();
+                    headers.insert(AUTHORIZATION, header_value);
+                    headers
+                })
+                .connect_timeout(config.connect_timeout)
+                .read_timeout(config.read_timeout)
+                .build()
+                .map_err(|err| Error::Config(format!("Unable to build the chargerhub client: {err}")))?;
 
-    runner_loop(&client, &chargerhub_url, &battery_capacity, &desired_soc, &verbose_mode, &authorization_header).await;
+            let hub = Arc::new(ChargerHubClient::new(chargerhub_client, url, authorization_header, &config));
+            runner_loop(&client, &hub, &config, &dr_events, &prices, &fleet, &wake).await;
+        }
+    }
     
     Ok(())
 }
@@ -0,0 +1,937 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use futures_util::{SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+use tokio::{net::{TcpListener, TcpStream}, sync::{mpsc, oneshot, Notify}, time::timeout};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http::{HeaderValue, StatusCode}, Message}
+};
+use crate::{
+    chargerhub::ChargerHubApi,
+    circuit_breaker::Degraded,
+    config::Config,
+    error::{ChargerStatus, Error},
+    fleet_state::{ConnectorStatus, FleetState, StatusNotification},
+    profile_registry::OwnedProfile,
+    sampled_value::SampledValue,
+    send_data::SEND_TIMEOUT,
+    types::{Charger, ChargeProfile, ChargingSchedule, ChargingSchedulePeriod, CommunicationType, CompositeSchedule, MeterValue, Transaction}
+};
+
+// OCPP-J message type ids, the first element of every frame
+const CALL: u64 = 2;
+const CALL_RESULT: u64 = 3;
+const CALL_ERROR: u64 = 4;
+
+const SUBPROTOCOL: &str = "ocpp1.6";
+
+// A charge point with an open WebSocket to the central system
+struct ChargePoint {
+    connection: u64,                                                   // tells a reconnect apart from the socket it replaced
+    outbound:   mpsc::UnboundedSender<Message>,
+    pending:    HashMap<String, oneshot::Sender<Result<Value, Error>>>,  // calls awaiting the charger's answer, by message id
+    calls:      Arc<tokio::sync::Mutex<()>>,                           // OCPP allows one outstanding call per connection
+    model:      Option<String>,
+    booted_at:  DateTime<Utc>,
+}
+
+// What the central system has been told about a connector
+#[derive(Default)]
+struct ConnectorRecord {
+    meter_value: Option<MeterValue>,
+    transaction: Option<Transaction>,   // the last transaction started, stopped once StopTransaction arrives
+}
+
+// Transaction ids handed to chargers. The last one is kept in a file so a
+// restart carries on from it, rather than handing out an id a charger still holds
+struct TransactionIds {
+    path: String,
+    last: Mutex<i32>,
+}
+
+impl TransactionIds {
+    fn load(path: &str) -> Result<TransactionIds, Error> {
+        // a missing file means no transaction has been started yet
+        let last = match std::fs::read_to_string(path) {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .map_err(|err| Error::Config(format!("Unable to parse the last transaction id in {path}: {err}")))?,
+            Err(_) => 0,
+        };
+        Ok(TransactionIds { path: path.to_owned(), last: Mutex::new(last) })
+    }
+
+    fn next(&self) -> Result<i32, Error> {
+        /*
+         * The id for a new transaction, wrapping back to 1 after i32::MAX. It is
+         * only handed out once it has been written to the file.
+         */
+        let mut last = self.last.lock().unwrap();
+        let transaction_id = if *last == i32::MAX { 1 } else { *last + 1 };
+        self.write(transaction_id)?;
+        *last = transaction_id;
+        Ok(transaction_id)
+    }
+
+    fn observe(&self, transaction_id: i32) {
+        // an id a charger reported, which must not be handed out again
+        let mut last = self.last.lock().unwrap();
+        if transaction_id > *last {
+            if let Err(err) = self.write(transaction_id) {
+                eprintln!("{err}");
+            }
+            *last = transaction_id;
+        }
+    }
+
+    fn write(&self, transaction_id: i32) -> Result<(), Error> {
+        std::fs::write(&self.path, transaction_id.to_string())
+            .map_err(|err| Error::Config(format!("Unable to write the last transaction id to {}: {err}", self.path)))
+    }
+}
+
+// An OCPP 1.6J central system for sites without chargerhub. Chargers connect
+// to ws://OCPP_BIND_ADDR/ocpp/{charge point id}, what they report is pushed
+// into the fleet state, and profiles are sent to them directly. Only the charge
+// points in OCPP_CHARGE_POINTS may connect, with HTTP Basic auth for those
+// given a password (OCPP security profile 1).
+pub struct OcppCentralSystem {
+    allowed:             HashMap<String, Option<String>>,   // charge point id to its Basic auth password
+    location_id:         i32,
+    heartbeat_interval:  Duration,
+    verbose_mode:        bool,
+    fleet:               Arc<FleetState>,
+    wake:                Arc<Notify>,
+    charge_points:       Mutex<HashMap<String, ChargePoint>>,
+    connectors:          Mutex<HashMap<(String, i32), ConnectorRecord>>,
+    next_connection:     AtomicU64,
+    next_message_id:     AtomicU64,
+    transaction_ids:     TransactionIds,
+}
+
+// BootNotification.req
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BootNotification {
+    charge_point_vendor: String,
+    charge_point_model:  String,
+}
+
+// StatusNotification.req
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OcppStatusNotification {
+    connector_id: i32,
+    error_code:   String,
+    status:       ConnectorStatus,
+    timestamp:    Option<DateTime<Utc>>,
+}
+
+// MeterValues.req
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MeterValues {
+    connector_id:   i32,
+    transaction_id: Option<i32>,
+    meter_value:    Vec<OcppMeterValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OcppMeterValue {
+    timestamp:     DateTime<Utc>,
+    sampled_value: Vec<SampledValue>,
+}
+
+// StartTransaction.req
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StartTransaction {
+    connector_id: i32,
+    id_tag:       String,
+    meter_start:  i32,
+    timestamp:    DateTime<Utc>,
+}
+
+// StopTransaction.req
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StopTransaction {
+    transaction_id: i32,
+    meter_stop:     i32,
+    timestamp:      DateTime<Utc>,
+    reason:         Option<String>,
+}
+
+// GetCompositeSchedule.conf
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OcppCompositeSchedule {
+    status:            String,
+    charging_schedule: Option<OcppChargingSchedule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OcppChargingSchedule {
+    charging_schedule_period: Vec<OcppChargingSchedulePeriod>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OcppChargingSchedulePeriod {
+    start_period: i32,
+    limit:        f32,
+}
+
+// The error code and description of a CALLERROR answering a charger's call
+type CallError = (&'static str, String);
+
+impl OcppCentralSystem {
+    pub fn parse_charge_points(charge_points: &str) -> Result<HashMap<String, Option<String>>, Error> {
+        /*
+         * Parse OCPP_CHARGE_POINTS, a comma separated list of the charge point ids
+         * allowed to connect, each with an optional Basic auth password after a
+         * colon IE "CH1:secret1,CH2:secret2,CH3".
+         */
+        let allowed = charge_points
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| match entry.split_once(':') {
+                Some((id, password)) if !password.is_empty() => (id.trim().to_owned(), Some(password.to_owned())),
+                Some((id, _)) => (id.trim().to_owned(), None),
+                None => (entry.trim().to_owned(), None),
+            })
+            .collect::<HashMap<String, Option<String>>>();
+        if allowed.is_empty() || allowed.contains_key("") {
+            return Err(Error::Config(format!("Unable to parse OCPP_CHARGE_POINTS \"{charge_points}\", expected charge_point_id[:password] entries")));
+        }
+        Ok(allowed)
+    }
+
+    pub async fn start(
+        bind_addr: &str,
+        allowed: &HashMap<String, Option<String>>,
+        transaction_id_file: &str,
+        config: &Config,
+        fleet: Arc<FleetState>,
+        wake: Arc<Notify>) -> Result<Arc<OcppCentralSystem>, Error>
+    {
+        /*
+         * Listen for charge point connections on their own task, serving each
+         * connection on a task of its own.
+         */
+        let listener = TcpListener::bind(bind_addr)
+            .await
+            .map_err(|err| Error::Config(format!("Unable to start the OCPP central system on OCPP_BIND_ADDR {bind_addr}: {err}")))?;
+
+        let central_system = Arc::new(OcppCentralSystem {
+            allowed:             allowed.clone(),
+            location_id:         config.location_id,
+            heartbeat_interval:  config.ocpp_heartbeat_interval,
+            verbose_mode:        config.verbose_mode,
+            fleet,
+            wake,
+            charge_points:       Mutex::new(HashMap::new()),
+            connectors:          Mutex::new(HashMap::new()),
+            next_connection:     AtomicU64::new(0),
+            next_message_id:     AtomicU64::new(0),
+            transaction_ids:     TransactionIds::load(transaction_id_file)?,
+        });
+        central_system.clone().accept(listener);
+        Ok(central_system)
+    }
+
+    fn accept(self: Arc<Self>, listener: TcpListener) {
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let central_system = self.clone();
+                        tokio::spawn(async move { central_system.serve(stream).await });
+                    }
+                    Err(err) => eprintln!("Unable to accept an OCPP connection: {err}"),
+                }
+            }
+        });
+    }
+
+    async fn serve(self: Arc<Self>, stream: TcpStream) {
+        /*
+         * Run one charge point's connection, answering its calls and routing the
+         * answers to ours, until the socket closes.
+         */
+        let mut path_id = None;
+        #[allow(clippy::result_large_err)]   // the callback's signature is tungstenite's
+        let handshake = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+            path_id = request.uri().path().rsplit('/').next().filter(|id| !id.is_empty()).map(str::to_owned);
+            let offers_ocpp16 = request.headers()
+                .get_all("Sec-WebSocket-Protocol")
+                .iter()
+                .filter_map(|protocols| protocols.to_str().ok())
+                .any(|protocols| protocols.split(',').any(|protocol| protocol.trim() == SUBPROTOCOL));
+
+            let Some(id) = path_id.as_deref().filter(|_| offers_ocpp16) else {
+                let mut rejection = ErrorResponse::new(Some(format!("connect to /ocpp/{{charge point id}} with the {SUBPROTOCOL} subprotocol")));
+                *rejection.status_mut() = StatusCode::BAD_REQUEST;
+                return Err(rejection);
+            };
+            if !self.authorized(id, request) {
+                let mut rejection = ErrorResponse::new(Some(String::from("unknown charge point or wrong credentials")));
+                *rejection.status_mut() = StatusCode::UNAUTHORIZED;
+                rejection.headers_mut().insert("WWW-Authenticate", HeaderValue::from_static("Basic realm=\"OCPP\""));
+                return Err(rejection);
+            }
+            response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+            Ok(response)
+        };
+        let socket = match accept_hdr_async(stream, handshake).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                eprintln!("Rejected an OCPP connection: {err}");
+                return;
+            }
+        };
+        let Some(charge_point_id) = path_id else { return };
+
+        let (mut sink, mut incoming) = socket.split();
+        let (outbound, mut outbox) = mpsc::unbounded_channel::<Message>();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outbox.recv().await {
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        println!("Charge point {charge_point_id} connected");
+        self.charge_points.lock().unwrap().insert(charge_point_id.clone(), ChargePoint {
+            connection,
+            outbound:  outbound.clone(),
+            pending:   HashMap::new(),
+            calls:     Arc::new(tokio::sync::Mutex::new(())),
+            model:     None,
+            booted_at: Utc::now(),
+        });
+
+        // after a restart nothing is known of the transactions already under way, so ask the
+        // charger for its connectors' statuses and meter values, which carry their transaction ids
+        let triggering = self.clone();
+        let triggered_id = charge_point_id.clone();
+        tokio::spawn(async move { triggering.trigger_reports(&triggered_id).await });
+
+        while let Some(message) = incoming.next().await {
+            match message {
+                Ok(Message::Text(frame)) => {
+                    if let Some(reply) = self.handle_frame(&charge_point_id, &frame) {
+                        let _ = outbound.send(Message::Text(reply.to_string()));
+                    }
+                }
+                Ok(Message::Close(_)) => break,
+                Ok(_) => {}   // pings are answered by tungstenite
+                Err(err) => {
+                    eprintln!("Charge point {charge_point_id} connection failed: {err}");
+                    break;
+                }
+            }
+        }
+        writer.abort();
+
+        // a charger which reconnected has already replaced this connection
+        let mut charge_points = self.charge_points.lock().unwrap();
+        if charge_points.get(&charge_point_id).is_some_and(|charge_point| charge_point.connection == connection) {
+            charge_points.remove(&charge_point_id);
+            println!("Charge point {charge_point_id} disconnected");
+            self.fleet.reconcile_now();
+            self.wake.notify_one();
+        }
+    }
+
+    async fn trigger_reports(&self, charge_point_id: &str) {
+        for requested_message in ["StatusNotification", "MeterValues"] {
+            let answer: Result<CommandStatus, Error> = self.call(charge_point_id, "TriggerMessage", json!({ "requestedMessage": requested_message })).await;
+            match answer {
+                Ok(answer) if answer.status == "Accepted" => {}
+                Ok(answer) => eprintln!("{charge_point_id} answered the TriggerMessage for {requested_message} with {}", answer.status),
+                Err(err) => eprintln!("Unable to ask {charge_point_id} for its {requested_message}: {err}"),
+            }
+        }
+    }
+
+    fn authorized(&self, charge_point_id: &str, request: &Request) -> bool {
+        /*
+         * Whether a charge point may connect, it must be in OCPP_CHARGE_POINTS and
+         * send its password as HTTP Basic auth if it was given one. The Basic auth
+         * user is the charge point id.
+         */
+        let Some(password) = self.allowed.get(charge_point_id) else {
+            eprintln!("Rejected a connection from unknown charge point {charge_point_id}");
+            return false;
+        };
+        let Some(password) = password else { return true };
+
+        let credentials = request.headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let authorized = credentials.is_some_and(|credentials| credentials == format!("{charge_point_id}:{password}"));
+        if !authorized {
+            eprintln!("Rejected a connection from charge point {charge_point_id} without its credentials");
+        }
+        authorized
+    }
+
+    fn handle_frame(&self, charge_point_id: &str, frame: &str) -> Option<Value> {
+        /*
+         * Handle one OCPP-J frame, returning the reply for a call. Answers to our
+         * own calls are handed to whoever is waiting on them.
+         */
+        if self.verbose_mode {
+            println!("{charge_point_id} -> {frame}");
+        }
+        let frame: Vec<Value> = match serde_json::from_str(frame) {
+            Ok(frame) => frame,
+            Err(err) => {
+                eprintln!("Unable to parse OCPP frame from {charge_point_id}: {err}");
+                return None;
+            }
+        };
+
+        match frame.first().and_then(Value::as_u64) {
+            Some(CALL) => {
+                let Ok((_, message_id, action, payload)) = serde_json::from_value::<(u64, String, String, Value)>(Value::Array(frame)) else {
+                    eprintln!("Malformed OCPP call from {charge_point_id}");
+                    return None;
+                };
+                Some(match self.handle_call(charge_point_id, &action, payload) {
+                    Ok(answer) => json!([CALL_RESULT, message_id, answer]),
+                    Err((code, description)) => {
+                        eprintln!("Answering {action} from {charge_point_id} with {code}: {description}");
+                        json!([CALL_ERROR, message_id, code, description, {}])
+                    }
+                })
+            }
+            Some(CALL_RESULT) => {
+                if let Ok((_, message_id, answer)) = serde_json::from_value::<(u64, String, Value)>(Value::Array(frame)) {
+                    self.answer(charge_point_id, &message_id, Ok(answer));
+                }
+                None
+            }
+            Some(CALL_ERROR) => {
+                if let Ok((_, message_id, code, description, _)) = serde_json::from_value::<(u64, String, String, String, Value)>(Value::Array(frame)) {
+                    let status = match ChargerStatus::from_ocpp(&code) {
+                        ChargerStatus::Unrecognised(_) if !description.is_empty() => ChargerStatus::Unrecognised(format!("{code} ({description})")),
+                        status => status,
+                    };
+                    self.answer(charge_point_id, &message_id, Err(Error::ChargerRejected(status)));
+                }
+                None
+            }
+            _ => {
+                eprintln!("Unknown OCPP message type from {charge_point_id}");
+                None
+            }
+        }
+    }
+
+    fn handle_call(&self, charge_point_id: &str, action: &str, payload: Value) -> Result<Value, CallError> {
+        match action {
+            "BootNotification" => {
+                let boot: BootNotification = parse_payload(payload)?;
+                println!("Charge point {charge_point_id} booted: {} {}", boot.charge_point_vendor, boot.charge_point_model);
+                if let Some(charge_point) = self.charge_points.lock().unwrap().get_mut(charge_point_id) {
+                    charge_point.model = Some(boot.charge_point_model);
+                    charge_point.booted_at = Utc::now();
+                }
+                // poll the new charger's connectors into the fleet rather than waiting for the next reconcile
+                self.fleet.reconcile_now();
+                self.wake.notify_one();
+                Ok(json!({
+                    "status": "Accepted",
+                    "currentTime": Utc::now(),
+                    "interval": self.heartbeat_interval.as_secs(),
+                }))
+            }
+            "Heartbeat" => Ok(json!({ "currentTime": Utc::now() })),
+            "Authorize" => Ok(json!({ "idTagInfo": { "status": "Accepted" } })),
+            "StatusNotification" => {
+                let notification: OcppStatusNotification = parse_payload(payload)?;
+                // connector 0 is the charge point itself, only its connectors are planned
+                if notification.connector_id > 0 {
+                    println!("{charge_point_id} - {} reported {:?}", notification.connector_id, notification.status);
+                    self.fleet.record_status(&StatusNotification {
+                        charger_id:   charge_point_id.to_owned(),
+                        connector_id: notification.connector_id,
+                        status:       notification.status,
+                        error_code:   Some(notification.error_code),
+                        timestamp:    notification.timestamp,
+                    });
+                    self.wake.notify_one();
+                }
+                Ok(json!({}))
+            }
+            "MeterValues" => {
+                let meter_values: MeterValues = parse_payload(payload)?;
+                self.record_meter_values(charge_point_id, meter_values);
+                Ok(json!({}))
+            }
+            "StartTransaction" => {
+                let start: StartTransaction = parse_payload(payload)?;
+                // the charger sends the start again later if it is not answered
+                let transaction_id = self.transaction_ids.next().map_err(|err| ("InternalError", err.to_string()))?;
+                let transaction = Transaction {
+                    connector_id:    start.connector_id,
+                    id_tag:          start.id_tag,
+                    meter_start:     start.meter_start,
+                    timestamp_start: start.timestamp,
+                    transaction_id:  Some(transaction_id),
+                    meter_stop:      None,
+                    timestamp_stop:  None,
+                    stop_reason:     None,
+                    charger_id:      Some(charge_point_id.to_owned()),
+                    voided:          None,
+                };
+                println!("{charge_point_id} - {} started transaction {transaction_id} for {}", start.connector_id, transaction.id_tag);
+                self.record_transaction(charge_point_id, transaction);
+                Ok(json!({ "idTagInfo": { "status": "Accepted" }, "transactionId": transaction_id }))
+            }
+            "StopTransaction" => {
+                let stop: StopTransaction = parse_payload(payload)?;
+                let started = self.connectors
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|((charger_id, _), _)| charger_id == charge_point_id)
+                    .find_map(|(_, connector)| connector.transaction.clone().filter(|transaction| transaction.transaction_id == Some(stop.transaction_id)));
+                match started {
+                    Some(started) => {
+                        println!("{charge_point_id} - {} stopped transaction {}", started.connector_id, stop.transaction_id);
+                        self.record_transaction(charge_point_id, Transaction {
+                            meter_stop:     Some(stop.meter_stop),
+                            timestamp_stop: Some(stop.timestamp),
+                            // OCPP leaves the reason out for a normal local stop
+                            stop_reason:    Some(stop.reason.unwrap_or_else(|| String::from("Local"))),
+                            ..started
+                        });
+                    }
+                    None => eprintln!("{charge_point_id} stopped unknown transaction {}", stop.transaction_id),
+                }
+                Ok(json!({ "idTagInfo": { "status": "Accepted" } }))
+            }
+            other => Err(("NotImplemented", format!("{other} is not supported by this central system"))),
+        }
+    }
+
+    fn record_meter_values(&self, charge_point_id: &str, meter_values: MeterValues) {
+        /*
+         * Keep the newest meter value reported on the connector. Meter values sent
+         * outside of a transaction are attributed to the connector's active
+         * transaction, and dropped if it has none. A transaction id the central
+         * system has not handed out, from a transaction started before a restart,
+         * is adopted as the connector's transaction from its first meter value.
+         */
+        let key = (charge_point_id.to_owned(), meter_values.connector_id);
+        let held = self.connectors.lock().unwrap().get(&key).and_then(|connector| connector.transaction.clone());
+        let active_transaction = held
+            .as_ref()
+            .filter(|transaction| transaction.timestamp_stop.is_none())
+            .and_then(|transaction| transaction.transaction_id);
+        let Some(transaction_id) = meter_values.transaction_id.or(active_transaction) else { return };
+
+        let reported: Vec<MeterValue> = meter_values.meter_value
+            .into_iter()
+            .map(|reported| MeterValue {
+                connector_id:  meter_values.connector_id,
+                charger_id:    charge_point_id.to_owned(),
+                transaction_id,
+                time_stamp:    reported.timestamp,
+                sampled_value: reported.sampled_value,
+            })
+            .collect();
+
+        if let Some(first) = reported.first().filter(|_| held.and_then(|transaction| transaction.transaction_id) != Some(transaction_id)) {
+            println!("{charge_point_id} - {} reported unknown transaction {transaction_id}, adopting it", meter_values.connector_id);
+            self.transaction_ids.observe(transaction_id);
+            self.record_transaction(charge_point_id, Transaction {
+                connector_id:    meter_values.connector_id,
+                id_tag:          String::new(),   // only StartTransaction carries the id tag
+                meter_start:     first.energy_register_wh().unwrap_or_default() as i32,
+                timestamp_start: first.time_stamp,
+                transaction_id:  Some(transaction_id),
+                meter_stop:      None,
+                timestamp_stop:  None,
+                stop_reason:     None,
+                charger_id:      Some(charge_point_id.to_owned()),
+                voided:          None,
+            });
+        }
+
+        let mut connectors = self.connectors.lock().unwrap();
+        let connector = connectors.entry(key).or_default();
+        for meter_value in reported {
+            if connector.meter_value.as_ref().is_none_or(|held| held.time_stamp <= meter_value.time_stamp) {
+                connector.meter_value = Some(meter_value.clone());
+            }
+            if self.fleet.record_meter_value(meter_value) {
+                self.wake.notify_one();
+            }
+        }
+    }
+
+    fn record_transaction(&self, charge_point_id: &str, transaction: Transaction) {
+        self.connectors
+            .lock()
+            .unwrap()
+            .entry((charge_point_id.to_owned(), transaction.connector_id))
+            .or_default()
+            .transaction = Some(transaction.clone());
+        if let Err(err) = self.fleet.record_transaction(transaction) {
+            eprintln!("Unable to record transaction from {charge_point_id}: {err}");
+        }
+        self.wake.notify_one();
+    }
+
+    fn answer(&self, charge_point_id: &str, message_id: &str, answer: Result<Value, Error>) {
+        let waiting = self.charge_points
+            .lock()
+            .unwrap()
+            .get_mut(charge_point_id)
+            .and_then(|charge_point| charge_point.pending.remove(message_id));
+        match waiting {
+            Some(waiting) => {
+                let _ = waiting.send(answer);
+            }
+            None => eprintln!("{charge_point_id} answered unknown message {message_id}"),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, charger_id: &str, action: &str, payload: Value) -> Result<T, Error> {
+        /*
+         * Send a call to a connected charger and wait for its answer. Calls to the
+         * same charger are sent one at a time as OCPP requires.
+         */
+        let context = format!("{action} to {charger_id}");
+        let not_connected = || Error::Transport { context: context.clone(), message: String::from("charger is not connected"), timed_out: false };
+
+        let calls = self.charge_points.lock().unwrap().get(charger_id).map(|charge_point| charge_point.calls.clone()).ok_or_else(not_connected)?;
+        let _outstanding = calls.lock().await;
+
+        let message_id = self.next_message_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let (waiting, answered) = oneshot::channel();
+        {
+            let mut charge_points = self.charge_points.lock().unwrap();
+            let charge_point = charge_points.get_mut(charger_id).ok_or_else(not_connected)?;
+            let frame = json!([CALL, message_id, action, payload]);
+            if self.verbose_mode {
+                println!("{charger_id} <- {frame}");
+            }
+            charge_point.outbound.send(Message::Text(frame.to_string())).map_err(|_| not_connected())?;
+            charge_point.pending.insert(message_id.clone(), waiting);
+        }
+
+        let answer = match timeout(SEND_TIMEOUT, answered).await {
+            Ok(Ok(answer)) => answer?,
+            Ok(Err(_)) => return Err(Error::Transport { context, message: String::from("connection closed before the charger answered"), timed_out: false }),
+            Err(_) => {
+                if let Some(charge_point) = self.charge_points.lock().unwrap().get_mut(charger_id) {
+                    charge_point.pending.remove(&message_id);
+                }
+                return Err(Error::Transport { context, message: format!("no answer after {}s", SEND_TIMEOUT.as_secs()), timed_out: true });
+            }
+        };
+        serde_json::from_value(answer.clone()).map_err(|err| Error::decode(&context, &answer.to_string(), err))
+    }
+}
+
+fn parse_payload<T: DeserializeOwned>(payload: Value) -> Result<T, CallError> {
+    serde_json::from_value(payload).map_err(|err| ("FormationViolation", err.to_string()))
+}
+
+// The status a charger answers most commands with
+#[derive(Debug, Deserialize)]
+struct CommandStatus {
+    status: String,
+}
+
+impl ChargerHubApi for OcppCentralSystem {
+    async fn chargers(&self) -> Result<Vec<Charger>, Error> {
+        // every connected charger is at this site
+        let mut chargers: Vec<Charger> = self.charge_points
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(charger_id, charge_point)| Charger {
+                id:                  charger_id.clone(),
+                charger_name:        charge_point.model.clone().unwrap_or_else(|| charger_id.clone()),
+                location_id:         Some(self.location_id),
+                communicate_through: CommunicationType::RustDirectOcpp,
+                latitude:            None,
+                longitude:           None,
+                created_at:          charge_point.booted_at,
+            })
+            .collect();
+        chargers.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(chargers)
+    }
+
+    async fn latest_meter_value(&self, charger_id: &str, connector_id: i32) -> Result<Option<MeterValue>, Error> {
+        Ok(self.connectors
+            .lock()
+            .unwrap()
+            .get(&(charger_id.to_owned(), connector_id))
+            .and_then(|connector| connector.meter_value.clone()))
+    }
+
+    async fn latest_transaction(&self, charger_id: &str, connector_id: i32) -> Result<Option<Transaction>, Error> {
+        Ok(self.connectors
+            .lock()
+            .unwrap()
+            .get(&(charger_id.to_owned(), connector_id))
+            .and_then(|connector| connector.transaction.clone()))
+    }
+
+    async fn set_charge_profile(&self, profile: &ChargeProfile, valid_to: DateTime<Utc>) -> Result<(), Error> {
+        let periods: Vec<Value> = profile.start_periods
+            .iter()
+            .zip(&profile.charge_rates)
+            .map(|(start_period, limit)| json!({ "startPeriod": start_period, "limit": limit }))
+            .collect();
+        let answer: CommandStatus = self.call(&profile.charger_id, "SetChargingProfile", json!({
+            "connectorId": profile.connector_id,
+            "csChargingProfiles": {
+                // one id per connector and stack level, so a new profile replaces the one it supersedes
+                "chargingProfileId": profile.connector_id * 100 + profile.stack_level,
+                "transactionId": profile.transaction_id,
+                "stackLevel": profile.stack_level,
+                "chargingProfilePurpose": profile.purpose,
+                "chargingProfileKind": "Absolute",
+                "validTo": valid_to,
+                "chargingSchedule": {
+                    "startSchedule": profile.start_schedule,
+                    "chargingRateUnit": "W",
+                    "chargingSchedulePeriod": periods,
+                },
+            },
+        })).await?;
+        match answer.status.as_str() {
+            "Accepted" => Ok(()),
+            other => Err(Error::ChargerRejected(ChargerStatus::from_ocpp(other))),
+        }
+    }
+
+    async fn clear_charge_profile(&self, profile: &OwnedProfile) -> Result<(), Error> {
+        let answer: CommandStatus = self.call(&profile.charger_id, "ClearChargingProfile", json!({
+            "connectorId": profile.connector_id,
+            "chargingProfilePurpose": "TxProfile",
+            "stackLevel": profile.stack_level,
+        })).await?;
+        // Unknown means there was no such profile left to clear
+        match answer.status.as_str() {
+            "Accepted" | "Unknown" => Ok(()),
+            other => Err(Error::ChargerRejected(ChargerStatus::from_ocpp(other))),
+        }
+    }
+
+    async fn composite_schedule(&self, charger_id: &str, connector_id: i32, duration: i32) -> Result<CompositeSchedule, Error> {
+        let answer: OcppCompositeSchedule = self.call(charger_id, "GetCompositeSchedule", json!({
+            "connectorId": connector_id,
+            "duration": duration,
+            "chargingRateUnit": "W",
+        })).await?;
+        Ok(CompositeSchedule {
+            status: answer.status,
+            charging_schedule: answer.charging_schedule.map(|schedule| ChargingSchedule {
+                charging_schedule_period: schedule.charging_schedule_period
+                    .into_iter()
+                    .map(|period| ChargingSchedulePeriod { start_period: period.start_period, limit: period.limit })
+                    .collect(),
+            }),
+        })
+    }
+
+    fn degraded(&self) -> Option<Degraded> {
+        // chargers are talked to directly, there is no hub to go down
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::{connect_async, tungstenite::{client::IntoClientRequest, Error as WsError}, MaybeTlsStream, WebSocketStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    fn transaction_id_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("busCurtailment-{name}-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn new_central_system(transaction_id_file: &str) -> Arc<OcppCentralSystem> {
+        Arc::new(OcppCentralSystem {
+            allowed:             OcppCentralSystem::parse_charge_points("CH1:secret1,CH2").unwrap(),
+            location_id:         7,
+            heartbeat_interval:  Duration::from_secs(300),
+            verbose_mode:        false,
+            fleet:               Arc::new(FleetState::new()),
+            wake:                Arc::new(Notify::new()),
+            charge_points:       Mutex::new(HashMap::new()),
+            connectors:          Mutex::new(HashMap::new()),
+            next_connection:     AtomicU64::new(0),
+            next_message_id:     AtomicU64::new(0),
+            transaction_ids:     TransactionIds::load(transaction_id_file).unwrap(),
+        })
+    }
+
+    async fn listening(central_system: &Arc<OcppCentralSystem>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        central_system.clone().accept(listener);
+        address
+    }
+
+    async fn connect(address: &str, charge_point_id: &str, subprotocol: Option<&str>, credentials: Option<&str>) -> Result<Client, WsError> {
+        let mut request = format!("ws://{address}/ocpp/{charge_point_id}").into_client_request().unwrap();
+        if let Some(subprotocol) = subprotocol {
+            request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_str(subprotocol).unwrap());
+        }
+        if let Some(credentials) = credentials {
+            request.headers_mut().insert("Authorization", HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials))).unwrap());
+        }
+        connect_async(request).await.map(|(client, _)| client)
+    }
+
+    async fn next_call(client: &mut Client) -> (String, String, Value) {
+        // the next call the central system sends the charger, as (message id, action, payload)
+        loop {
+            if let Message::Text(frame) = client.next().await.unwrap().unwrap() {
+                let (_, message_id, action, payload): (u64, String, String, Value) = serde_json::from_str(&frame).unwrap();
+                return (message_id, action, payload);
+            }
+        }
+    }
+
+    fn call(central_system: &OcppCentralSystem, action: &str, payload: Value) -> Value {
+        let frame = json!([CALL, "1", action, payload]).to_string();
+        central_system.handle_frame("CH1", &frame).unwrap()
+    }
+
+    fn rejected_with(result: Result<Client, WsError>) -> Option<u16> {
+        match result {
+            Err(WsError::Http(response)) => Some(response.status().as_u16()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn only_listed_charge_points_with_their_credentials_connect() {
+        let central_system = new_central_system(&transaction_id_file("handshake"));
+        let address = listening(&central_system).await;
+
+        assert_eq!(rejected_with(connect(&address, "CH1", None, Some("CH1:secret1")).await), Some(400));
+        assert_eq!(rejected_with(connect(&address, "CH9", Some("ocpp1.6"), None).await), Some(401));
+        assert_eq!(rejected_with(connect(&address, "CH1", Some("ocpp1.6"), None).await), Some(401));
+        assert_eq!(rejected_with(connect(&address, "CH1", Some("ocpp1.6"), Some("CH1:wrong")).await), Some(401));
+        assert_eq!(rejected_with(connect(&address, "CH1", Some("ocpp1.6"), Some("CH2:secret1")).await), Some(401));
+
+        assert!(connect(&address, "CH1", Some("ocpp1.6"), Some("CH1:secret1")).await.is_ok());
+        //CH2 was not given a password
+        assert!(connect(&address, "CH2", Some("ocpp1.6"), None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn connecting_charge_points_are_asked_for_their_state() {
+        let central_system = new_central_system(&transaction_id_file("trigger"));
+        let address = listening(&central_system).await;
+        let mut client = connect(&address, "CH2", Some("ocpp1.6"), None).await.unwrap();
+
+        for requested_message in ["StatusNotification", "MeterValues"] {
+            let (message_id, action, payload) = next_call(&mut client).await;
+            assert_eq!(action, "TriggerMessage");
+            assert_eq!(payload, json!({ "requestedMessage": requested_message }));
+            client.send(Message::Text(json!([CALL_RESULT, message_id, { "status": "Accepted" }]).to_string())).await.unwrap();
+        }
+
+        //Answers and errors are routed back to the call waiting on them
+        let asking = tokio::spawn({
+            let central_system = central_system.clone();
+            async move { central_system.composite_schedule("CH2", 1, 3600).await }
+        });
+        let (message_id, action, _) = next_call(&mut client).await;
+        assert_eq!(action, "GetCompositeSchedule");
+        client.send(Message::Text(json!([CALL_ERROR, message_id, "NotSupported", "", {}]).to_string())).await.unwrap();
+        assert!(matches!(asking.await.unwrap(), Err(Error::ChargerRejected(ChargerStatus::NotSupported))));
+    }
+
+    #[tokio::test]
+    async fn calls_are_answered_by_action() {
+        let central_system = new_central_system(&transaction_id_file("dispatch"));
+
+        let boot = call(&central_system, "BootNotification", json!({ "chargePointVendor": "Vendor", "chargePointModel": "Model" }));
+        assert_eq!((&boot[0], &boot[1], &boot[2]["status"], &boot[2]["interval"]), (&json!(CALL_RESULT), &json!("1"), &json!("Accepted"), &json!(300)));
+        assert_eq!(call(&central_system, "Heartbeat", json!({}))[0], json!(CALL_RESULT));
+
+        let unsupported = call(&central_system, "DataTransfer", json!({ "vendorId": "Vendor" }));
+        assert_eq!((&unsupported[0], &unsupported[2]), (&json!(CALL_ERROR), &json!("NotImplemented")));
+        let malformed = call(&central_system, "StartTransaction", json!({ "connectorId": 1 }));
+        assert_eq!((&malformed[0], &malformed[2]), (&json!(CALL_ERROR), &json!("FormationViolation")));
+
+        //Frames which cannot be read, and answers nobody is waiting on, get no reply
+        assert_eq!(central_system.handle_frame("CH1", "not a frame"), None);
+        assert_eq!(central_system.handle_frame("CH1", &json!([CALL_RESULT, "9", {}]).to_string()), None);
+    }
+
+    #[tokio::test]
+    async fn transaction_ids_carry_on_after_a_restart() {
+        let path = transaction_id_file("transactions");
+        let start = json!({ "connectorId": 1, "idTag": "BUS1", "meterStart": 1000, "timestamp": "2026-10-18T20:00:00Z" });
+
+        let central_system = new_central_system(&path);
+        assert_eq!(call(&central_system, "StartTransaction", start.clone())[2]["transactionId"], json!(1));
+        assert_eq!(call(&central_system, "StartTransaction", start.clone())[2]["transactionId"], json!(2));
+
+        let stop = call(&central_system, "StopTransaction", json!({ "transactionId": 2, "meterStop": 5000, "timestamp": "2026-10-18T21:00:00Z" }));
+        assert_eq!(stop[2]["idTagInfo"]["status"], json!("Accepted"));
+        let stopped = central_system.latest_transaction("CH1", 1).await.unwrap().unwrap();
+        assert_eq!((stopped.transaction_id, stopped.meter_stop, stopped.stop_reason.as_deref()), (Some(2), Some(5000), Some("Local")));
+
+        let restarted = new_central_system(&path);
+        assert_eq!(call(&restarted, "StartTransaction", start)[2]["transactionId"], json!(3));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn transactions_from_before_a_restart_are_adopted() {
+        let path = transaction_id_file("adopted");
+        let central_system = new_central_system(&path);
+
+        let meter_values = call(&central_system, "MeterValues", json!({
+            "connectorId": 1,
+            "transactionId": 41,
+            "meterValue": [{
+                "timestamp": "2026-10-18T20:00:00Z",
+                "sampledValue": [{ "value": "12500", "measurand": "Energy.Active.Import.Register", "unit": "Wh" }],
+            }],
+        }));
+        assert_eq!(meter_values[0], json!(CALL_RESULT));
+
+        let adopted = central_system.latest_transaction("CH1", 1).await.unwrap().unwrap();
+        assert_eq!((adopted.transaction_id, adopted.meter_start), (Some(41), 12500));
+        assert!(adopted.is_active());
+        let meter_value = central_system.latest_meter_value("CH1", 1).await.unwrap().unwrap();
+        assert_eq!(meter_value.transaction_id, 41);
+
+        //The adopted id is never handed out again
+        let start = json!({ "connectorId": 2, "idTag": "BUS2", "meterStart": 0, "timestamp": "2026-10-18T20:05:00Z" });
+        assert_eq!(call(&central_system, "StartTransaction", start)[2]["transactionId"], json!(42));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,965 @@
+use chrono::{DateTime, Utc};
+use reqwest::{header::CONTENT_TYPE, Client};
+use roxmltree::{Document, Node};
+use std::{collections::HashSet, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Notify;
+use crate::{
+    config::Config,
+    demand_response::{DrEvent, DrEventStore, DrLimit},
+    error::Error,
+    fleet_state::FleetState,
+    freshness::FreshnessTracker,
+    price_curve::{PriceCurve, PricePeriod}
+};
+
+// Settings for taking part in an OpenADR 2.0b program as a VEN
+#[derive(Debug, Clone)]
+pub struct OpenAdrConfig {
+    pub vtn_url:           String,             // up to the service name, IE https://vtn.example.com/OpenADR2/Simple/2.0b
+    pub ven_name:          String,
+    pub poll_interval:     Option<Duration>,   // None polls as often as the VTN asks
+    pub report_interval:   Duration,           // for telemetry requests which do not set a granularity
+    pub simple_reductions: Vec<f32>,           // percent reduction for SIMPLE levels 1, 2, 3
+    pub opt_in:            bool,
+}
+
+// Events the VTN sends are stored under ids starting with this, so they can be
+// replaced as a whole without touching events from the file, command line or API
+const EVENT_ID_PREFIX: &str = "openadr-";
+
+// The one telemetry report the VEN offers, the depot's charging load
+const REPORT_SPECIFIER_ID: &str = "depot-telemetry";
+const REPORT_RID: &str = "depot_power";
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// Open ended events are recorded as lasting this long, until the VTN cancels them
+const OPEN_ENDED: chrono::Duration = chrono::Duration::days(365);
+
+// A telemetry report the VTN asked for
+struct ReportRequest {
+    request_id:  String,
+    granularity: Option<Duration>,
+    sent_at:     Option<Instant>,
+}
+
+// One interval of an event signal
+struct SignalInterval {
+    uid:   String,
+    start: DateTime<Utc>,
+    end:   DateTime<Utc>,
+    value: f32,
+}
+
+// An event signal, IE SIMPLE levels or ELECTRICITY_PRICE prices
+struct EventSignal {
+    name:      String,
+    kind:      String,   // signalType, IE level, setpoint or price
+    scale_w:   f32,      // watts per unit of a power signal's values
+    intervals: Vec<SignalInterval>,
+}
+
+// An eiEvent from an oadrDistributeEvent
+struct VtnEvent {
+    id:                String,
+    modification:      String,
+    status:            String,
+    response_required: bool,
+    signals:           Vec<EventSignal>,
+}
+
+// An OpenADR 2.0b VEN using the simple HTTP pull model. It registers with the
+// VTN, polls it for events, turns load shed signals into demand response
+// events and price signals into the price curve, opts in or out of each event
+// and reports the depot's charging load back.
+pub struct OpenAdrVen {
+    client:          Client,
+    config:          OpenAdrConfig,
+    verbose_mode:    bool,
+    dr_events:       Arc<DrEventStore>,
+    prices:          Arc<PriceCurve>,
+    fleet:           Arc<FleetState>,
+    freshness:       FreshnessTracker,            // meter values too old to report are left out
+    wake:            Arc<Notify>,
+    ven_id:          Option<String>,
+    registration_id: Option<String>,
+    poll_interval:   Duration,
+    report_requests: Vec<ReportRequest>,
+    responded:       HashSet<(String, String)>,   // (event id, modification number) already opted in or out of
+    next_request_id: u64,
+}
+
+impl OpenAdrVen {
+    pub fn start(
+        config: &Config,
+        openadr: &OpenAdrConfig,
+        dr_events: Arc<DrEventStore>,
+        prices: Arc<PriceCurve>,
+        fleet: Arc<FleetState>,
+        wake: Arc<Notify>) -> Result<(), Error>
+    {
+        /*
+         * Run the VEN on its own task. It gets a client of its own so chargerhub's
+         * authorization header is never sent to the VTN.
+         */
+        let client = Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            .build()
+            .map_err(|err| Error::Config(format!("Unable to build the OpenADR client: {err}")))?;
+
+        let mut ven = OpenAdrVen {
+            client,
+            config:          openadr.clone(),
+            verbose_mode:    config.verbose_mode,
+            dr_events,
+            prices,
+            fleet,
+            freshness:       FreshnessTracker::new(config.meter_value_max_age),
+            wake,
+            ven_id:          None,
+            registration_id: None,
+            poll_interval:   openadr.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            report_requests: Vec::new(),
+            responded:       HashSet::new(),
+            next_request_id: 0,
+        };
+        tokio::spawn(async move { ven.run().await });
+        Ok(())
+    }
+
+    async fn run(&mut self) {
+        loop {
+            if self.ven_id.is_none() {
+                if let Err(err) = self.register().await {
+                    eprintln!("Unable to register with the VTN: {err}");
+                }
+            }
+            if self.ven_id.is_some() {
+                if let Err(err) = self.poll().await {
+                    eprintln!("Unable to poll the VTN: {err}");
+                }
+                if let Err(err) = self.send_reports().await {
+                    eprintln!("Unable to report telemetry to the VTN: {err}");
+                }
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn register(&mut self) -> Result<(), Error> {
+        /*
+         * Register as a pull model VEN, then offer the VTN the telemetry report.
+         * The VTN may ask for the report in its answer or in a later poll.
+         */
+        let request_id = self.request_id();
+        let body = self.post("EiRegisterParty", &format!(
+            "<oadr:oadrCreatePartyRegistration ei:schemaVersion=\"2.0b\">\
+                <pyld:requestID>{request_id}</pyld:requestID>\
+                {registration}\
+                <oadr:oadrProfileName>2.0b</oadr:oadrProfileName>\
+                <oadr:oadrTransportName>simpleHttp</oadr:oadrTransportName>\
+                <oadr:oadrReportOnly>false</oadr:oadrReportOnly>\
+                <oadr:oadrXmlSignature>false</oadr:oadrXmlSignature>\
+                <oadr:oadrVenName>{ven_name}</oadr:oadrVenName>\
+                <oadr:oadrHttpPullModel>true</oadr:oadrHttpPullModel>\
+            </oadr:oadrCreatePartyRegistration>",
+            registration = match (&self.registration_id, &self.ven_id) {
+                (Some(registration_id), Some(ven_id)) => format!("<ei:registrationID>{}</ei:registrationID><ei:venID>{}</ei:venID>", escape(registration_id), escape(ven_id)),
+                _ => String::new(),
+            },
+            ven_name = escape(&self.config.ven_name),
+        )).await?;
+        let document = parse("registration", &body)?;
+        let registered = payload(&document, "oadrCreatedPartyRegistration")?;
+        check_response("registration", registered)?;
+
+        let ven_id = text(registered, &["venID"]).ok_or(Error::NoData(String::from("VTN registration did not include a venID")))?;
+        self.registration_id = text(registered, &["registrationID"]).map(str::to_owned);
+        if let Some(poll_frequency) = text(registered, &["oadrRequestedOadrPollFreq", "duration"]).and_then(parse_duration) {
+            self.poll_interval = self.config.poll_interval.unwrap_or(poll_frequency.to_std().unwrap_or(DEFAULT_POLL_INTERVAL));
+        }
+        println!("Registered with the VTN as {ven_id}, polling every {}s", self.poll_interval.as_secs());
+        self.ven_id = Some(ven_id.to_owned());
+        self.register_report().await
+    }
+
+    async fn register_report(&mut self) -> Result<(), Error> {
+        let request_id = self.request_id();
+        let body = self.post("EiReport", &format!(
+            "<oadr:oadrRegisterReport ei:schemaVersion=\"2.0b\">\
+                <pyld:requestID>{request_id}</pyld:requestID>\
+                <oadr:oadrReport>\
+                    <oadr:oadrReportDescription>\
+                        <ei:rID>{REPORT_RID}</ei:rID>\
+                        <ei:reportDataSource><ei:resourceID>depot</ei:resourceID></ei:reportDataSource>\
+                        <ei:reportType>usage</ei:reportType>\
+                        <power:realPower>\
+                            <power:itemDescription>RealPower</power:itemDescription>\
+                            <power:itemUnits>W</power:itemUnits>\
+                            <scale:siScaleCode>k</scale:siScaleCode>\
+                            <power:powerAttributes><power:hertz>60</power:hertz><power:voltage>480</power:voltage><power:ac>true</power:ac></power:powerAttributes>\
+                        </power:realPower>\
+                        <ei:readingType>Direct Read</ei:readingType>\
+                        <oadr:oadrSamplingRate><oadr:oadrMinPeriod>PT1M</oadr:oadrMinPeriod><oadr:oadrMaxPeriod>PT1H</oadr:oadrMaxPeriod><oadr:oadrOnChange>false</oadr:oadrOnChange></oadr:oadrSamplingRate>\
+                    </oadr:oadrReportDescription>\
+                    <ei:reportRequestID>0</ei:reportRequestID>\
+                    <ei:reportSpecifierID>{REPORT_SPECIFIER_ID}</ei:reportSpecifierID>\
+                    <ei:reportName>METADATA_TELEMETRY_USAGE</ei:reportName>\
+                    <ei:createdDateTime>{created}</ei:createdDateTime>\
+                </oadr:oadrReport>\
+                {ven_id}\
+            </oadr:oadrRegisterReport>",
+            created = Utc::now().to_rfc3339(),
+            ven_id = self.ven_id_element(),
+        )).await?;
+        let document = parse("report registration", &body)?;
+        let registered = payload(&document, "oadrRegisteredReport")?;
+        check_response("report registration", registered)?;
+
+        let requests: Vec<Node> = children(registered, "oadrReportRequest").collect();
+        if !requests.is_empty() {
+            let request_id = text(registered, &["eiResponse", "requestID"]).unwrap_or_default().to_owned();
+            self.create_reports(&request_id, &requests).await?;
+        }
+        Ok(())
+    }
+
+    async fn poll(&mut self) -> Result<(), Error> {
+        /*
+         * Ask the VTN for anything it has for us. Events come back as a full
+         * oadrDistributeEvent, report requests and cancellations one at a time.
+         */
+        let body = self.post("OadrPoll", &format!(
+            "<oadr:oadrPoll ei:schemaVersion=\"2.0b\">{}</oadr:oadrPoll>",
+            self.ven_id_element()
+        )).await?;
+        let document = parse("poll", &body)?;
+        let Some(message) = document.descendants().find(|node| node.is_element() && node.tag_name().name() == "oadrSignedObject").and_then(|signed| signed.first_element_child()) else {
+            return Err(Error::NoData(String::from("VTN answered the poll without a message")));
+        };
+        if self.verbose_mode {
+            println!("VTN answered the poll with {}", message.tag_name().name());
+        }
+
+        match message.tag_name().name() {
+            "oadrResponse" => check_response("poll", message),
+            "oadrDistributeEvent" => self.distribute_events(message).await,
+            "oadrCreateReport" => {
+                let request_id = text(message, &["requestID"]).unwrap_or_default().to_owned();
+                let requests: Vec<Node> = children(message, "oadrReportRequest").collect();
+                self.create_reports(&request_id, &requests).await
+            }
+            "oadrCancelReport" => {
+                let request_id = text(message, &["requestID"]).unwrap_or_default().to_owned();
+                let cancelled: Vec<&str> = children(message, "reportRequestID").filter_map(|node| node.text()).collect();
+                self.report_requests.retain(|request| !cancelled.contains(&request.request_id.as_str()));
+                println!("VTN cancelled telemetry report(s) {}", cancelled.join(", "));
+                self.acknowledge("EiReport", "oadrCanceledReport", &request_id, "<oadr:oadrPendingReports/>").await
+            }
+            "oadrRequestReregistration" => {
+                println!("VTN asked the VEN to register again");
+                self.register().await
+            }
+            "oadrCancelPartyRegistration" => {
+                println!("VTN cancelled the VEN's registration, registering again");
+                self.ven_id = None;
+                self.registration_id = None;
+                self.report_requests.clear();
+                Ok(())
+            }
+            other => {
+                eprintln!("Ignoring unsupported VTN message {other}");
+                Ok(())
+            }
+        }
+    }
+
+    async fn distribute_events(&mut self, message: Node<'_, '_>) -> Result<(), Error> {
+        /*
+         * Replace the events and prices from the VTN with the ones it holds now,
+         * events missing from the list having ended or been cancelled, and opt in
+         * or out of each new event or modification which asks for a response.
+         * Responses only count as given once the VTN has accepted them, so a failed
+         * post is sent again on the next poll.
+         */
+        let request_id = text(message, &["requestID"]).unwrap_or_default().to_owned();
+        let events: Vec<VtnEvent> = children(message, "oadrEvent").filter_map(|event| parse_event(event).inspect_err(|err| eprintln!("{err}")).ok()).collect();
+
+        let mut dr_events = Vec::new();
+        let mut price_periods = Vec::new();
+        let mut responses = String::new();
+        let mut responding = Vec::new();
+        for event in &events {
+            let in_effect = !matches!(event.status.as_str(), "cancelled" | "completed");
+            let supported = event.signals.iter().any(|signal| self.signal_supported(signal));
+            let opt_in = self.config.opt_in && supported;
+
+            if in_effect {
+                for signal in &event.signals {
+                    match (signal.name.as_str(), signal.kind.as_str()) {
+                        ("ELECTRICITY_PRICE", "price") => price_periods.extend(signal.intervals
+                            .iter()
+                            .map(|interval| PricePeriod { start: interval.start, end: interval.end, price: interval.value })),
+                        _ if !opt_in => {}
+                        ("SIMPLE", _) | ("LOAD_DISPATCH", "setpoint") => dr_events.extend(signal.intervals
+                            .iter()
+                            .filter_map(|interval| self.dr_limit(signal, interval.value).map(|limit| DrEvent {
+                                id:    format!("{EVENT_ID_PREFIX}{}-{}-{}", event.id, signal.name, interval.uid),
+                                start: interval.start,
+                                end:   interval.end,
+                                limit,
+                            }))),
+                        _ => {}
+                    }
+                }
+            }
+
+            let qualified = (event.id.clone(), event.modification.clone());
+            if event.response_required && !self.responded.contains(&qualified) {
+                println!("Opting {} VTN event {} (modification {}, {})",
+                    if opt_in { "in to" } else { "out of" }, event.id, event.modification, event.status);
+                responses.push_str(&format!(
+                    "<ei:eventResponse>\
+                        <ei:responseCode>200</ei:responseCode>\
+                        <ei:responseDescription>OK</ei:responseDescription>\
+                        <pyld:requestID>{}</pyld:requestID>\
+                        <ei:qualifiedEventID><ei:eventID>{}</ei:eventID><ei:modificationNumber>{}</ei:modificationNumber></ei:qualifiedEventID>\
+                        <ei:optType>{}</ei:optType>\
+                    </ei:eventResponse>",
+                    escape(&request_id), escape(&event.id), escape(&event.modification), if opt_in { "optIn" } else { "optOut" }
+                ));
+                responding.push(qualified);
+            }
+        }
+        self.responded.retain(|(event_id, _)| events.iter().any(|event| event.id == *event_id));
+
+        if let Err(err) = self.dr_events.replace_source(EVENT_ID_PREFIX, dr_events) {
+            eprintln!("Unable to store VTN events: {err}");
+        }
+        self.prices.replace(price_periods);
+        self.wake.notify_one();
+
+        if responses.is_empty() {
+            return Ok(());
+        }
+        let body = self.post("EiEvent", &format!(
+            "<oadr:oadrCreatedEvent ei:schemaVersion=\"2.0b\">\
+                <pyld:eiCreatedEvent>\
+                    <ei:eiResponse><ei:responseCode>200</ei:responseCode><ei:responseDescription>OK</ei:responseDescription><pyld:requestID>{}</pyld:requestID></ei:eiResponse>\
+                    <ei:eventResponses>{responses}</ei:eventResponses>\
+                    {}\
+                </pyld:eiCreatedEvent>\
+            </oadr:oadrCreatedEvent>",
+            escape(&request_id),
+            self.ven_id_element()
+        )).await?;
+        let document = parse("event response", &body)?;
+        check_response("event response", payload(&document, "oadrResponse")?)?;
+        self.responded.extend(responding);
+        Ok(())
+    }
+
+    fn signal_supported(&self, signal: &EventSignal) -> bool {
+        matches!((signal.name.as_str(), signal.kind.as_str()), ("SIMPLE", _) | ("LOAD_DISPATCH", "setpoint") | ("ELECTRICITY_PRICE", "price"))
+    }
+
+    fn dr_limit(&self, signal: &EventSignal, value: f32) -> Option<DrLimit> {
+        /*
+         * The depot limit a load shed interval asks for. SIMPLE levels map to the
+         * configured reductions, level 0 being normal operation, and LOAD_DISPATCH
+         * setpoints are a limit in their own right.
+         */
+        match signal.name.as_str() {
+            "SIMPLE" => {
+                let level = value.round() as usize;
+                (level > 0)
+                    .then(|| self.config.simple_reductions.get(level - 1).or(self.config.simple_reductions.last()))
+                    .flatten()
+                    .map(|percent| DrLimit::PercentReduction(*percent))
+            }
+            _ => Some(DrLimit::Kw(value * signal.scale_w / 1000.0)),
+        }
+    }
+
+    async fn create_reports(&mut self, request_id: &str, requests: &[Node<'_, '_>]) -> Result<(), Error> {
+        /*
+         * Take on the VTN's requests for the telemetry report, and tell it which
+         * reports are now pending.
+         */
+        for request in requests {
+            let Some(report_request_id) = text(*request, &["reportRequestID"]) else { continue };
+            if text(*request, &["reportSpecifier", "reportSpecifierID"]) != Some(REPORT_SPECIFIER_ID) {
+                eprintln!("VTN asked for unknown report in request {report_request_id}");
+                continue;
+            }
+            let granularity = text(*request, &["reportSpecifier", "granularity", "duration"])
+                .and_then(parse_duration)
+                .and_then(|granularity| granularity.to_std().ok())
+                .filter(|granularity| !granularity.is_zero());
+            println!("VTN requested telemetry report {report_request_id}");
+            self.report_requests.retain(|existing| existing.request_id != report_request_id);
+            self.report_requests.push(ReportRequest { request_id: report_request_id.to_owned(), granularity, sent_at: None });
+        }
+
+        let pending: String = self.report_requests
+            .iter()
+            .map(|request| format!("<ei:reportRequestID>{}</ei:reportRequestID>", escape(&request.request_id)))
+            .collect();
+        self.acknowledge("EiReport", "oadrCreatedReport", request_id, &format!("<oadr:oadrPendingReports>{pending}</oadr:oadrPendingReports>")).await
+    }
+
+    async fn send_reports(&mut self) -> Result<(), Error> {
+        /*
+         * Send the depot's charging load for every report request which is due.
+         * Meter values older than the freshness threshold are left out of the
+         * load, which is then reported at a lower data quality.
+         */
+        let due: Vec<String> = self.report_requests
+            .iter()
+            .filter(|request| request.sent_at.is_none_or(|sent_at| sent_at.elapsed() >= request.granularity.unwrap_or(self.config.report_interval)))
+            .map(|request| request.request_id.clone())
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let mut load_w = 0.0;
+        let mut stale_readings = 0;
+        for (charger_id, time_stamp, power_w) in self.fleet.charging_loads() {
+            match self.freshness.check(&charger_id, time_stamp, now) {
+                None => load_w += power_w,
+                Some(_) => stale_readings += 1,
+            }
+        }
+        let load_kw = load_w / 1000.0;
+        let quality = data_quality(load_w, stale_readings);
+        let right_now = now.to_rfc3339();
+        let reports: String = due
+            .iter()
+            .map(|report_request_id| format!(
+                "<oadr:oadrReport>\
+                    <xcal:dtstart><xcal:date-time>{right_now}</xcal:date-time></xcal:dtstart>\
+                    <strm:intervals>\
+                        <ei:interval>\
+                            <xcal:dtstart><xcal:date-time>{right_now}</xcal:date-time></xcal:dtstart>\
+                            <oadr:oadrReportPayload>\
+                                <ei:rID>{REPORT_RID}</ei:rID>\
+                                <ei:payloadFloat><ei:value>{load_kw:.3}</ei:value></ei:payloadFloat>\
+                                <oadr:oadrDataQuality>{quality}</oadr:oadrDataQuality>\
+                            </oadr:oadrReportPayload>\
+                        </ei:interval>\
+                    </strm:intervals>\
+                    <ei:eiReportID>{REPORT_SPECIFIER_ID}-{right_now}</ei:eiReportID>\
+                    <ei:reportRequestID>{}</ei:reportRequestID>\
+                    <ei:reportSpecifierID>{REPORT_SPECIFIER_ID}</ei:reportSpecifierID>\
+                    <ei:reportName>TELEMETRY_USAGE</ei:reportName>\
+                    <ei:createdDateTime>{right_now}</ei:createdDateTime>\
+                </oadr:oadrReport>",
+                escape(report_request_id)
+            ))
+            .collect();
+
+        let request_id = self.request_id();
+        let body = self.post("EiReport", &format!(
+            "<oadr:oadrUpdateReport ei:schemaVersion=\"2.0b\"><pyld:requestID>{request_id}</pyld:requestID>{reports}{}</oadr:oadrUpdateReport>",
+            self.ven_id_element()
+        )).await?;
+        let document = parse("telemetry report", &body)?;
+        check_response("telemetry report", payload(&document, "oadrUpdatedReport")?)?;
+
+        if self.verbose_mode {
+            println!("Reported {load_kw:.1}kW of charging load to the VTN ({quality}, {stale_readings} stale reading(s) left out)");
+        }
+        for request in self.report_requests.iter_mut().filter(|request| due.contains(&request.request_id)) {
+            request.sent_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    async fn acknowledge(&mut self, service: &str, message: &str, request_id: &str, contents: &str) -> Result<(), Error> {
+        // answer a request from the VTN which carries its own requestID, on the service it belongs to
+        let body = self.post(service, &format!(
+            "<oadr:{message} ei:schemaVersion=\"2.0b\">\
+                <ei:eiResponse><ei:responseCode>200</ei:responseCode><ei:responseDescription>OK</ei:responseDescription><pyld:requestID>{}</pyld:requestID></ei:eiResponse>\
+                {contents}{}\
+            </oadr:{message}>",
+            escape(request_id),
+            self.ven_id_element()
+        )).await?;
+        let document = parse(message, &body)?;
+        check_response(message, payload(&document, "oadrResponse")?)
+    }
+
+    async fn post(&self, service: &str, message: &str) -> Result<String, Error> {
+        let context = format!("unable to reach the VTN's {service} service");
+        let url = format!("{}/{service}", self.config.vtn_url.trim_end_matches('/'));
+        let request = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <oadr:oadrPayload \
+                xmlns:oadr=\"http://openadr.org/oadr-2.0b/2012/07\" \
+                xmlns:ei=\"http://docs.oasis-open.org/ns/energyinterop/201110\" \
+                xmlns:pyld=\"http://docs.oasis-open.org/ns/energyinterop/201110/payloads\" \
+                xmlns:emix=\"http://docs.oasis-open.org/ns/emix/2011/06\" \
+                xmlns:power=\"http://docs.oasis-open.org/ns/emix/2011/06/power\" \
+                xmlns:scale=\"http://docs.oasis-open.org/ns/emix/2011/06/siscale\" \
+                xmlns:xcal=\"urn:ietf:params:xml:ns:icalendar-2.0\" \
+                xmlns:strm=\"urn:ietf:params:xml:ns:icalendar-2.0:stream\">\
+                <oadr:oadrSignedObject>{message}</oadr:oadrSignedObject>\
+            </oadr:oadrPayload>");
+
+        let response = self.client
+            .post(url)
+            .header(CONTENT_TYPE, "application/xml")
+            .body(request)
+            .send()
+            .await
+            .map_err(|err| Error::from_reqwest(&context, err))?;
+        let status = response.status();
+        let body = response.text().await.map_err(|err| Error::from_reqwest(&context, err))?;
+        if !status.is_success() {
+            return Err(Error::Http { context, status: status.as_u16(), body });
+        }
+        Ok(body)
+    }
+
+    fn request_id(&mut self) -> String {
+        self.next_request_id += 1;
+        format!("{}-{}", Utc::now().timestamp_millis(), self.next_request_id)
+    }
+
+    fn ven_id_element(&self) -> String {
+        self.ven_id.as_deref().map(|ven_id| format!("<ei:venID>{}</ei:venID>", escape(ven_id))).unwrap_or_default()
+    }
+}
+
+
+fn data_quality(load_w: f32, stale_readings: usize) -> &'static str {
+    // how much of the depot's load the reported value covers, as an OpenADR data quality
+    match stale_readings {
+        0 => "Quality Good - Non Specific",
+        _ if load_w > 0.0 => "Quality Uncertain - Non Specific",
+        _ => "No Quality - No Value",
+    }
+}
+
+fn parse_event(event: Node) -> Result<VtnEvent, Error> {
+    /*
+     * Read an oadrEvent's descriptor and signals. Each interval starts where the
+     * previous one ended, from the event's dtstart, and an interval without a
+     * duration runs to the end of the event.
+     */
+    let ei_event = child(event, "eiEvent").ok_or(Error::NoData(String::from("VTN event without an eiEvent")))?;
+    let id = text(ei_event, &["eventDescriptor", "eventID"]).ok_or(Error::NoData(String::from("VTN event without an eventID")))?;
+    let decode = |message: &str| Error::Decode { context: format!("unable to read VTN event {id}"), message: message.to_owned() };
+
+    let active_period = child(ei_event, "eiActivePeriod").and_then(|period| child(period, "properties")).ok_or_else(|| decode("no eiActivePeriod"))?;
+    let start = text(active_period, &["dtstart", "date-time"])
+        .and_then(|start| start.parse::<DateTime<Utc>>().ok())
+        .ok_or_else(|| decode("no dtstart"))?;
+    let end = match text(active_period, &["duration", "duration"]).and_then(parse_duration) {
+        Some(duration) if duration > chrono::Duration::zero() => start + duration,
+        _ => start + OPEN_ENDED,
+    };
+
+    let mut signals = Vec::new();
+    for signal in child(ei_event, "eiEventSignals").into_iter().flat_map(|signals| children(signals, "eiEventSignal")) {
+        let mut interval_start = start;
+        let mut intervals = Vec::new();
+        for interval in child(signal, "intervals").into_iter().flat_map(|intervals| children(intervals, "interval")) {
+            let interval_end = match text(interval, &["duration", "duration"]).and_then(parse_duration) {
+                Some(duration) if duration > chrono::Duration::zero() => (interval_start + duration).min(end),
+                _ => end,
+            };
+            let value = interval
+                .descendants()
+                .find(|node| node.is_element() && node.tag_name().name() == "signalPayload")
+                .and_then(|payload| text(payload, &["payloadFloat", "value"]))
+                .and_then(|value| value.trim().parse::<f32>().ok())
+                .ok_or_else(|| decode("interval without a payloadFloat"))?;
+            intervals.push(SignalInterval {
+                uid: text(interval, &["uid", "text"]).map_or_else(|| intervals.len().to_string(), str::to_owned),
+                start: interval_start,
+                end: interval_end,
+                value,
+            });
+            interval_start = interval_end;
+        }
+
+        // power signals are in watts scaled by their siScaleCode, kW when no itemBase says otherwise
+        let scale_w = signal
+            .descendants()
+            .find(|node| node.is_element() && node.tag_name().name() == "siScaleCode")
+            .and_then(|code| code.text())
+            .map_or(1000.0, |code| match code.trim() {
+                "none" => 1.0,
+                "k" => 1000.0,
+                "M" => 1_000_000.0,
+                _ => 1000.0,
+            });
+        signals.push(EventSignal {
+            name: text(signal, &["signalName"]).unwrap_or_default().to_owned(),
+            kind: text(signal, &["signalType"]).unwrap_or_default().to_owned(),
+            scale_w,
+            intervals,
+        });
+    }
+
+    Ok(VtnEvent {
+        id:                id.to_owned(),
+        modification:      text(ei_event, &["eventDescriptor", "modificationNumber"]).unwrap_or("0").to_owned(),
+        status:            text(ei_event, &["eventDescriptor", "eventStatus"]).unwrap_or("none").to_owned(),
+        response_required: text(event, &["oadrResponseRequired"]) != Some("never"),
+        signals,
+    })
+}
+
+fn parse_duration(duration: &str) -> Option<chrono::Duration> {
+    /*
+     * Parse the day and time parts of an ISO 8601 duration, IE PT1H30M or P1DT12H.
+     * Years and months have no fixed length and are not accepted.
+     */
+    let (negative, duration) = match duration.trim().strip_prefix('-') {
+        Some(duration) => (true, duration),
+        None => (false, duration.trim().strip_prefix('+').unwrap_or(duration.trim())),
+    };
+    let duration = duration.strip_prefix('P')?;
+
+    let mut seconds = 0.0;
+    let mut in_time = false;
+    let mut number = String::new();
+    for character in duration.chars() {
+        match character {
+            'T' => in_time = true,
+            '0'..='9' | '.' => number.push(character),
+            unit => {
+                let value: f64 = number.parse().ok()?;
+                number.clear();
+                seconds += value * match (in_time, unit) {
+                    (false, 'W') => 604_800.0,
+                    (false, 'D') => 86_400.0,
+                    (true, 'H') => 3_600.0,
+                    (true, 'M') => 60.0,
+                    (true, 'S') => 1.0,
+                    _ => return None,
+                };
+            }
+        }
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    let duration = chrono::Duration::milliseconds((seconds * 1000.0) as i64);
+    Some(if negative { -duration } else { duration })
+}
+
+fn parse<'a>(context: &str, body: &'a str) -> Result<Document<'a>, Error> {
+    Document::parse(body).map_err(|err| Error::decode(&format!("unable to parse the VTN's {context} answer"), body, err))
+}
+
+fn payload<'a, 'i>(document: &'a Document<'i>, name: &str) -> Result<Node<'a, 'i>, Error> {
+    document
+        .descendants()
+        .find(|node| node.is_element() && node.tag_name().name() == name)
+        .ok_or_else(|| Error::NoData(format!("VTN did not answer with {name}")))
+}
+
+fn check_response(context: &str, message: Node) -> Result<(), Error> {
+    /*
+     * The eiResponse every VTN answer carries, any code outside of 2xx is the VTN
+     * turning the request down.
+     */
+    let code = text(message, &["eiResponse", "responseCode"]).unwrap_or("200");
+    match code.parse::<u16>() {
+        Ok(200..=299) => Ok(()),
+        _ => Err(Error::Http {
+            context: format!("VTN refused the {context}"),
+            status:  code.parse().unwrap_or_default(),
+            body:    text(message, &["eiResponse", "responseDescription"]).unwrap_or_default().to_owned(),
+        }),
+    }
+}
+
+fn child<'a, 'i>(node: Node<'a, 'i>, name: &str) -> Option<Node<'a, 'i>> {
+    node.children().find(|child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn children<'a, 'i: 'a>(node: Node<'a, 'i>, name: &'a str) -> impl Iterator<Item = Node<'a, 'i>> + 'a {
+    node.children().filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn text<'a>(node: Node<'a, '_>, path: &[&str]) -> Option<&'a str> {
+    // the text at the end of a path of child elements, matched on their names without namespaces
+    path.iter()
+        .try_fold(node, |node, name| child(node, name))
+        .and_then(|node| node.text())
+        .map(str::trim)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::test_http::{self, Received};
+
+    // An oadrDistributeEvent as a VTN sends it, a SIMPLE event in two intervals
+    // which asks for a response and a LOAD_DISPATCH setpoint in MW which does not
+    const DISTRIBUTE_EVENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<oadr:oadrPayload xmlns:oadr="http://openadr.org/oadr-2.0b/2012/07" xmlns:ei="http://docs.oasis-open.org/ns/energyinterop/201110" xmlns:pyld="http://docs.oasis-open.org/ns/energyinterop/201110/payloads" xmlns:emix="http://docs.oasis-open.org/ns/emix/2011/06" xmlns:power="http://docs.oasis-open.org/ns/emix/2011/06/power" xmlns:scale="http://docs.oasis-open.org/ns/emix/2011/06/siscale" xmlns:xcal="urn:ietf:params:xml:ns:icalendar-2.0" xmlns:strm="urn:ietf:params:xml:ns:icalendar-2.0:stream">
+  <oadr:oadrSignedObject>
+    <oadr:oadrDistributeEvent ei:schemaVersion="2.0b">
+      <ei:eiResponse><ei:responseCode>200</ei:responseCode><pyld:requestID/></ei:eiResponse>
+      <pyld:requestID>distribute-42</pyld:requestID>
+      <ei:vtnID>test-vtn</ei:vtnID>
+      <oadr:oadrEvent>
+        <ei:eiEvent>
+          <ei:eventDescriptor>
+            <ei:eventID>simple-1</ei:eventID>
+            <ei:modificationNumber>2</ei:modificationNumber>
+            <ei:eventStatus>far</ei:eventStatus>
+          </ei:eventDescriptor>
+          <ei:eiActivePeriod>
+            <xcal:properties>
+              <xcal:dtstart><xcal:date-time>2099-10-18T20:00:00Z</xcal:date-time></xcal:dtstart>
+              <xcal:duration><xcal:duration>PT1H30M</xcal:duration></xcal:duration>
+            </xcal:properties>
+          </ei:eiActivePeriod>
+          <ei:eiEventSignals>
+            <ei:eiEventSignal>
+              <strm:intervals>
+                <ei:interval>
+                  <xcal:duration><xcal:duration>PT1H</xcal:duration></xcal:duration>
+                  <xcal:uid><xcal:text>0</xcal:text></xcal:uid>
+                  <ei:signalPayload><ei:payloadFloat><ei:value>1</ei:value></ei:payloadFloat></ei:signalPayload>
+                </ei:interval>
+                <ei:interval>
+                  <xcal:duration><xcal:duration>PT1H</xcal:duration></xcal:duration>
+                  <xcal:uid><xcal:text>1</xcal:text></xcal:uid>
+                  <ei:signalPayload><ei:payloadFloat><ei:value>3</ei:value></ei:payloadFloat></ei:signalPayload>
+                </ei:interval>
+              </strm:intervals>
+              <ei:signalName>SIMPLE</ei:signalName>
+              <ei:signalType>level</ei:signalType>
+              <ei:signalID>simple-signal</ei:signalID>
+            </ei:eiEventSignal>
+          </ei:eiEventSignals>
+        </ei:eiEvent>
+        <oadr:oadrResponseRequired>always</oadr:oadrResponseRequired>
+      </oadr:oadrEvent>
+      <oadr:oadrEvent>
+        <ei:eiEvent>
+          <ei:eventDescriptor>
+            <ei:eventID>dispatch-1</ei:eventID>
+            <ei:modificationNumber>0</ei:modificationNumber>
+            <ei:eventStatus>active</ei:eventStatus>
+          </ei:eventDescriptor>
+          <ei:eiActivePeriod>
+            <xcal:properties>
+              <xcal:dtstart><xcal:date-time>2099-10-18T21:00:00Z</xcal:date-time></xcal:dtstart>
+              <xcal:duration><xcal:duration>PT2H</xcal:duration></xcal:duration>
+            </xcal:properties>
+          </ei:eiActivePeriod>
+          <ei:eiEventSignals>
+            <ei:eiEventSignal>
+              <strm:intervals>
+                <ei:interval>
+                  <ei:signalPayload><ei:payloadFloat><ei:value>0.25</ei:value></ei:payloadFloat></ei:signalPayload>
+                </ei:interval>
+              </strm:intervals>
+              <ei:signalName>LOAD_DISPATCH</ei:signalName>
+              <ei:signalType>setpoint</ei:signalType>
+              <ei:signalID>dispatch-signal</ei:signalID>
+              <power:realPower>
+                <power:itemDescription>RealPower</power:itemDescription>
+                <power:itemUnits>W</power:itemUnits>
+                <scale:siScaleCode>M</scale:siScaleCode>
+              </power:realPower>
+            </ei:eiEventSignal>
+          </ei:eiEventSignals>
+        </ei:eiEvent>
+        <oadr:oadrResponseRequired>never</oadr:oadrResponseRequired>
+      </oadr:oadrEvent>
+    </oadr:oadrDistributeEvent>
+  </oadr:oadrSignedObject>
+</oadr:oadrPayload>"#;
+
+    const OADR_RESPONSE: &str = r#"<oadr:oadrPayload xmlns:oadr="http://openadr.org/oadr-2.0b/2012/07" xmlns:ei="http://docs.oasis-open.org/ns/energyinterop/201110" xmlns:pyld="http://docs.oasis-open.org/ns/energyinterop/201110/payloads">
+  <oadr:oadrSignedObject>
+    <oadr:oadrResponse ei:schemaVersion="2.0b">
+      <ei:eiResponse><ei:responseCode>200</ei:responseCode><ei:responseDescription>OK</ei:responseDescription><pyld:requestID/></ei:eiResponse>
+    </oadr:oadrResponse>
+  </oadr:oadrSignedObject>
+</oadr:oadrPayload>"#;
+
+    const CANCEL_REPORT: &str = r#"<oadr:oadrPayload xmlns:oadr="http://openadr.org/oadr-2.0b/2012/07" xmlns:ei="http://docs.oasis-open.org/ns/energyinterop/201110" xmlns:pyld="http://docs.oasis-open.org/ns/energyinterop/201110/payloads">
+  <oadr:oadrSignedObject>
+    <oadr:oadrCancelReport ei:schemaVersion="2.0b">
+      <pyld:requestID>cancel-7</pyld:requestID>
+      <ei:reportRequestID>telemetry-1</ei:reportRequestID>
+      <pyld:reportToFollow>false</pyld:reportToFollow>
+    </oadr:oadrCancelReport>
+  </oadr:oadrSignedObject>
+</oadr:oadrPayload>"#;
+
+    fn ven(vtn_url: &str) -> OpenAdrVen {
+        OpenAdrVen {
+            client:          Client::new(),
+            config:          OpenAdrConfig {
+                vtn_url:           vtn_url.to_owned(),
+                ven_name:          String::from("depot"),
+                poll_interval:     None,
+                report_interval:   Duration::from_secs(60),
+                simple_reductions: vec![20.0, 50.0, 80.0],
+                opt_in:            true,
+            },
+            verbose_mode:    false,
+            dr_events:       Arc::new(DrEventStore::new(None)),
+            prices:          Arc::new(PriceCurve::new()),
+            fleet:           Arc::new(FleetState::new()),
+            freshness:       FreshnessTracker::new(chrono::Duration::minutes(15)),
+            wake:            Arc::new(Notify::new()),
+            ven_id:          Some(String::from("ven-1")),
+            registration_id: Some(String::from("registration-1")),
+            poll_interval:   DEFAULT_POLL_INTERVAL,
+            report_requests: Vec::new(),
+            responded:       HashSet::new(),
+            next_request_id: 0,
+        }
+    }
+
+    async fn vtn(answer: fn(&Received) -> (u16, String)) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let (base_url, posts) = test_http::serve(answer).await;
+        (format!("{base_url}/OpenADR2/Simple/2.0b"), posts)
+    }
+
+    fn service(post: &Received) -> &str {
+        post.target.rsplit('/').next().unwrap_or_default()
+    }
+
+    fn vtn_events(document: &Document) -> Vec<VtnEvent> {
+        let distribute = payload(document, "oadrDistributeEvent").unwrap();
+        children(distribute, "oadrEvent").map(|event| parse_event(event).unwrap()).collect()
+    }
+
+    #[test]
+    fn durations_are_read_in_days_and_time() {
+        assert_eq!(parse_duration("PT1H30M"), Some(chrono::Duration::minutes(90)));
+        assert_eq!(parse_duration("P1DT12H"), Some(chrono::Duration::hours(36)));
+        assert_eq!(parse_duration("P1W"), Some(chrono::Duration::days(7)));
+        assert_eq!(parse_duration(" -PT15M "), Some(chrono::Duration::minutes(-15)));
+        assert_eq!(parse_duration("PT0.5S"), Some(chrono::Duration::milliseconds(500)));
+        assert_eq!(parse_duration("PT0S"), Some(chrono::Duration::zero()));
+
+        //Years and months have no fixed length, and units must follow their number
+        assert_eq!(parse_duration("P1M"), None);
+        assert_eq!(parse_duration("P1Y"), None);
+        assert_eq!(parse_duration("PT1H5"), None);
+        assert_eq!(parse_duration("1H"), None);
+    }
+
+    #[test]
+    fn vtn_events_are_read_with_their_intervals() {
+        let document = Document::parse(DISTRIBUTE_EVENT).unwrap();
+        let events = vtn_events(&document);
+        assert_eq!(events.len(), 2);
+
+        let start = "2099-10-18T20:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let simple = &events[0];
+        assert_eq!((simple.id.as_str(), simple.modification.as_str(), simple.status.as_str()), ("simple-1", "2", "far"));
+        assert!(simple.response_required);
+        let signal = &simple.signals[0];
+        assert_eq!((signal.name.as_str(), signal.kind.as_str()), ("SIMPLE", "level"));
+        let intervals: Vec<(&str, DateTime<Utc>, DateTime<Utc>, f32)> = signal.intervals
+            .iter()
+            .map(|interval| (interval.uid.as_str(), interval.start, interval.end, interval.value))
+            .collect();
+        //The second interval is cut short at the end of the event's active period
+        assert_eq!(intervals, vec![
+            ("0", start, start + chrono::Duration::hours(1), 1.0),
+            ("1", start + chrono::Duration::hours(1), start + chrono::Duration::minutes(90), 3.0),
+        ]);
+
+        let dispatch = &events[1];
+        assert!(!dispatch.response_required);
+        let signal = &dispatch.signals[0];
+        assert_eq!(signal.scale_w, 1_000_000.0);
+        assert_eq!(signal.intervals[0].uid, "0");
+        assert_eq!(signal.intervals[0].end, start + chrono::Duration::hours(3));
+    }
+
+    #[test]
+    fn load_shed_signals_become_depot_limits() {
+        let document = Document::parse(DISTRIBUTE_EVENT).unwrap();
+        let events = vtn_events(&document);
+        let ven = ven("http://127.0.0.1:9");
+        let simple = &events[0].signals[0];
+
+        //SIMPLE level 0 is normal operation, levels past the configured ones take the last reduction
+        assert_eq!(ven.dr_limit(simple, 0.0), None);
+        assert_eq!(ven.dr_limit(simple, 1.0), Some(DrLimit::PercentReduction(20.0)));
+        assert_eq!(ven.dr_limit(simple, 3.0), Some(DrLimit::PercentReduction(80.0)));
+        assert_eq!(ven.dr_limit(simple, 4.0), Some(DrLimit::PercentReduction(80.0)));
+
+        //0.25MW dispatch setpoint
+        assert_eq!(ven.dr_limit(&events[1].signals[0], 0.25), Some(DrLimit::Kw(250.0)));
+    }
+
+    #[tokio::test]
+    async fn event_responses_count_once_the_vtn_accepts_them() {
+        let document = Document::parse(DISTRIBUTE_EVENT).unwrap();
+        let distribute = payload(&document, "oadrDistributeEvent").unwrap();
+
+        //A VTN which cannot take the response leaves the event to be answered on the next poll
+        let (failing_url, _) = vtn(|_| (500, String::from("unavailable"))).await;
+        let mut ven = ven(&failing_url);
+        assert!(ven.distribute_events(distribute).await.is_err());
+        assert!(ven.responded.is_empty());
+        assert_eq!(ven.dr_events.all().len(), 3);
+
+        let (vtn_url, posts) = vtn(|_| (200, String::from(OADR_RESPONSE))).await;
+        ven.config.vtn_url = vtn_url;
+        ven.distribute_events(distribute).await.unwrap();
+        assert_eq!(ven.responded, HashSet::from([(String::from("simple-1"), String::from("2"))]));
+        {
+            let posts = posts.lock().unwrap();
+            assert_eq!(posts.len(), 1);
+            assert_eq!(service(&posts[0]), "EiEvent");
+            let sent = Document::parse(&posts[0].body).unwrap();
+            let created = payload(&sent, "eiCreatedEvent").unwrap();
+            assert_eq!(text(created, &["eiResponse", "requestID"]), Some("distribute-42"));
+            let responses: Vec<Node> = children(child(created, "eventResponses").unwrap(), "eventResponse").collect();
+            assert_eq!(responses.len(), 1);
+            assert_eq!(text(responses[0], &["requestID"]), Some("distribute-42"));
+            assert_eq!(text(responses[0], &["qualifiedEventID", "eventID"]), Some("simple-1"));
+            assert_eq!(text(responses[0], &["optType"]), Some("optIn"));
+        }
+
+        //Nothing is sent again for an event already answered
+        ven.distribute_events(distribute).await.unwrap();
+        assert_eq!(posts.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_reports_are_acknowledged_on_the_report_service() {
+        let (vtn_url, posts) = vtn(|post| match service(post) {
+            "OadrPoll" => (200, String::from(CANCEL_REPORT)),
+            _ => (200, String::from(OADR_RESPONSE)),
+        }).await;
+        let mut ven = ven(&vtn_url);
+        ven.report_requests.push(ReportRequest { request_id: String::from("telemetry-1"), granularity: None, sent_at: None });
+
+        ven.poll().await.unwrap();
+        assert!(ven.report_requests.is_empty());
+        let posts = posts.lock().unwrap();
+        let services: Vec<&str> = posts.iter().map(service).collect();
+        assert_eq!(services, vec!["OadrPoll", "EiReport"]);
+        let sent = Document::parse(&posts[1].body).unwrap();
+        let canceled = payload(&sent, "oadrCanceledReport").unwrap();
+        assert_eq!(text(canceled, &["eiResponse", "requestID"]), Some("cancel-7"));
+    }
+}
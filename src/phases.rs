@@ -0,0 +1,277 @@
+use std::{collections::HashMap, str::FromStr};
+use crate::{error::Error, planner::ChargePlan};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    L1,
+    L2,
+    L3,
+}
+
+impl FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Phase, String> {
+        // accept the line to neutral form OCPP uses, IE L1-N
+        match s.trim().trim_end_matches("-N") {
+            "L1" => Ok(Phase::L1),
+            "L2" => Ok(Phase::L2),
+            "L3" => Ok(Phase::L3),
+            other => Err(format!("unknown phase {other}, expected L1, L2 or L3")),
+        }
+    }
+}
+
+// Settings for balancing single phase AC chargers across the three phases
+pub struct PhaseBalancing {
+    pub assignments:           HashMap<String, Phase>,  // charger id -> phase it is wired to
+    pub voltage:               f32,                     // line to neutral voltage
+    pub current_limit_a:       Option<f32>,             // per phase current limit
+    pub max_imbalance_percent: Option<f32>,
+}
+
+impl PhaseBalancing {
+    pub fn parse_assignments(assignments: &str) -> Result<HashMap<String, Phase>, Error> {
+        /*
+         * Parse CHARGER_PHASES, a comma separated list of charger_id:phase pairs
+         * IE "CH1:L1,CH2:L2,CH3:L3".
+         */
+        assignments
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (charger_id, phase) = pair
+                    .rsplit_once(':')
+                    .ok_or_else(|| Error::Config(format!("Unable to parse CHARGER_PHASES entry \"{pair}\", expected charger_id:phase")))?;
+                let phase = phase
+                    .parse::<Phase>()
+                    .map_err(|err| Error::Config(format!("Unable to parse CHARGER_PHASES entry \"{pair}\": {err}")))?;
+                Ok((charger_id.trim().to_owned(), phase))
+            })
+            .collect()
+    }
+
+    pub fn phase_for(&self, charger_id: &str, measured_phase: Option<Phase>) -> Option<Phase> {
+        /*
+         * The phase a charger draws from. The configured assignment wins, the
+         * phase seen in its meter values is used for chargers without one.
+         */
+        match (self.assignments.get(charger_id), measured_phase) {
+            (Some(configured), Some(measured)) if *configured != measured => {
+                eprintln!("Charger {} is configured on {:?} but its meter values report {:?}, using {:?}", charger_id, configured, measured, configured);
+                Some(*configured)
+            }
+            (Some(configured), _) => Some(*configured),
+            (None, measured) => measured,
+        }
+    }
+
+    pub fn fit(&self, plans: &mut [ChargePlan], floor: f32, verbose_mode: &bool) {
+        /*
+         * Keep each phase's current under its limit, then bring down the most
+         * loaded phases until the imbalance is under the allowed percentage.
+         * Plans for three phase chargers (no phase) put a third of their rate on
+         * every phase. They count towards each phase's load and are scaled with
+         * the single phase plans to meet the current limit, but as they load the
+         * phases evenly only single phase plans are brought down for imbalance.
+         * Imbalance is measured as (max - min) / max across the phases which
+         * have at least one single phase charger drawing from them. A charger
+         * is taken to load a phase with what it was measured drawing on it when
+         * that is less than its planned rate, IE a bus which is tapering, so
+         * only phases actually drawing more than allowed have their rates cut.
+         */
+        let phases = [Phase::L1, Phase::L2, Phase::L3];
+
+        if let Some(current_limit_a) = self.current_limit_a {
+            for phase in phases {
+                if fit_phase(plans, phase, current_limit_a * self.voltage, floor, true) && *verbose_mode {
+                    println!("phase {:?} limited to {}A", phase, current_limit_a);
+                }
+            }
+        }
+
+        let Some(max_imbalance_percent) = self.max_imbalance_percent else { return };
+
+        let totals: Vec<(Phase, f32)> = phases
+            .iter()
+            .filter(|phase| plans.iter().any(|plan| plan.phase == Some(**phase) && plan.charge_rate > 0.0))
+            .map(|phase| (*phase, plans.iter().map(|plan| self.drawn_w(plan, *phase)).sum::<f32>()))
+            .collect();
+        if totals.len() < 2 {
+            return;
+        }
+
+        let min_total = totals.iter().map(|(_, total)| *total).fold(f32::MAX, f32::min);
+        let allowed_max = min_total / (1.0 - max_imbalance_percent.clamp(0.0, 99.0) / 100.0);
+        for (phase, total) in totals {
+            if total > allowed_max {
+                fit_phase(plans, phase, allowed_max, floor, false);
+                if *verbose_mode {
+                    println!("phase {:?} reduced from {:.1}A to {:.1}A to stay within {}% imbalance",
+                        phase, total / self.voltage, allowed_max / self.voltage, max_imbalance_percent);
+                }
+            }
+        }
+    }
+
+    fn drawn_w(&self, plan: &ChargePlan, phase: Phase) -> f32 {
+        /*
+         * The load a plan puts on a phase, its planned rate (a third of it for
+         * three phase chargers) or the current measured on the phase if lower.
+         */
+        let planned_w = match plan.phase {
+            Some(plan_phase) if plan_phase == phase => plan.charge_rate,
+            Some(_) => return 0.0,
+            None => plan.charge_rate / 3.0,
+        };
+        match plan.measured_a.iter().find(|(measured_phase, _)| *measured_phase == phase) {
+            Some((_, current_a)) => planned_w.min(current_a * self.voltage),
+            None => planned_w,
+        }
+    }
+}
+
+fn fit_phase(plans: &mut [ChargePlan], phase: Phase, limit_w: f32, floor: f32, scale_three_phase: bool) -> bool {
+    /*
+     * Fit the load on one phase under a limit. Each member is weighted by the
+     * share of its rate the phase carries, a third for three phase plans. When
+     * three phase plans are not scaled their share is held and only the single
+     * phase plans make room. Like fit_plans, plans which cannot meet their
+     * target keep their rate as long as curtailing the others makes enough room.
+     *
+     * @Output: true if any rate had to be scaled down
+     */
+    let three_phase_w = match scale_three_phase {
+        true => 0.0,
+        false => plans.iter().filter(|plan| plan.phase.is_none()).map(|plan| plan.charge_rate).sum::<f32>() / 3.0,
+    };
+    let members: Vec<(usize, f32)> = plans
+        .iter()
+        .enumerate()
+        .filter_map(|(index, plan)| match plan.phase {
+            Some(plan_phase) if plan_phase == phase => Some((index, 1.0)),
+            None if scale_three_phase => Some((index, 1.0 / 3.0)),
+            _ => None,
+        })
+        .collect();
+
+    let (infeasible, others): (Vec<_>, Vec<_>) = members.into_iter().partition(|(index, _)| plans[*index].infeasible);
+    let limit_w = limit_w - three_phase_w;
+    let mut curtailed = fit_shares(plans, &others, limit_w - phase_load_w(plans, &infeasible), floor);
+    curtailed |= fit_shares(plans, &infeasible, limit_w - phase_load_w(plans, &others), floor);
+    curtailed
+}
+
+fn phase_load_w(plans: &[ChargePlan], members: &[(usize, f32)]) -> f32 {
+    members.iter().map(|(index, share)| plans[*index].charge_rate * share).sum()
+}
+
+fn fit_shares(plans: &mut [ChargePlan], members: &[(usize, f32)], limit_w: f32, floor: f32) -> bool {
+    /*
+     * Proportionally scale the portion of each member's rate above the floor so
+     * the phase's load from them is at most the limit, as fit_to_limit does for
+     * members which draw their whole rate from the phase.
+     */
+    let load_w = phase_load_w(plans, members);
+    if load_w <= limit_w {
+        return false;
+    }
+
+    let floor_w: f32 = members.iter().map(|(_, share)| floor * share).sum();
+    let above_floor_w = load_w - floor_w;
+    let factor = if limit_w <= floor_w || above_floor_w <= 0.0 {
+        0.0
+    } else {
+        (limit_w - floor_w) / above_floor_w
+    };
+
+    for (index, _) in members {
+        let plan = &mut plans[*index];
+        plan.charge_rate = floor + (plan.charge_rate - floor).max(0.0) * factor;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(charger_id: &str, phase: Option<Phase>, charge_rate: f32) -> ChargePlan {
+        ChargePlan { charger_id: charger_id.to_owned(), charge_rate, phase, ..Default::default() }
+    }
+
+    fn phase_load_a(plans: &[ChargePlan], phase: Phase, voltage: f32) -> f32 {
+        plans
+            .iter()
+            .map(|plan| match plan.phase {
+                Some(plan_phase) if plan_phase == phase => plan.charge_rate,
+                None => plan.charge_rate / 3.0,
+                _ => 0.0,
+            })
+            .sum::<f32>() / voltage
+    }
+
+    #[test]
+    fn three_phase_plans_count_towards_the_current_limit() {
+        let balancing = PhaseBalancing {
+            assignments:           HashMap::new(),
+            voltage:               230.0,
+            current_limit_a:       Some(63.0),
+            max_imbalance_percent: None,
+        };
+        // L1 carries 10kW plus a third of the 22kW three phase charger, about 75A
+        let mut plans = vec![plan("CH1", Some(Phase::L1), 10_000.0), plan("CH2", None, 22_000.0)];
+        balancing.fit(&mut plans, 800.0, &false);
+
+        for phase in [Phase::L1, Phase::L2, Phase::L3] {
+            let load_a = phase_load_a(&plans, phase, 230.0);
+            assert!(load_a <= 63.0 + 0.01, "{phase:?} at {load_a}A");
+        }
+        assert!(plans[1].charge_rate < 22_000.0, "the three phase plan was not scaled");
+    }
+
+    #[test]
+    fn three_phase_plans_are_held_for_imbalance() {
+        let balancing = PhaseBalancing {
+            assignments:           HashMap::new(),
+            voltage:               230.0,
+            current_limit_a:       None,
+            max_imbalance_percent: Some(20.0),
+        };
+        let mut plans = vec![
+            plan("CH1", Some(Phase::L1), 20_000.0),
+            plan("CH2", Some(Phase::L2), 5_000.0),
+            plan("CH3", None, 30_000.0),
+        ];
+        balancing.fit(&mut plans, 800.0, &false);
+
+        // L2 carries 15kW, so L1 may carry 18.75kW of which 10kW is the three phase share
+        assert_eq!(plans[2].charge_rate, 30_000.0);
+        let l1_a = phase_load_a(&plans, Phase::L1, 230.0);
+        let l2_a = phase_load_a(&plans, Phase::L2, 230.0);
+        assert!((l1_a - l2_a) / l1_a <= 0.2 + 0.001, "L1 {l1_a}A, L2 {l2_a}A");
+    }
+
+    #[test]
+    fn imbalance_is_judged_on_measured_current() {
+        let balancing = PhaseBalancing {
+            assignments:           HashMap::new(),
+            voltage:               230.0,
+            current_limit_a:       None,
+            max_imbalance_percent: Some(20.0),
+        };
+        // on plans alone L1 looks far more loaded, but its bus is tapering at 10kW
+        let mut plans = vec![plan("CH1", Some(Phase::L1), 20_000.0), plan("CH2", Some(Phase::L2), 12_000.0)];
+        plans[0].measured_a = vec![(Phase::L1, 10_000.0 / 230.0)];
+        plans[1].measured_a = vec![(Phase::L2, 12_000.0 / 230.0)];
+        balancing.fit(&mut plans, 800.0, &false);
+        assert_eq!(plans[0].charge_rate, 20_000.0);
+        assert_eq!(plans[1].charge_rate, 12_000.0);
+
+        // once L1 is drawing its full rate it is brought down to within 20% of L2
+        plans[0].measured_a = vec![(Phase::L1, 20_000.0 / 230.0)];
+        balancing.fit(&mut plans, 800.0, &false);
+        assert!((plans[0].charge_rate - 15_000.0).abs() < 1.0, "L1 at {}W", plans[0].charge_rate);
+        assert_eq!(plans[1].charge_rate, 12_000.0);
+    }
+}
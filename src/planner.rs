@@ -0,0 +1,270 @@
+use chrono::{DateTime, Duration, Local};
+use crate::{phases::Phase, price_curve::ExpensivePeriod, roster::PreconditionWindow, soc_estimator::SocReading, types::ChargingBounds};
+
+// Energy a plan has to deliver by its deadline, kept so the plan can be checked
+// again once it has been fit under the site's shared limits
+#[derive(Debug, Clone, Copy)]
+pub struct DeadlineTarget {
+    pub energy_needed_wh: f32,
+    pub deadline:         DateTime<Local>,
+}
+
+// Charge rate decided for a single connector during a recalculation, before
+// it is turned into a charge profile and sent to chargerhub
+#[derive(Debug, Clone, Default)]
+pub struct ChargePlan {
+    pub charger_id:     String,
+    pub connector_id:   i32,
+    pub transaction_id: i32,
+    pub charge_rate:    f32,                        // watts, until charging should be finished
+    pub precondition:   Option<PreconditionWindow>,
+    pub calculated:     bool,                       // rate was calculated from a reported SOC or delivered energy
+    pub phase:          Option<Phase>,              // phase a single phase AC charger draws from
+    pub measured_a:     Vec<(Phase, f32)>,          // current last measured on each phase the charger reports
+    pub soc:            Option<SocReading>,         // measured or estimated SOC the rate was planned from
+    pub infeasible:     bool,                       // target cannot be met by the deadline even at the upper bound
+    pub stale:          Option<Duration>,           // age of the meter value if it was too old to plan from
+    pub expensive:      Vec<ExpensivePeriod>,       // priced periods charging is held at the lower bound through
+    pub target:         Option<DeadlineTarget>,     // None for rates not planned towards a target
+}
+
+impl ChargePlan {
+    pub fn schedule(&self, right_now: DateTime<Local>, stop_time: DateTime<Local>, crg_bounds: ChargingBounds) -> (Vec<i32>, Vec<f32>) {
+        /*
+         * Build the start periods (seconds from now) and charge rates for the
+         * charge profile. Charging drops to the lower bound through the expensive
+         * periods the price curve moved it out of. Buses which pre-condition before
+         * the curtailment window ends drop to the lower bound once their
+         * pre-conditioning window starts, leaving the headroom for the HVAC load.
+         */
+        let lower_bnd = crg_bounds.lower_bnd as f32;
+        let offset = |at: DateTime<Local>| (at - right_now).num_seconds().max(0) as i32;
+        let mut periods: Vec<(i32, f32)> = vec![(0, self.charge_rate)];
+        let mut push = |start_period: i32, rate: f32| {
+            match periods.last_mut() {
+                Some(last) if last.0 == start_period => last.1 = rate,
+                Some(last) if last.1 == rate => {}
+                _ => periods.push((start_period, rate)),
+            }
+        };
+        for (start, end) in &self.expensive {
+            push(offset(*start), lower_bnd);
+            push(offset(*end), self.charge_rate);
+        }
+
+        if let Some(window) = self.precondition.filter(|window| window.start < stop_time) {
+            let precondition_start = offset(window.start);
+            periods.retain(|(start_period, _)| *start_period < precondition_start);
+            if periods.last().is_none_or(|last| last.1 != lower_bnd) {
+                periods.push((precondition_start, lower_bnd));
+            }
+        }
+        periods.into_iter().unzip()
+    }
+
+    pub fn shortfall_wh(&self, right_now: DateTime<Local>, crg_bounds: ChargingBounds) -> Option<f32> {
+        /*
+         * How far short of its target the plan's schedule leaves the bus at its
+         * deadline, charging at the plan's rate except through the expensive
+         * periods, where it is held at the lower bound.
+         */
+        let target = self.target?;
+        let hours = |start: DateTime<Local>, end: DateTime<Local>| {
+            (end.min(target.deadline) - start.max(right_now)).num_seconds().max(0) as f32 / 3600.0
+        };
+        let expensive_hours: f32 = self.expensive.iter().map(|(start, end)| hours(*start, *end)).sum();
+        let delivered_wh = self.charge_rate * (hours(right_now, target.deadline) - expensive_hours)
+            + crg_bounds.lower_bnd as f32 * expensive_hours;
+        Some(target.energy_needed_wh - delivered_wh).filter(|shortfall_wh| *shortfall_wh > 0.0)
+    }
+
+    pub fn reserved_w(&self, right_now: DateTime<Local>, stop_time: DateTime<Local>) -> f32 {
+        /*
+         * HVAC load to keep free for this bus if it pre-conditions at any point
+         * before the curtailment window ends.
+         */
+        match self.precondition {
+            Some(window) if window.start < stop_time && window.departure > right_now => window.reserve_w,
+            _ => 0.0,
+        }
+    }
+}
+
+pub fn charge_deadline(stop_time: DateTime<Local>, precondition: Option<PreconditionWindow>) -> DateTime<Local> {
+    /*
+     * Battery charging must be done by the end of the curtailment window or the
+     * start of pre-conditioning, whichever comes first.
+     */
+    match precondition {
+        Some(window) if window.start < stop_time => window.start,
+        _ => stop_time,
+    }
+}
+
+pub fn clamp_plans(plans: &mut [ChargePlan], crg_bounds: ChargingBounds) {
+    /*
+     * Clamp every plan between the charge rate bounds before fitting them under
+     * any limits, chargers are never sent a rate outside of these anyway.
+     */
+    for plan in plans.iter_mut() {
+        plan.charge_rate = plan.charge_rate.clamp(crg_bounds.lower_bnd as f32, crg_bounds.upper_bnd as f32);
+    }
+}
+
+pub fn fit_to_site_limit(
+    plans: &mut [ChargePlan],
+    site_limit_w: f32,
+    right_now: DateTime<Local>,
+    stop_time: DateTime<Local>,
+    crg_bounds: ChargingBounds,
+    verbose_mode: &bool)
+{
+    /*
+     * Scale every (already clamped) plan down so the sum of all charge rates plus
+     * the HVAC load of buses pre-conditioning before the window ends stays under
+     * the site limit.
+     */
+    let reserved_w: f32 = plans
+        .iter()
+        .map(|plan| plan.reserved_w(right_now, stop_time))
+        .sum();
+
+    let members: Vec<usize> = (0..plans.len()).collect();
+    let curtailed = fit_plans(plans, &members, site_limit_w - reserved_w, crg_bounds.lower_bnd as f32);
+
+    if *verbose_mode {
+        println!("site limit {}W, {}W reserved for pre-conditioning, plans {}",
+            site_limit_w,
+            reserved_w,
+            if curtailed { "scaled down to fit" } else { "fit without scaling" }
+        );
+    }
+}
+
+pub fn fit_plans(plans: &mut [ChargePlan], members: &[usize], limit: f32, floor: f32) -> bool {
+    /*
+     * Fit the given plans under a limit. Plans which cannot meet their target
+     * even at full power keep it as long as curtailing the other plans (down to
+     * the floor) makes enough room, otherwise they are scaled down as well.
+     *
+     * @Output: true if any rate had to be scaled down
+     */
+    let (infeasible, others): (Vec<usize>, Vec<usize>) = members.iter().partition(|index| plans[**index].infeasible);
+    let infeasible_w: f32 = infeasible.iter().map(|index| plans[*index].charge_rate).sum();
+
+    let mut curtailed = fit_members(plans, &others, limit - infeasible_w, floor);
+    let others_w: f32 = others.iter().map(|index| plans[*index].charge_rate).sum();
+    curtailed |= fit_members(plans, &infeasible, limit - others_w, floor);
+    curtailed
+}
+
+fn fit_members(plans: &mut [ChargePlan], members: &[usize], limit: f32, floor: f32) -> bool {
+    let mut rates: Vec<f32> = members.iter().map(|index| plans[*index].charge_rate).collect();
+    let curtailed = fit_to_limit(&mut rates, limit, floor);
+    for (index, rate) in members.iter().zip(rates) {
+        plans[*index].charge_rate = rate;
+    }
+    curtailed
+}
+
+pub fn fit_to_limit(rates: &mut [f32], limit: f32, floor: f32) -> bool {
+    /*
+     * Proportionally scale the portion of each rate above the floor so the
+     * rates sum to at most the limit. If even the floors exceed the limit every
+     * rate is set to the floor, since chargers cannot be sent less than that.
+     *
+     * @Output: true if the rates had to be scaled down
+     */
+    let total: f32 = rates.iter().sum();
+    if total <= limit {
+        return false;
+    }
+
+    let floor_total = floor * rates.len() as f32;
+    let above_floor = total - floor_total;
+    let factor = if limit <= floor_total || above_floor <= 0.0 {
+        0.0
+    } else {
+        (limit - floor_total) / above_floor
+    };
+
+    for rate in rates.iter_mut() {
+        *rate = floor + (*rate - floor).max(0.0) * factor;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(charge_rate: f32, expensive: Vec<ExpensivePeriod>, target: Option<DeadlineTarget>) -> ChargePlan {
+        ChargePlan { charger_id: String::from("charger"), charge_rate, expensive, target, ..Default::default() }
+    }
+
+    #[test]
+    fn expensive_periods_count_at_the_lower_bound() {
+        let right_now = Local::now();
+        let bounds = ChargingBounds { lower_bnd: 2000, upper_bnd: 20000 };
+        let target = Some(DeadlineTarget { energy_needed_wh: 40000.0, deadline: right_now + Duration::hours(4) });
+        let expensive = vec![(right_now + Duration::hours(1), right_now + Duration::hours(2))];
+
+        //3 hours at 12kW and one at the lower bound make 38kWh, 2kWh short
+        let shortfall_wh = plan(12000.0, expensive.clone(), target).shortfall_wh(right_now, bounds).unwrap();
+        assert!((shortfall_wh - 2000.0).abs() < 1.0);
+
+        //Charging through the expensive period covers it
+        assert_eq!(plan(12000.0, Vec::new(), target).shortfall_wh(right_now, bounds), None);
+
+        //Periods after the deadline do not take anything away
+        let late = vec![(right_now + Duration::hours(5), right_now + Duration::hours(6))];
+        assert_eq!(plan(10000.0, late, target).shortfall_wh(right_now, bounds), None);
+        assert_eq!(plan(10000.0, expensive, None).shortfall_wh(right_now, bounds), None);
+    }
+
+    #[test]
+    fn pre_conditioning_load_is_kept_free_under_the_site_limit() {
+        let right_now = Local::now();
+        let stop_time = right_now + Duration::hours(6);
+        let bounds = ChargingBounds { lower_bnd: 2000, upper_bnd: 20000 };
+        let window = PreconditionWindow {
+            start:     right_now + Duration::hours(1),
+            departure: right_now + Duration::hours(2),
+            reserve_w: 10000.0,
+        };
+        let mut preconditioning = plan(20000.0, Vec::new(), None);
+        preconditioning.precondition = Some(window);
+        let mut plans = vec![preconditioning, plan(20000.0, Vec::new(), None)];
+
+        //10kW of the 40kW limit is kept for the HVAC load, the rest is shared above the lower bound
+        fit_to_site_limit(&mut plans, 40000.0, right_now, stop_time, bounds, &false);
+        assert!((plans[0].charge_rate - 15000.0).abs() < 1.0);
+        assert!((plans[1].charge_rate - 15000.0).abs() < 1.0);
+
+        //Charging finishes when pre-conditioning starts, dropping to the lower bound
+        assert_eq!(charge_deadline(stop_time, Some(window)), window.start);
+        assert_eq!(plans[0].schedule(right_now, stop_time, bounds), (vec![0, 3600], vec![15000.0, 2000.0]));
+
+        //A window after the curtailment window has ended takes nothing from it
+        assert_eq!(plans[0].reserved_w(right_now, right_now + Duration::minutes(30)), 0.0);
+        assert_eq!(charge_deadline(right_now + Duration::minutes(30), Some(window)), right_now + Duration::minutes(30));
+    }
+
+    #[test]
+    fn buses_short_of_their_deadline_are_fit_last() {
+        let right_now = Local::now();
+        let bounds = ChargingBounds { lower_bnd: 2000, upper_bnd: 100_000 };
+        let mut short = plan(100_000.0, Vec::new(), None);
+        short.infeasible = true;
+        let mut plans = vec![short, plan(50_000.0, Vec::new(), None), plan(50_000.0, Vec::new(), None)];
+
+        //The others make room for the bus which is short, down to the lower bound if they have to
+        fit_to_site_limit(&mut plans, 150_000.0, right_now, right_now + Duration::hours(6), bounds, &false);
+        let rates: Vec<f32> = plans.iter().map(|plan| plan.charge_rate.round()).collect();
+        assert_eq!(rates, vec![100_000.0, 25_000.0, 25_000.0]);
+
+        fit_to_site_limit(&mut plans, 90_000.0, right_now, right_now + Duration::hours(6), bounds, &false);
+        let rates: Vec<f32> = plans.iter().map(|plan| plan.charge_rate.round()).collect();
+        assert_eq!(rates, vec![86_000.0, 2000.0, 2000.0]);
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Local, Utc};
+use std::sync::{atomic::{AtomicU64, Ordering}, Mutex};
+
+// The price of electricity over one period, in whatever currency the VTN prices in
+#[derive(Debug, Clone, PartialEq)]
+pub struct PricePeriod {
+    pub start: DateTime<Utc>,
+    pub end:   DateTime<Utc>,
+    pub price: f32,   // per kWh
+}
+
+// A stretch of time charging is held at its lower bound through
+pub type ExpensivePeriod = (DateTime<Local>, DateTime<Local>);
+
+// Prices the utility has published for the coming hours. Periods are replaced
+// as a whole whenever the VTN sends new ones, and the version lets the run
+// loop re-plan once they change.
+#[derive(Default)]
+pub struct PriceCurve {
+    periods: Mutex<Vec<PricePeriod>>,
+    version: AtomicU64,
+}
+
+impl PriceCurve {
+    pub fn new() -> PriceCurve {
+        PriceCurve::default()
+    }
+
+    pub fn replace(&self, mut periods: Vec<PricePeriod>) {
+        periods.sort_by_key(|period| period.start);
+        let mut held = self.periods.lock().unwrap();
+        if *held != periods {
+            *held = periods;
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    pub fn expensive_periods(
+        &self,
+        right_now: DateTime<Local>,
+        deadline: DateTime<Local>,
+        energy_needed_wh: f32,
+        max_w: f32) -> Option<(f32, Vec<ExpensivePeriod>)>
+    {
+        /*
+         * Move a session's charging into the cheapest stretches before its
+         * deadline. The window is split wherever the price changes, the cheapest
+         * pieces are taken until max_w could deliver the energy in them, and the
+         * rest are returned as the periods to hold the bus at its lower bound.
+         * Stretches without a published price count as the most expensive.
+         *
+         * @Output: the rate to charge at outside of the expensive periods, and
+         *          the expensive periods. None when there is nothing to shift.
+         */
+        let periods = self.periods.lock().unwrap();
+        let right_now = right_now.with_timezone(&Utc);
+        let deadline = deadline.with_timezone(&Utc);
+        if energy_needed_wh <= 0.0 || max_w <= 0.0 || deadline <= right_now {
+            return None;
+        }
+        if !periods.iter().any(|period| period.start < deadline && period.end > right_now) {
+            return None;
+        }
+
+        let mut boundaries: Vec<DateTime<Utc>> = periods
+            .iter()
+            .flat_map(|period| [period.start, period.end])
+            .filter(|boundary| *boundary > right_now && *boundary < deadline)
+            .collect();
+        boundaries.push(right_now);
+        boundaries.push(deadline);
+        boundaries.sort();
+        boundaries.dedup();
+
+        let unpriced = periods.iter().map(|period| period.price).fold(f32::MIN, f32::max);
+        let mut pieces: Vec<(DateTime<Utc>, DateTime<Utc>, f32)> = boundaries
+            .windows(2)
+            .map(|piece| {
+                let price = periods
+                    .iter()
+                    .find(|period| period.start <= piece[0] && period.end > piece[0])
+                    .map_or(unpriced, |period| period.price);
+                (piece[0], piece[1], price)
+            })
+            .collect();
+        pieces.sort_by(|a, b| a.2.total_cmp(&b.2).then(a.0.cmp(&b.0)));
+
+        // take pieces cheapest first, along with any others at the same price
+        let mut charging_hours = 0.0;
+        let mut threshold = None;
+        for (start, end, price) in &pieces {
+            if threshold.is_some_and(|threshold| *price > threshold) {
+                break;
+            }
+            charging_hours += (*end - *start).num_seconds() as f32 / 3600.0;
+            if threshold.is_none() && max_w * charging_hours >= energy_needed_wh {
+                threshold = Some(*price);
+            }
+        }
+        let threshold = threshold?;
+
+        let mut expensive: Vec<ExpensivePeriod> = pieces
+            .iter()
+            .filter(|(_, _, price)| *price > threshold)
+            .map(|(start, end, _)| (start.with_timezone(&Local), end.with_timezone(&Local)))
+            .collect();
+        if expensive.is_empty() {
+            return None;
+        }
+        expensive.sort();
+        Some((energy_needed_wh / charging_hours, expensive))
+    }
+}
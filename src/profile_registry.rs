@@ -0,0 +1,92 @@
+use std::ops::RangeInclusive;
+
+// A TxProfile this service installed, bound to the transaction it was sent for
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedProfile {
+    pub charger_id:     String,
+    pub connector_id:   i32,
+    pub transaction_id: i32,
+    pub stack_level:    i32,
+}
+
+// Tracks which charging profiles belong to this service, so only those are
+// ever updated or cleared. Profiles are kept within a range of stack levels
+// reserved for this service, leaving every other level (and any
+// TxDefaultProfile) to the systems which set them.
+pub struct ProfileRegistry {
+    stack_levels: RangeInclusive<i32>,
+    profiles:     Vec<OwnedProfile>,
+    accepted:     Vec<OwnedProfile>,   // profiles a charger has accepted at least once
+}
+
+impl ProfileRegistry {
+    pub fn new(stack_levels: RangeInclusive<i32>) -> ProfileRegistry {
+        ProfileRegistry {
+            stack_levels,
+            profiles: Vec::new(),
+            accepted: Vec::new(),
+        }
+    }
+
+    pub fn profile_for(&mut self, charger_id: &str, connector_id: i32, transaction_id: i32) -> Option<OwnedProfile> {
+        /*
+         * The profile to send for a transaction. A transaction keeps the stack
+         * level it was first given so every update replaces the same profile,
+         * new transactions get the lowest level in the range which is not still
+         * held by another of our profiles on the connector.
+         *
+         * @Output: None if every reserved stack level on the connector is taken
+         */
+        if let Some(profile) = self.profiles.iter().find(|profile| profile.transaction_id == transaction_id && profile.charger_id == charger_id) {
+            return Some(profile.clone());
+        }
+
+        let stack_level = self.stack_levels.clone().find(|level| {
+            !self.profiles.iter().any(|profile| {
+                profile.charger_id == charger_id && profile.connector_id == connector_id && profile.stack_level == *level
+            })
+        })?;
+
+        let profile = OwnedProfile {
+            charger_id: charger_id.to_owned(),
+            connector_id,
+            transaction_id,
+            stack_level,
+        };
+        self.profiles.push(profile.clone());
+        Some(profile)
+    }
+
+    pub fn record_outcome(&mut self, owned: &OwnedProfile, accepted: bool) {
+        /*
+         * Record the charger's answer to a profile. A profile which was never
+         * accepted is not in effect anywhere, so a failure frees its stack level;
+         * a failed update leaves the previously accepted version in effect.
+         */
+        let previously_accepted = self.accepted.contains(owned);
+        if accepted && !previously_accepted {
+            self.accepted.push(owned.clone());
+        }
+        else if !accepted && !previously_accepted {
+            self.forget(owned);
+        }
+    }
+
+    pub fn retain_transactions(&mut self, active_transactions: &[i32]) {
+        /*
+         * Forget profiles whose transaction has ended, chargers discard a
+         * TxProfile along with its transaction.
+         */
+        self.profiles.retain(|profile| active_transactions.contains(&profile.transaction_id));
+        self.accepted.retain(|profile| active_transactions.contains(&profile.transaction_id));
+    }
+
+    pub fn owned(&self) -> &[OwnedProfile] {
+        &self.profiles
+    }
+
+    pub fn forget(&mut self, owned: &OwnedProfile) {
+        self.profiles.retain(|profile| profile != owned);
+        self.accepted.retain(|profile| profile != owned);
+    }
+}
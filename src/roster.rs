@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use chrono::{DateTime, Duration, Local, NaiveTime};
+use std::collections::HashSet;
+use crate::error::Error;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct VehicleType {
+    pub name:                 String,
+    pub battery_capacity:     i32,  // KwH
+    pub precondition_minutes: i64,  // length of cabin pre-conditioning before pull-out
+    pub precondition_kw:      f32,  // HVAC load drawn from shore power while pre-conditioning
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Vehicle {
+    pub id_tag:       String,       // id tag the bus starts its transactions with
+    pub vehicle_type: String,
+    pub departure:    Option<NaiveTime>,
+    pub target_kwh:   Option<f32>,  // energy to deliver each night when the bus does not report SOC
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Roster {
+    pub vehicle_types: Vec<VehicleType>,
+    pub vehicles:      Vec<Vehicle>,
+}
+
+// Window before pull-out in which the bus pre-heats/cools from shore power
+#[derive(Debug, Clone, Copy)]
+pub struct PreconditionWindow {
+    pub start:     DateTime<Local>,
+    pub departure: DateTime<Local>,
+    pub reserve_w: f32,
+}
+
+impl Roster {
+    pub fn load(path: &str) -> Result<Roster, Error> {
+        /*
+         * Read the roster of vehicles and vehicle types from a JSON file.
+         * Every vehicle must reference a vehicle type defined in the same file.
+         */
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("Unable to read roster file {path}: {err}")))?;
+        let roster: Roster = serde_json::from_str(&contents)
+            .map_err(|err| Error::Config(format!("Unable to parse roster file {path}: {err}")))?;
+
+        let type_names: HashSet<&str> = roster.vehicle_types.iter().map(|t| t.name.as_str()).collect();
+        for vehicle in &roster.vehicles {
+            if !type_names.contains(vehicle.vehicle_type.as_str()) {
+                return Err(Error::Config(format!("Vehicle {} in {path} references unknown vehicle type {}", vehicle.id_tag, vehicle.vehicle_type)));
+            }
+        }
+        Ok(roster)
+    }
+
+    pub fn vehicle(&self, id_tag: &str) -> Option<&Vehicle> {
+        self.vehicles.iter().find(|vehicle| vehicle.id_tag == id_tag)
+    }
+
+    pub fn vehicle_type(&self, vehicle: &Vehicle) -> Option<&VehicleType> {
+        self.vehicle_types.iter().find(|vehicle_type| vehicle_type.name == vehicle.vehicle_type)
+    }
+
+    pub fn precondition_window(&self, vehicle: &Vehicle, right_now: DateTime<Local>) -> Option<PreconditionWindow> {
+        /*
+         * Find the next pre-conditioning window for the vehicle, if it has a
+         * departure time and its type pre-conditions at all. A window that has
+         * already started but whose departure has not yet passed is returned too.
+         */
+        let departure_time = vehicle.departure?;
+        let vehicle_type = self.vehicle_type(vehicle)?;
+        if vehicle_type.precondition_minutes <= 0 || vehicle_type.precondition_kw <= 0.0 {
+            return None;
+        }
+
+        let mut departure = right_now
+            .with_time(departure_time)
+            .single()?;
+        if departure <= right_now {
+            departure += Duration::days(1);
+        }
+
+        Some(PreconditionWindow {
+            start: departure - Duration::minutes(vehicle_type.precondition_minutes),
+            departure,
+            reserve_w: vehicle_type.precondition_kw * 1000.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn roster() -> Roster {
+        let vehicle = |id_tag: &str, vehicle_type: &str, departure: Option<(u32, u32)>| Vehicle {
+            id_tag:       id_tag.to_owned(),
+            vehicle_type: vehicle_type.to_owned(),
+            departure:    departure.and_then(|(hour, minute)| NaiveTime::from_hms_opt(hour, minute, 0)),
+            target_kwh:   None,
+        };
+        Roster {
+            vehicle_types: vec![
+                VehicleType { name: String::from("hvac"), battery_capacity: 440, precondition_minutes: 30, precondition_kw: 12.0 },
+                VehicleType { name: String::from("none"), battery_capacity: 440, precondition_minutes: 0, precondition_kw: 12.0 },
+            ],
+            vehicles: vec![
+                vehicle("BUS1", "hvac", Some((5, 30))),
+                vehicle("BUS2", "none", Some((5, 30))),
+                vehicle("BUS3", "hvac", None),
+            ],
+        }
+    }
+
+    #[test]
+    fn precondition_windows_end_at_the_next_departure() {
+        let roster = roster();
+        let at = |day: u32, hour: u32, minute: u32| Local.with_ymd_and_hms(2026, 10, day, hour, minute, 0).single().unwrap();
+        let bus = roster.vehicle("BUS1").unwrap();
+
+        //The evening before, the window is the next morning's
+        let window = roster.precondition_window(bus, at(18, 22, 0)).unwrap();
+        assert_eq!((window.start, window.departure, window.reserve_w), (at(19, 5, 0), at(19, 5, 30), 12000.0));
+
+        //Once the window has started it is kept until the bus departs
+        let window = roster.precondition_window(bus, at(19, 5, 10)).unwrap();
+        assert_eq!((window.start, window.departure), (at(19, 5, 0), at(19, 5, 30)));
+
+        //Buses which do not pre-condition, or have no departure, reserve nothing
+        assert!(roster.precondition_window(roster.vehicle("BUS2").unwrap(), at(18, 22, 0)).is_none());
+        assert!(roster.precondition_window(roster.vehicle("BUS3").unwrap(), at(18, 22, 0)).is_none());
+    }
+}
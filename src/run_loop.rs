@@ -1,68 +1,107 @@
 use reqwest::Client;
-use chrono::{DateTime, Duration, Local, Timelike, Utc};
-use std::{collections::HashMap, time};
+use chrono::{DateTime, Duration, Local, LocalResult, TimeZone, Utc};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time};
+use tokio::sync::Notify;
 use crate::{
-    get_data::{get_charge_rate, get_chargers, get_meter_values}, send_data::create_charge_profile, types::{ChargeProfile, ChargingBounds}, util::parse_meterval
+    alerts::{raise_alert, Alert},
+    chargerhub::ChargerHubApi,
+    config::Config,
+    demand_response::{log_compliance, strictest, DrEventStore},
+    fleet_state::{FleetSnapshot, FleetState},
+    freshness::{stale_charge_rate, FreshnessTracker},
+    get_data::{get_charge_rate, get_chargers, get_energy_charge_rate, get_meter_values},
+    planner::{charge_deadline, clamp_plans, fit_to_site_limit, ChargePlan, DeadlineTarget},
+    price_curve::PriceCurve,
+    profile_registry::{OwnedProfile, ProfileRegistry},
+    send_data::{create_charge_profile, RETRY_BUDGET},
+    soc_estimator::SocEstimator, types::{ChargeProfile, ChargingPolicy},
+    verification::{verify_profile, Verification}
 };
 
-pub async fn runner_loop(client: &Client, chargerhub_url: &String, battery_capacity: &i32, desired_soc: &i8, verbose_mode: &bool, auth_key: &String) {
-
-    let charge_clamp_lower = dotenv::var("CHARGE_CLAMP_LOWER")
-        .expect("CHARGE_CLAMP_LOWER was not specified in .env")
-        .parse::<i32>()
-        .expect("Something went wrong reading in the lower bound for charge rates. Please verify CHARGE_CLAMP_LOWER is of type i32");
-
-    let charge_clamp_upper = dotenv::var("CHARGE_CLAMP_UPPER")
-        .expect("CHARGE_CLAMP_UPPER was not specified in .env")
-        .parse::<i32>()
-        .expect("Something went wrong reading in the lower bound for charge rates. Please verify CHARGE_CLAMP_LOWER is of type i32");
-
-    let location_id = dotenv::var("LOCATION_ID")
-        .expect("LOCATION_ID was not specified in .env")
-        .parse::<i32>()
-        .expect("Something went wrong reading in the location ID for relevant chargers. Please verify LOCATION_ID is of type u32");
-
-    let default_charge_rate = dotenv::var("CHARGE_RATE_DEFAULT")
-        .expect("CHARGE_RATE_DEFAULT was not specified in .env")
-        .parse::<f32>()
-        .expect("Something went wrong reading in the default charge rate. Please verify CHARGE_RATE_DEFAULT is of type f32");
-    
-    let curtailment_start_hour = dotenv::var("CURTAILMENT_START_HOUR")
-        .expect("CURTAILMENT_START_HOUR was not specified in .env")
-        .parse::<u32>()
-        .expect("Something went wrong reading in the default charge rate. Please verify CURTAILMENT_START_HOUR is of type u16");
-
-    let curtailment_stop_hour = dotenv::var("CURTAILMENT_STOP_HOUR")
-        .expect("CURTAILMENT_STOP_HOUR was not specified in .env")
-        .parse::<u32>()
-        .expect("Something went wrong reading in the default charge rate. Please verify CURTAILMENT_STOP_HOUR is of type u16");
+pub async fn runner_loop<H: ChargerHubApi>(
+    client: &Client,
+    hub: &Arc<H>,
+    config: &Config,
+    dr_events: &DrEventStore,
+    prices: &PriceCurve,
+    fleet: &FleetState,
+    wake: &Notify)
+{
 
+    let battery_capacity = &config.battery_capacity;
+    let verbose_mode = &config.verbose_mode;
+    let default_charge_rate = config.default_charge_rate;
 
 
     const TIME_BETWEEN_LOOPS: u64 = 5 * 60; // number of minutes to wait between loops
-                                            
+
     let time_between_recalculations = Duration::new(15 * 60, 0).expect("Static duration failed to initialize"); // number of minutes to wait between recalculating
                                                                                                                                              // charge charge rates
-                                                
+
     let mut prev_profiles = HashMap::new();
-                                               
+
     let mut last_recalculation = Local::now() - Duration::minutes(TIME_BETWEEN_LOOPS as i64); // last time new charge profiles were calculated
-                                                                
-    let mut start_time =  set_start_time(curtailment_start_hour);
 
-    // Set time to stop curtailment to 5am. If before midnight, add day to chrono datetime
-    let mut stop_time = set_stop_time(last_recalculation, curtailment_stop_hour);
+    // name of the policy the last recalculation was made under, so switching
+    // from the layover window to the overnight window recalculates right away
+    let mut last_policy: Option<String> = None;
+
+    // whether the last recalculation only capped rates to a demand response event outside
+    // of every window, so the window starting under the same policy still re-plans
+    let mut last_dr_only = false;
+
+    // ids of the demand response events in effect during the last loop, so the
+    // start and end of an event re-plan right away
+    let mut last_dr_events: Vec<String> = Vec::new();
+
+    // version of the price curve the last recalculation planned around
+    let mut last_prices = prices.version();
+
+    // building load from the site main meter, both the latest reading and the
+    // one the last recalculation planned around, plus the chargers' measured
+    // draw for meters which read the whole service
+    let mut building_load_w: Option<f32> = None;
+    let mut planned_building_load_w: Option<f32> = None;
+    let mut charger_load_w = 0.0;
+
+    // SOC of each session between sparse SOC readings
+    let mut soc_estimator = SocEstimator::new(config.soc_efficiency, config.soc_drift_per_hour, config.soc_max_uncertainty);
+
+    // sessions which have already raised an alert for missing their target
+    let mut alerted_sessions: HashSet<i32> = HashSet::new();
+
+    // age of each charger's meter values, stale sessions are planned conservatively
+    let mut freshness = FreshnessTracker::new(config.meter_value_max_age);
+
+    // sessions (transaction id -> "charger - connector") the last recalculation
+    // planned for, so buses connecting and disconnecting re-plan right away
+    let mut planned_sessions: HashMap<i32, String> = HashMap::new();
+
+    // TxProfiles this service has installed, which are updated each recalculation
+    // and cleared to hand the connectors back to full rate when the window ends
+    let mut profile_registry = ProfileRegistry::new(config.stack_levels.clone());
+
+    // start of the chargerhub outage the service is degraded for, if any
+    let mut degraded_since: Option<DateTime<Utc>> = None;
 
     let mut right_now = Local::now();
 
     if *verbose_mode {
-        println!("time between loops: {},\ntime between recalculations: {},\ncurtailment start time: {},\ncurtailment stop time: {},\ncurrent server time: {}", 
-            &TIME_BETWEEN_LOOPS, 
-            &time_between_recalculations, 
-            &start_time, 
-            &stop_time, 
+        println!("time between loops: {},\ntime between recalculations: {},\ncurrent server time: {}",
+            &TIME_BETWEEN_LOOPS,
+            &time_between_recalculations,
             &right_now
         );
+        for policy in config.policies() {
+            println!("{} curtailment window: {}:00 - {}:00, target SOC {}%, clamp {}W - {}W",
+                policy.name,
+                policy.start_hour,
+                policy.stop_hour,
+                policy.desired_soc,
+                policy.bounds.lower_bnd,
+                policy.bounds.upper_bnd
+            );
+        }
     }
 
     /*
@@ -76,140 +115,658 @@ pub async fn runner_loop(client: &Client, chargerhub_url: &String, battery_capac
         //Conditions to recalculate charges includes if the current time is after bus routes end for the day, and a bus being connected/disconnected from the pool.
         //Additionally, charge profiles should be recalculated every N minutes to ensure charging is completed by the desired time
 
-        //Check that right now is within a day of the start time, otherwise reset the start/end times
-        let start_delta = start_time - right_now;
-        if start_delta.num_hours() >= 24 {
-            last_recalculation = Local::now();
-            start_time = set_start_time(curtailment_start_hour);
-            stop_time = set_stop_time(last_recalculation, curtailment_stop_hour);
+        //Find the policy (overnight or daytime layover) whose window we are currently in
+        let active_policy = config
+            .policies()
+            .into_iter()
+            .find_map(|policy| active_window(policy, right_now).map(|(_, stop_time)| (policy, stop_time)));
+
+        //Pick up demand response events added to the events file since the last loop
+        dr_events.reload();
+        let active_dr_events = dr_events.active(right_now.with_timezone(&Utc));
+        let dr_changed = active_dr_events.iter().map(|event| &event.id).ne(last_dr_events.iter());
+        let prices_changed = prices.version() != last_prices;
+
+        //Demand response events are enforced even outside of the curtailment windows, using the
+        //overnight bounds with profiles that expire when the last event does. There is no charging
+        //deadline outside of a window, so rates are only capped to the event's limit
+        let dr_only = active_policy.is_none();
+        let active_policy = active_policy.or_else(|| {
+            active_dr_events.iter().map(|event| event.end).max().map(|end| (&config.overnight, end.with_timezone(&Local)))
+        });
+
+
+        //Read the depot's sessions every loop during a window, re-planning right away when a bus connects
+        //or disconnects so a new bus is planned for and the power freed by a departing bus goes to the others.
+        //Sessions come from the state chargerhub pushes through the webhooks, polled in full now and then to
+        //pick up anything a missed push left out (every loop when the webhooks are not being served)
+        let mut fetch = None;
+        if active_policy.is_some() {
+            let polled = fleet.reconcile_due(config.reconcile_interval) && reconcile_fleet(hub, config, fleet).await;
+            //Without the webhooks nothing but the poll updates the state, so only plan from a successful one
+            if polled || (!config.reconcile_interval.is_zero() && fleet.reconciled()) {
+                fetch = Some(fleet.snapshot(&config.roster));
+            }
         }
+        let sessions_changed = fetch.as_ref().filter(|_| last_policy.is_some()).is_some_and(|fetch| sessions_changed(&planned_sessions, fetch));
 
-        
-        //TODO: Add check here for if new busses were connected/disconnected
         //We could also add rules here for charge behavior based on time of night
         //(IE, if check occurred during non-peak then increase charge rate)
 
-        if time_delta >= time_between_recalculations && right_now >= start_time {
+        let policy_changed = active_policy.map(|(policy, _)| &policy.name) != last_policy.as_ref() || (last_policy.is_some() && dr_only != last_dr_only);
+
+        //Read the building's load off of the site main meter, re-planning right away if it
+        //rose enough since the last recalculation that the chargers could overload the service
+        let mut load_spiked = false;
+        if let Some(site_meter) = &config.site_meter {
+            match site_meter.reader.read_w().await {
+                Ok(reading_w) => {
+                    let load_w = site_meter.building_load_w(reading_w, charger_load_w);
+                    if let Some(planned_w) = planned_building_load_w {
+                        load_spiked = load_w - planned_w >= site_meter.spike_w;
+                        if load_spiked {
+                            println!("Building load rose from {:.0}W to {:.0}W, recalculating charge profiles", planned_w, load_w);
+                        }
+                    }
+                    building_load_w = Some(load_w);
+                }
+                Err(err) => eprintln!("{err}, keeping the last building load reading"),
+            }
+        }
+
+        //While chargerhub is down nothing can be read from or sent to it. The profiles it last accepted stay in
+        //place (they expire with their window on their own) and an alert is raised once per outage
+        let degraded = hub.degraded();
+        let recovered = degraded.is_none() && degraded_since.is_some();
+        match (&degraded, degraded_since) {
+            (Some(outage), None) => {
+                let alert = Alert::ChargerHubUnavailable {
+                    since:                outage.since,
+                    consecutive_failures: outage.consecutive_failures,
+                    last_error:           outage.last_error.clone(),
+                };
+                raise_alert(client, config.alert_webhook_url.as_deref(), &alert).await;
+                degraded_since = Some(outage.since);
+            }
+            (None, Some(since)) => {
+                println!("chargerhub recovered after being unavailable since {since}, recalculating charge profiles");
+                degraded_since = None;
+            }
+            _ => {}
+        }
+
+        let recalculate = time_delta >= time_between_recalculations || policy_changed || dr_changed || load_spiked || sessions_changed || recovered || prices_changed;
+        if let (Some((policy, stop_time)), Some(fetch)) = (active_policy.filter(|_| recalculate && degraded.is_none()), fetch) {
+
 
 
-                                        
             last_recalculation = Local::now();
+            last_policy = Some(policy.name.clone());
+            last_dr_only = dr_only;
+            if prices_changed {
+                println!("Electricity prices changed, recalculating charge profiles");
+                last_prices = prices.version();
+            }
 
-            //Obtain all chargers at the bus depo site. 
-            let chargers = get_chargers(client, chargerhub_url, location_id, verbose_mode, auth_key)
-                .await
-                .expect("Unable to grab chargers from charge site");
+            if *verbose_mode {
+                match dr_only {
+                    true => println!("Capping charge rates to the demand response event until {}", stop_time),
+                    false => println!("Recalculating charge profiles under the {} policy until {}", policy.name, stop_time),
+                }
+            }
 
-            //Grab meter values for each charger
-            let meter_values = get_meter_values(client, chargerhub_url, chargers, verbose_mode, auth_key)
-                .await
-                .expect("Failed to obtain meter values from charger hub");
-            //Create charge profiles
-            for value in meter_values {
-                //parse the SOC out of the meter values and get % charge needed to get to desired SOC
-                let current_soc = parse_meterval(&value).await;
+            //Connectors which could not be read are left out of this cycle
+            planned_sessions = fetch
+                .sessions
+                .iter()
+                .map(|session| (session.transaction_id, format!("{} - {}", session.charger_id, session.connector_id)))
+                .collect();
+            for unavailable in &fetch.unavailable {
+                eprintln!("{} - {} is unavailable this cycle: {}", unavailable.charger_id, unavailable.connector_id, unavailable.reason);
+            }
+            let all_connectors_read = fetch.unavailable.is_empty();
+            //Decide a charge rate for every connector before sending anything, so the
+            //rates can be fit under the site limit as a whole
+            let mut plans: Vec<ChargePlan> = Vec::new();
+            let mut measured_w = 0.0;
+            let mut active_transactions: Vec<i32> = Vec::new();
+            let mut session_details: HashMap<i32, (String, i32)> = HashMap::new(); // id tag and battery capacity by transaction
+            for session in fetch.sessions {
+                let value = &session.meter_value;
+                if *verbose_mode {
+                    println!("{} - {}: {} since {}, {}", session.charger_id, session.connector_id, session.id_tag, session.started_at, value.summary());
+                }
 
-                if current_soc != -1 {
+                //Meter values older than the freshness threshold no longer describe the session, so they are
+                //planned at a conservative rate and kept out of the measured load and the SOC estimate
+                let stale = freshness.check(&session.charger_id, value.time_stamp, right_now.with_timezone(&Utc));
+                if stale.is_none() {
+                    measured_w += value.active_power_w().unwrap_or(0.0);
+                }
 
-                    let soc_needed = if current_soc >= *desired_soc {
+                //Look the bus up in the roster for its battery size and pre-conditioning window
+                let vehicle = session.vehicle.as_ref();
+                let capacity = session.battery_capacity(&config.roster, *battery_capacity);
+                let precondition = vehicle.and_then(|vehicle| config.roster.precondition_window(vehicle, right_now));
+
+                //parse the SOC out of the meter values and get % charge needed to get to desired SOC,
+                //estimating it from the energy delivered since the last reading when it is missing
+                let soc = match stale {
+                    Some(_) => None,
+                    None => soc_estimator.update(
+                        session.transaction_id,
+                        session.latest_soc,
+                        value.energy_register_wh(),
+                        capacity,
+                        value.time_stamp),
+                };
+                active_transactions.push(session.transaction_id);
+
+                let deadline = charge_deadline(stop_time, precondition);
+                let time_to_charge = deadline - right_now;
+                let (charge_rate, calculated, energy_needed_wh) = if dr_only {
+                    (policy.bounds.upper_bnd as f32, false, None)
+                }
+                else if stale.is_some() {
+                    (stale_charge_rate(config.stale_charge_rate, &policy.bounds), false, None)
+                }
+                else if let Some(soc) = soc {
+                    let current_soc = soc.planning_soc();
+
+                    let soc_needed = if current_soc >= policy.desired_soc {
                         0
                     }
                     else {
-                        desired_soc - current_soc
+                        policy.desired_soc - current_soc
                     };
 
 
-                    //Calculate the power needed for each bus and create a charge profile based on the needed power
-                    let time_to_charge = stop_time - right_now;
-                    let mut charge_rate = get_charge_rate(time_to_charge, soc_needed, battery_capacity, verbose_mode).await;
-
-                    //submit charge profiles to chargerhub which should handle the communication with the charger
-                    let charge_profile = create_charge_profile(
-                        client, 
-                        chargerhub_url, 
-                        &value.connector_id, 
-                        &value.charger_id, 
-                        &mut charge_rate, 
-                        stop_time.with_timezone(&Utc),
-                        verbose_mode, 
-                        ChargingBounds{lower_bnd: charge_clamp_lower, upper_bnd: charge_clamp_upper},
-                        auth_key
-                        ).await;
-                    prev_profiles.entry(format!("{} - {}", &value.charger_id, &value.connector_id))
-                        .or_insert_with(Vec::new)
-                        .push(charge_profile);
-
+                    //Calculate the power needed for each bus, finishing before it starts pre-conditioning
+                    let energy_needed_wh = soc_needed as f32 / 100.0 * capacity as f32 * 1000.0;
+                    (get_charge_rate(time_to_charge, soc_needed, &capacity, verbose_mode).await, true, Some(energy_needed_wh))
+                }
+                else if let Some(energy_needed_wh) = session.energy_needed_wh(policy) {
+                    //Buses which never report SOC are charged towards an energy target instead
+                    (get_energy_charge_rate(time_to_charge, energy_needed_wh, verbose_mode).await, true, Some(energy_needed_wh))
                 }
                 else {
                     let empty_vec: Vec<ChargeProfile> = Vec::new(); // Define a static empty vector
-                    let profile_list = prev_profiles.get(&format!("{} - {}", &value.charger_id, &value.connector_id)).unwrap_or(&empty_vec);
-                    if !profile_list.is_empty() {
-                        let most_recent_profile = profile_list
-                            .last()
-                            .expect("Unable to unwrap charge profile from previous profiles hashmap");
-                        create_charge_profile(
-                        client,
-                        chargerhub_url, 
-                        &value.connector_id, 
-                        &value.charger_id, 
-                        &mut most_recent_profile.charge_rates[0].to_owned(), 
-                        stop_time.with_timezone(&Utc),
-                        verbose_mode, 
-                        ChargingBounds{lower_bnd: charge_clamp_lower, upper_bnd: charge_clamp_upper},
-                        auth_key).await;
+                    let profile_list = prev_profiles.get(&format!("{} - {}", &session.charger_id, &session.connector_id)).unwrap_or(&empty_vec);
+                    if let Some(most_recent_profile) = profile_list.last() {
+                        (most_recent_profile.charge_rates[0], false, None)
+                    }
+
+                    else {
+                        (default_charge_rate, false, None)
                     }
+                };
+
+                //Buses which cannot reach their target by the deadline even at the upper bound are
+                //sent full power and an alert is raised (once per session) with how short they will be
+                let max_w = policy.bounds.upper_bnd as f32;
+                let hours_left = (time_to_charge.num_seconds().max(0) as f32) / 3600.0;
+                let shortfall_wh = energy_needed_wh
+                    .map(|needed_wh| needed_wh - max_w * hours_left)
+                    .filter(|shortfall_wh| *shortfall_wh > 0.0);
+                let infeasible = shortfall_wh.is_some();
+                let charge_rate = if infeasible { max_w } else { charge_rate };
+
+                if let Some(shortfall_wh) = shortfall_wh.filter(|_| !alerted_sessions.contains(&session.transaction_id)) {
+                    let shortfall_soc = shortfall_wh / (capacity as f32 * 1000.0) * 100.0;
+                    let alert = Alert::InfeasibleDeadline {
+                        charger_id:     session.charger_id.clone(),
+                        connector_id:   session.connector_id,
+                        transaction_id: session.transaction_id,
+                        id_tag:         session.id_tag.clone(),
+                        deadline:       deadline.with_timezone(&Utc),
+                        required_w:     energy_needed_wh.filter(|_| hours_left > 0.0).map(|needed_wh| needed_wh / hours_left),
+                        max_w,
+                        shortfall_kwh:  shortfall_wh / 1000.0,
+                        shortfall_soc,
+                        expected_soc:   soc.map(|_| policy.desired_soc as f32 - shortfall_soc),
+                    };
+                    raise_alert(client, config.alert_webhook_url.as_deref(), &alert).await;
+                    alerted_sessions.insert(session.transaction_id);
+                }
+
+                //Buses already pre-conditioning only get the minimum rate, the HVAC load takes priority
+                let charge_rate = match precondition {
+                    Some(window) if window.start <= right_now => policy.bounds.lower_bnd as f32,
+                    _ => charge_rate,
+                };
+
+                //Charging is moved out of the most expensive priced periods before the deadline,
+                //as long as the bus can still reach its target in the cheaper ones
+                let (charge_rate, expensive) = match energy_needed_wh {
+                    Some(energy_needed_wh) if !infeasible && precondition.is_none_or(|window| window.start > right_now) => prices
+                        .expensive_periods(right_now, deadline, energy_needed_wh, max_w)
+                        .unwrap_or((charge_rate, Vec::new())),
+                    _ => (charge_rate, Vec::new()),
+                };
+
+                let phase = match &config.phase_balancing {
+                    Some(phase_balancing) => phase_balancing.phase_for(&session.charger_id, value.loaded_phase()),
+                    None => None,
+                };
+                //What the charger is actually drawing on each phase, unless the reading is too old to go by
+                let measured_a = match stale {
+                    None => value.current_a().into_iter().filter_map(|(phase, current_a)| Some((phase?, current_a))).collect(),
+                    Some(_) => Vec::new(),
+                };
+
+                plans.push(ChargePlan {
+                    charger_id: session.charger_id.clone(),
+                    connector_id: session.connector_id,
+                    transaction_id: session.transaction_id,
+                    charge_rate,
+                    precondition,
+                    calculated,
+                    phase,
+                    measured_a,
+                    soc,
+                    infeasible,
+                    stale,
+                    expensive,
+                    target: energy_needed_wh
+                        .filter(|_| !infeasible)
+                        .map(|energy_needed_wh| DeadlineTarget { energy_needed_wh, deadline }),
+                });
+                session_details.insert(session.transaction_id, (session.id_tag.clone(), capacity));
+            }
+            if *verbose_mode {
+                println!("Meter value freshness:");
+                freshness.report(right_now.with_timezone(&Utc));
+            }
+
+            //Sessions missing from the fetch have only ended if every connector could be read
+            if all_connectors_read {
+                soc_estimator.retain_sessions(&active_transactions);
+                alerted_sessions.retain(|transaction_id| active_transactions.contains(transaction_id));
+                profile_registry.retain_transactions(&active_transactions);
+            }
+
+            charger_load_w = measured_w;
+            planned_building_load_w = building_load_w;
+
+            //Only what the building leaves of the utility service (less a margin) can go to charging
+            let headroom_w = config
+                .site_meter
+                .as_ref()
+                .zip(building_load_w)
+                .map(|(site_meter, load_w)| site_meter.headroom_w(load_w));
+            let normal_limit_w = match (config.site_limit_w, headroom_w) {
+                (Some(site_limit_w), Some(headroom_w)) => Some(site_limit_w.min(headroom_w)),
+                (limit, None) | (None, limit) => limit,
+            };
+            if *verbose_mode {
+                if let Some(headroom_w) = headroom_w {
+                    println!("building load {:.0}W leaves {:.0}W of headroom for charging", building_load_w.unwrap_or_default(), headroom_w);
+                }
+            }
+
+            //During a demand response event the depot is held to the event's limit, percentage
+            //reductions are taken off the site limit or, without one, off of what we planned to draw.
+            //When events overlap the one with the lowest limit against that baseline is followed
+            clamp_plans(&mut plans, policy.bounds);
+            let baseline_w = normal_limit_w.unwrap_or_else(|| plans.iter().map(|plan| plan.charge_rate).sum());
+            let dr_event = strictest(&active_dr_events, baseline_w);
+            let site_limit_w = match dr_event {
+                Some(event) => Some(event.limit_w(baseline_w).min(normal_limit_w.unwrap_or(f32::MAX))),
+                None => normal_limit_w,
+            };
+
+            //Keep the depot under its aggregated limit, leaving headroom for pre-conditioning HVAC load
+            if let Some(site_limit_w) = site_limit_w {
+                fit_to_site_limit(&mut plans, site_limit_w, right_now, stop_time, policy.bounds, verbose_mode);
+            }
+
+            //Keep every transformer, panel and charger in the depot's electrical tree under its rating
+            if let Some(topology) = &config.topology {
+                topology.fit(&mut plans, right_now, stop_time, policy.bounds.lower_bnd as f32, verbose_mode);
+            }
+
+            //Keep single phase AC chargers within each phase's current limit and the allowed imbalance
+            if let Some(phase_balancing) = &config.phase_balancing {
+                phase_balancing.fit(&mut plans, policy.bounds.lower_bnd as f32, verbose_mode);
+            }
 
-                    else { 
-                        create_charge_profile(
-                            client,
-                            chargerhub_url, 
-                            &value.connector_id, 
-                            &value.charger_id, 
-                            &mut default_charge_rate.to_owned(),
+            //The shared limits can leave a bus short of its target after all. Buses moved out of the
+            //expensive periods charge through them instead, and an alert is raised if that is still not enough
+            for plan in plans.iter_mut() {
+                if !plan.expensive.is_empty() && plan.shortfall_wh(right_now, policy.bounds).is_some() {
+                    plan.expensive.clear();
+                }
+                let (Some(shortfall_wh), Some(target)) = (plan.shortfall_wh(right_now, policy.bounds), plan.target) else { continue };
+                let Some((id_tag, capacity)) = session_details.get(&plan.transaction_id) else { continue };
+                if alerted_sessions.contains(&plan.transaction_id) {
+                    continue;
+                }
+                let hours_left = ((target.deadline - right_now).num_seconds().max(0) as f32) / 3600.0;
+                let shortfall_soc = shortfall_wh / (*capacity as f32 * 1000.0) * 100.0;
+                let alert = Alert::InfeasibleDeadline {
+                    charger_id:     plan.charger_id.clone(),
+                    connector_id:   plan.connector_id,
+                    transaction_id: plan.transaction_id,
+                    id_tag:         id_tag.clone(),
+                    deadline:       target.deadline.with_timezone(&Utc),
+                    required_w:     Some(target.energy_needed_wh).filter(|_| hours_left > 0.0).map(|needed_wh| needed_wh / hours_left),
+                    max_w:          plan.charge_rate,
+                    shortfall_kwh:  shortfall_wh / 1000.0,
+                    shortfall_soc,
+                    expected_soc:   plan.soc.map(|_| policy.desired_soc as f32 - shortfall_soc),
+                };
+                raise_alert(client, config.alert_webhook_url.as_deref(), &alert).await;
+                alerted_sessions.insert(plan.transaction_id);
+                plan.infeasible = true;
+            }
+
+            if let (Some(event), Some(site_limit_w)) = (dr_event, site_limit_w) {
+                let planned_w = plans.iter().map(|plan| plan.charge_rate).sum();
+                log_compliance(event, site_limit_w, planned_w, measured_w);
+            }
+
+            //submit charge profiles to chargerhub which should handle the communication with the charger
+            println!("Charge profiles for {} session(s) under the {} policy:", plans.len(), policy.name);
+            let retry_until = time::Instant::now() + RETRY_BUDGET;
+            for plan in plans {
+                println!("  {} - {}: SOC {}, {:.0}W{}{}{}",
+                    plan.charger_id,
+                    plan.connector_id,
+                    plan.soc.map(|soc| soc.to_string()).unwrap_or_else(|| String::from("unknown")),
+                    plan.charge_rate,
+                    if plan.infeasible { " (cannot reach target by deadline)" } else { "" },
+                    if plan.expensive.is_empty() { String::new() } else { format!(" (lower bound through {} expensive period(s))", plan.expensive.len()) },
+                    plan.stale.map(|age| format!(" (stale, last reported {}s ago)", age.num_seconds())).unwrap_or_default()
+                );
+
+                let Some(owned_profile) = profile_registry.profile_for(&plan.charger_id, plan.connector_id, plan.transaction_id) else {
+                    eprintln!("Every reserved stack level on {} - {} is in use, not sending a profile for transaction {}",
+                        plan.charger_id, plan.connector_id, plan.transaction_id);
+                    continue;
+                };
+
+                let (start_periods, charge_rates) = plan.schedule(right_now, stop_time, policy.bounds);
+                //Read the composite schedule back after sending, resending the profile if the charger is not
+                //following it and raising an alert once the retries run out
+                let mut attempts = 0;
+                let accepted_profile = loop {
+                    let result = create_charge_profile(
+                        hub.as_ref(),
+                        &owned_profile,
+                        start_periods.clone(),
+                        charge_rates.clone(),
                         stop_time.with_timezone(&Utc),
-                        verbose_mode, 
-                        ChargingBounds{lower_bnd: charge_clamp_lower, upper_bnd: charge_clamp_upper},
-                        auth_key).await;
+                        policy.bounds,
+                        retry_until
+                        ).await;
+                    attempts += 1;
+
+                    //Profiles which were not accepted are never recorded as in effect
+                    profile_registry.record_outcome(&owned_profile, result.is_ok());
+                    let charge_profile = match result {
+                        Ok(charge_profile) => charge_profile,
+                        Err(err) => {
+                            eprintln!("Profile for {} - {} (transaction {}) did not take effect: {}",
+                                plan.charger_id, plan.connector_id, plan.transaction_id, err);
+                            break None;
+                        }
+                    };
+
+                    let Some(verification) = &config.schedule_verification else { break Some(charge_profile) };
+                    let duration = (stop_time - right_now).num_seconds().max(1) as i32;
+                    match verify_profile(hub.as_ref(), &owned_profile, &charge_profile, duration, verification.tolerance_w).await {
+                        Verification::Matches => break Some(charge_profile),
+                        Verification::Unavailable(reason) => {
+                            eprintln!("Unable to verify the profile on {} - {}: {}", plan.charger_id, plan.connector_id, reason);
+                            break Some(charge_profile);
+                        }
+                        Verification::Mismatch { start_period, expected_w, applied_w } if attempts <= verification.retries => {
+                            eprintln!("{} - {} is applying {} instead of {:.0}W at +{}s, resending its profile",
+                                plan.charger_id,
+                                plan.connector_id,
+                                applied_w.map(|applied_w| format!("{applied_w:.0}W")).unwrap_or_else(|| String::from("no limit")),
+                                expected_w,
+                                start_period
+                            );
+                        }
+                        Verification::Mismatch { start_period, expected_w, applied_w } => {
+                            let alert = Alert::ScheduleMismatch {
+                                charger_id:     plan.charger_id.clone(),
+                                connector_id:   plan.connector_id,
+                                transaction_id: plan.transaction_id,
+                                start_period,
+                                expected_w,
+                                applied_w,
+                                attempts,
+                            };
+                            raise_alert(client, config.alert_webhook_url.as_deref(), &alert).await;
+                            break Some(charge_profile);
+                        }
                     }
+                };
+                if let Some(charge_profile) = accepted_profile.filter(|_| plan.calculated) {
+                    prev_profiles.entry(format!("{} - {}", &plan.charger_id, &plan.connector_id))
+                        .or_insert_with(Vec::new)
+                        .push(charge_profile);
                 }
             }
         }
+        else if active_policy.is_none() {
+            //The window just ended, hand every connector we curtailed back to full rate
+            if let Some(policy) = last_policy.take().and_then(|name| config.policies().into_iter().find(|policy| policy.name == name)) {
+                end_of_window_handoff(hub, config, policy, fleet, &mut profile_registry, &mut soc_estimator).await;
+                prev_profiles.clear();
+                planned_sessions.clear();
+            }
+            println!("Outside of every curtailment window.\nchecking again at {}", right_now + Duration::seconds(TIME_BETWEEN_LOOPS as i64));
+        }
+        else if let Some(outage) = &degraded {
+            println!("chargerhub unavailable since {}, keeping the last accepted charge profiles in place", outage.since);
+        }
         else {
             println!("Conditions not met to recalculate new charge profiles.\nchecking again at {}", right_now + time_between_recalculations);
         }
-        //Sleep for reasonable amount of time
-        std::thread::sleep(time::Duration::from_secs(TIME_BETWEEN_LOOPS));
+
+        if dr_changed {
+            for ended in last_dr_events.iter().filter(|id| !active_dr_events.iter().any(|event| event.id == **id)) {
+                println!("Demand response event {} ended", ended);
+            }
+            for started in active_dr_events.iter().filter(|event| !last_dr_events.contains(&event.id)) {
+                println!("Demand response event {} in effect until {}", started.id, started.end);
+            }
+            if active_dr_events.is_empty() {
+                println!("No demand response events in effect, normal planning restored");
+            }
+            last_dr_events = active_dr_events.into_iter().map(|event| event.id).collect();
+        }
+
+        //Sleep for reasonable amount of time, waking early for the end of the window, the next session poll, the
+        //next demand response boundary, the next site meter reading or when an event is pushed through the API
+        let mut sleep_for = time::Duration::from_secs(TIME_BETWEEN_LOOPS);
+        if let Some((_, stop_time)) = active_policy {
+            sleep_for = sleep_for.min((stop_time - Local::now()).to_std().unwrap_or_default());
+            sleep_for = sleep_for.min(config.session_poll_interval);
+        }
+        if let Some(boundary) = dr_events.next_boundary(Utc::now()) {
+            sleep_for = sleep_for.min((boundary - Utc::now()).to_std().unwrap_or_default());
+        }
+        if let Some(site_meter) = &config.site_meter {
+            sleep_for = sleep_for.min(site_meter.poll_interval);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_for) => {}
+            _ = wake.notified() => {}
+        }
     }
 
 }
 
 
-fn set_start_time(curtailment_start_hour: u32) -> DateTime<Local>{
-    let start_time = Local::now() // only perform curtailment if after start time
-        .with_hour(curtailment_start_hour)
-        .unwrap()
-        .with_minute(0)
-        .unwrap()
-        .with_second(0)
-        .unwrap();
-    start_time
+async fn end_of_window_handoff<H: ChargerHubApi>(
+    hub: &Arc<H>,
+    config: &Config,
+    policy: &ChargingPolicy,
+    fleet: &FleetState,
+    profile_registry: &mut ProfileRegistry,
+    soc_estimator: &mut SocEstimator)
+{
+    /*
+     * Clear every profile this service owns and log each bus' final SOC against
+     * the window's target. Profiles which fail to clear stay in the registry so
+     * they are reused rather than stacked on top of.
+     */
+    let owned_profiles = profile_registry.owned().to_vec();
+    println!("{} curtailment window ended, handing {} connector(s) back to full rate", policy.name, owned_profiles.len());
+
+    if !reconcile_fleet(hub, config, fleet).await {
+        eprintln!("Unable to read final meter values, reporting from the last known state");
+    }
+    let sessions = fleet.snapshot(&config.roster).sessions;
+
+    for owned_profile in owned_profiles {
+        let OwnedProfile { charger_id, connector_id, transaction_id, .. } = &owned_profile;
+        match hub.clear_charge_profile(&owned_profile).await {
+            Ok(()) => profile_registry.forget(&owned_profile),
+            Err(err) => eprintln!("Unable to clear the charge profile on {} - {}: {}", charger_id, connector_id, err),
+        }
+
+        let final_soc = sessions
+            .iter()
+            .find(|session| session.transaction_id == *transaction_id)
+            .and_then(|session| soc_estimator.update(
+                session.transaction_id,
+                session.latest_soc,
+                session.meter_value.energy_register_wh(),
+                session.battery_capacity(&config.roster, config.battery_capacity),
+                session.meter_value.time_stamp));
+
+        match final_soc {
+            Some(soc) if soc.soc < policy.desired_soc as f32 => println!("  {} - {}: final SOC {}, {:.1}% short of the {}% target",
+                charger_id, connector_id, soc, policy.desired_soc as f32 - soc.soc, policy.desired_soc),
+            Some(soc) => println!("  {} - {}: final SOC {}, reached the {}% target", charger_id, connector_id, soc, policy.desired_soc),
+            None => println!("  {} - {}: final SOC unknown, target was {}%", charger_id, connector_id, policy.desired_soc),
+        }
+    }
 }
 
-fn set_stop_time(last_recalculation: DateTime<Local>, curtailment_stop_hour: u32) -> DateTime<Local> {
-    // Set time to stop curtailment to stop time specified in env. If before midnight, add day to chrono datetime
-    let mut stop_time = Local::now()
-        .with_hour(curtailment_stop_hour)
-        .unwrap()
-        .with_minute(0)
-        .unwrap()
-        .with_second(0)
-        .unwrap();
-
-    stop_time = if last_recalculation > stop_time { // move stop time forward by a day if
-       stop_time + Duration::days(1)                // calculation made before midnight
-    } else {
-       stop_time
-    };
- 
-    stop_time
+async fn reconcile_fleet<H: ChargerHubApi>(hub: &Arc<H>, config: &Config, fleet: &FleetState) -> bool {
+    /*
+     * Poll every connector at the location and reconcile the fleet state with
+     * what chargerhub holds. False if the location's chargers could not be listed.
+     */
+    match get_chargers(hub.as_ref(), config.location_id, &config.verbose_mode).await {
+        Ok(chargers) => {
+            let charger_ids = chargers.iter().map(|charger| charger.id.clone()).collect();
+            fleet.reconcile(charger_ids, get_meter_values(hub, chargers, config.fetch_concurrency, &config.verbose_mode).await);
+            true
+        }
+        Err(err) => {
+            eprintln!("Unable to grab chargers from charge site: {err}");
+            false
+        }
+    }
+}
+
+fn sessions_changed(planned_sessions: &HashMap<i32, String>, fetch: &FleetSnapshot) -> bool {
+    /*
+     * Whether a bus connected or disconnected since the last recalculation, given
+     * the sessions (transaction id -> "charger - connector") it planned for.
+     */
+    let mut changed = false;
+    for session in fetch.sessions.iter().filter(|session| !planned_sessions.contains_key(&session.transaction_id)) {
+        println!("{} connected to {} - {}, recalculating charge profiles", session.id_tag, session.charger_id, session.connector_id);
+        changed = true;
+    }
+    //A session missing from the poll has only ended if every connector could be read
+    if fetch.unavailable.is_empty() {
+        for (transaction_id, connector) in planned_sessions {
+            if !fetch.sessions.iter().any(|session| session.transaction_id == *transaction_id) {
+                println!("Transaction {} on {} ended, recalculating charge profiles", transaction_id, connector);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+
+fn active_window(policy: &ChargingPolicy, right_now: DateTime<Local>) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    /*
+     * Return the start and stop time of the policy's window if right_now falls
+     * inside of it. Windows whose stop hour is before their start hour (IE 22 - 3)
+     * wrap past midnight, in which case the window may have started yesterday or
+     * may end tomorrow.
+     */
+    let start_today = at_hour(right_now, policy.start_hour)?;
+    let stop_today = at_hour(right_now, policy.stop_hour)?;
+
+    if policy.start_hour < policy.stop_hour {
+        if right_now >= start_today && right_now < stop_today {
+            return Some((start_today, stop_today));
+        }
+    }
+    else if right_now >= start_today {
+        return Some((start_today, stop_today + Duration::days(1)));
+    }
+    else if right_now < stop_today {
+        return Some((start_today - Duration::days(1), stop_today));
+    }
+    None
+}
+
+fn at_hour(right_now: DateTime<Local>, hour: u32) -> Option<DateTime<Local>> {
+    /*
+     * The start of the given hour on right_now's day. When the clocks go back
+     * and the hour happens twice the first one is used, when they go forward
+     * and the hour is skipped the window starts once the clocks have changed.
+     */
+    let naive = right_now.date_naive().and_hms_opt(hour, 0, 0)?;
+    match Local.from_local_datetime(&naive) {
+        LocalResult::Single(time) => Some(time),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        LocalResult::None => Local.from_local_datetime(&(naive + Duration::hours(1))).earliest(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::Error, get_data::UnavailableConnector, roster::Roster, session::Session};
+
+    fn session(charger_id: &str, transaction_id: i32) -> Session {
+        let meter_value = serde_json::from_value(serde_json::json!({
+            "connector_id": 1,
+            "charger_id": charger_id,
+            "transaction_id": transaction_id,
+            "time_stamp": "2026-10-18T22:00:00Z",
+            "sampled_value": [],
+        })).unwrap();
+        let transaction = serde_json::from_value(serde_json::json!({
+            "connector_id": 1,
+            "id_tag": "BUS1",
+            "meter_start": 0,
+            "timestamp_start": "2026-10-18T20:00:00Z",
+            "transaction_id": transaction_id,
+        })).unwrap();
+        Session::new(meter_value, transaction, &Roster::default())
+    }
+
+    #[test]
+    fn buses_connecting_or_disconnecting_trigger_a_recalculation() {
+        let planned = HashMap::from([(7, String::from("CH1 - 1"))]);
+        let snapshot = |sessions: Vec<Session>, unavailable: Vec<UnavailableConnector>| FleetSnapshot { sessions, unavailable };
+
+        assert!(!sessions_changed(&planned, &snapshot(vec![session("CH1", 7)], Vec::new())));
+        assert!(sessions_changed(&planned, &snapshot(vec![session("CH1", 7), session("CH2", 8)], Vec::new())));
+        assert!(sessions_changed(&planned, &snapshot(Vec::new(), Vec::new())));
+
+        // CH1 could not be read, so its bus may well still be there
+        let unread = UnavailableConnector {
+            charger_id:   String::from("CH1"),
+            connector_id: 1,
+            reason:       Error::Transport { context: String::from("unable to fetch meter values"), message: String::new(), timed_out: true },
+        };
+        assert!(!sessions_changed(&planned, &snapshot(Vec::new(), vec![unread])));
+    }
 }
@@ -0,0 +1,326 @@
+use serde::{Deserialize, Serialize};
+use crate::{phases::Phase, types::MeterValue};
+
+// OCPP 1.6 SampledValue, one reading within a meter value. Every field but
+// the value is optional in OCPP and falls back to the default the spec gives.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SampledValue {
+    pub value:     String,
+    #[serde(default)]
+    pub context:   ReadingContext,
+    #[serde(default)]
+    pub format:    ValueFormat,
+    #[serde(default)]
+    pub measurand: Measurand,
+    pub phase:     Option<MeterPhase>,
+    #[serde(default)]
+    pub location:  Location,
+    #[serde(default)]
+    pub unit:      UnitOfMeasure,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Measurand {
+    #[serde(rename = "Current.Export")]
+    CurrentExport,
+    #[serde(rename = "Current.Import")]
+    CurrentImport,
+    #[serde(rename = "Current.Offered")]
+    CurrentOffered,
+    #[serde(rename = "Energy.Active.Export.Register")]
+    EnergyActiveExportRegister,
+    #[default]
+    #[serde(rename = "Energy.Active.Import.Register")]
+    EnergyActiveImportRegister,
+    #[serde(rename = "Energy.Reactive.Export.Register")]
+    EnergyReactiveExportRegister,
+    #[serde(rename = "Energy.Reactive.Import.Register")]
+    EnergyReactiveImportRegister,
+    #[serde(rename = "Energy.Active.Export.Interval")]
+    EnergyActiveExportInterval,
+    #[serde(rename = "Energy.Active.Import.Interval")]
+    EnergyActiveImportInterval,
+    #[serde(rename = "Energy.Reactive.Export.Interval")]
+    EnergyReactiveExportInterval,
+    #[serde(rename = "Energy.Reactive.Import.Interval")]
+    EnergyReactiveImportInterval,
+    Frequency,
+    #[serde(rename = "Power.Active.Export")]
+    PowerActiveExport,
+    #[serde(rename = "Power.Active.Import")]
+    PowerActiveImport,
+    #[serde(rename = "Power.Factor")]
+    PowerFactor,
+    #[serde(rename = "Power.Offered")]
+    PowerOffered,
+    #[serde(rename = "Power.Reactive.Export")]
+    PowerReactiveExport,
+    #[serde(rename = "Power.Reactive.Import")]
+    PowerReactiveImport,
+    #[serde(rename = "RPM")]
+    Rpm,
+    SoC,
+    Temperature,
+    Voltage,
+    #[serde(other)]
+    Unknown,   // vendor specific measurands are kept rather than failing the whole meter value
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnitOfMeasure {
+    #[default]
+    Wh,
+    #[serde(rename = "kWh")]
+    KWh,
+    #[serde(rename = "varh")]
+    Varh,
+    #[serde(rename = "kvarh")]
+    Kvarh,
+    W,
+    #[serde(rename = "kW")]
+    KW,
+    #[serde(rename = "VA")]
+    Va,
+    #[serde(rename = "kVA")]
+    Kva,
+    #[serde(rename = "var")]
+    Var,
+    #[serde(rename = "kvar")]
+    Kvar,
+    A,
+    V,
+    Celsius,
+    Fahrenheit,
+    K,
+    Percent,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadingContext {
+    #[serde(rename = "Interruption.Begin")]
+    InterruptionBegin,
+    #[serde(rename = "Interruption.End")]
+    InterruptionEnd,
+    Other,
+    #[serde(rename = "Sample.Clock")]
+    SampleClock,
+    #[default]
+    #[serde(rename = "Sample.Periodic")]
+    SamplePeriodic,
+    #[serde(rename = "Transaction.Begin")]
+    TransactionBegin,
+    #[serde(rename = "Transaction.End")]
+    TransactionEnd,
+    Trigger,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Location {
+    Body,
+    Cable,
+    #[serde(rename = "EV")]
+    Ev,
+    Inlet,
+    #[default]
+    Outlet,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueFormat {
+    #[default]
+    Raw,
+    SignedData,   // the value is a signed blob rather than a number
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum MeterPhase {
+    L1,
+    L2,
+    L3,
+    N,
+    #[serde(rename = "L1-N")]
+    L1N,
+    #[serde(rename = "L2-N")]
+    L2N,
+    #[serde(rename = "L3-N")]
+    L3N,
+    #[serde(rename = "L1-L2")]
+    L1L2,
+    #[serde(rename = "L2-L3")]
+    L2L3,
+    #[serde(rename = "L3-L1")]
+    L3L1,
+    #[serde(other)]
+    Unknown,
+}
+
+impl MeterPhase {
+    pub fn supply_phase(&self) -> Option<Phase> {
+        /*
+         * The supply phase a line or line to neutral reading was taken on.
+         */
+        match self {
+            MeterPhase::L1 | MeterPhase::L1N => Some(Phase::L1),
+            MeterPhase::L2 | MeterPhase::L2N => Some(Phase::L2),
+            MeterPhase::L3 | MeterPhase::L3N => Some(Phase::L3),
+            _ => None,
+        }
+    }
+}
+
+impl SampledValue {
+    pub fn normalized(&self) -> Option<f32> {
+        /*
+         * The reading converted to the base unit of its measurand: W, Wh, var,
+         * varh, VA, A, V, percent or degrees Celsius. None if the value is not a
+         * number, IE signed data.
+         */
+        if self.format == ValueFormat::SignedData {
+            return None;
+        }
+        let value = self.value.trim().parse::<f32>().ok()?;
+        Some(match self.unit {
+            UnitOfMeasure::KWh | UnitOfMeasure::Kvarh | UnitOfMeasure::KW | UnitOfMeasure::Kva | UnitOfMeasure::Kvar => value * 1000.0,
+            UnitOfMeasure::K => value - 273.15,
+            UnitOfMeasure::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            _ => value,
+        })
+    }
+}
+
+impl MeterValue {
+    fn reading(&self, measurand: Measurand) -> Option<f32> {
+        /*
+         * The normalized reading for a measurand taken across the whole connector,
+         * IE without a phase.
+         */
+        self.sampled_value
+            .iter()
+            .filter(|sample| sample.measurand == measurand && sample.phase.is_none())
+            .find_map(|sample| sample.normalized())
+    }
+
+    fn phase_readings(&self, measurand: Measurand) -> Vec<(Phase, f32)> {
+        self.sampled_value
+            .iter()
+            .filter(|sample| sample.measurand == measurand)
+            .filter_map(|sample| Some((sample.phase?.supply_phase()?, sample.normalized()?)))
+            .collect()
+    }
+
+    pub fn soc(&self) -> Option<f32> {
+        /*
+         * State of charge reported by the bus in percent.
+         */
+        self.reading(Measurand::SoC)
+    }
+
+    pub fn active_power_w(&self) -> Option<f32> {
+        /*
+         * Active power drawn in watts, summing the phases when no total is reported.
+         */
+        self.reading(Measurand::PowerActiveImport).or_else(|| {
+            let phases = self.phase_readings(Measurand::PowerActiveImport);
+            (!phases.is_empty()).then(|| phases.iter().map(|(_, power)| power).sum())
+        })
+    }
+
+    pub fn offered_power_w(&self) -> Option<f32> {
+        /*
+         * Maximum power the charger is offering the bus in watts.
+         */
+        self.reading(Measurand::PowerOffered)
+    }
+
+    pub fn energy_register_wh(&self) -> Option<f32> {
+        /*
+         * Energy import register in watt hours.
+         */
+        self.reading(Measurand::EnergyActiveImportRegister)
+    }
+
+    pub fn voltage_v(&self) -> Option<f32> {
+        /*
+         * Voltage in volts, averaging the phases when no single reading is reported.
+         */
+        self.reading(Measurand::Voltage).or_else(|| {
+            let phases = self.phase_readings(Measurand::Voltage);
+            (!phases.is_empty()).then(|| phases.iter().map(|(_, voltage)| voltage).sum::<f32>() / phases.len() as f32)
+        })
+    }
+
+    pub fn current_a(&self) -> Vec<(Option<Phase>, f32)> {
+        /*
+         * Current drawn in amps, per phase when the charger reports phases.
+         */
+        let phases = self.phase_readings(Measurand::CurrentImport);
+        if phases.is_empty() {
+            self.reading(Measurand::CurrentImport).map(|current| (None, current)).into_iter().collect()
+        } else {
+            phases.into_iter().map(|(phase, current)| (Some(phase), current)).collect()
+        }
+    }
+
+    pub fn temperature_c(&self) -> Option<f32> {
+        /*
+         * Temperature in degrees Celsius, the connector body's if several locations report one.
+         */
+        self.sampled_value
+            .iter()
+            .filter(|sample| sample.measurand == Measurand::Temperature)
+            .max_by_key(|sample| sample.location == Location::Body)
+            .and_then(|sample| sample.normalized())
+    }
+
+    pub fn summary(&self) -> String {
+        /*
+         * One line with every normalized reading the meter value carries, for verbose logging.
+         */
+        let reading = |value: Option<f32>, unit: &str| value.map(|value| format!("{value:.1}{unit}")).unwrap_or_else(|| String::from("-"));
+        let currents: Vec<String> = self
+            .current_a()
+            .iter()
+            .map(|(phase, current)| match phase {
+                Some(phase) => format!("{phase:?} {current:.1}A"),
+                None => format!("{current:.1}A"),
+            })
+            .collect();
+
+        format!("SOC {}, power {} (offered {}), register {}, voltage {}, current [{}], temperature {}",
+            reading(self.soc(), "%"),
+            reading(self.active_power_w(), "W"),
+            reading(self.offered_power_w(), "W"),
+            reading(self.energy_register_wh(), "Wh"),
+            reading(self.voltage_v(), "V"),
+            currents.join(", "),
+            reading(self.temperature_c(), "C")
+        )
+    }
+
+    pub fn loaded_phase(&self) -> Option<Phase> {
+        /*
+         * The phase a single phase charger is drawing from. Only answers when
+         * exactly one phase carries more than 1A, chargers drawing from all
+         * three phases return None.
+         */
+        let loaded_phases: Vec<Phase> = self
+            .phase_readings(Measurand::CurrentImport)
+            .into_iter()
+            .filter(|(_, current)| *current > 1.0)
+            .map(|(phase, _)| phase)
+            .collect();
+
+        match loaded_phases.as_slice() {
+            [phase] => Some(*phase),
+            _ => None,
+        }
+    }
+}
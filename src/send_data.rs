@@ -1,19 +1,21 @@
-use reqwest::{Client, header::{AUTHORIZATION, HeaderValue}};
 use chrono::{Utc, DateTime};
-use serde_json::json;
-use crate::types::{ChargeProfile, ChargingBounds};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use crate::{chargerhub::ChargerHubApi, error::Error, profile_registry::OwnedProfile, types::{ChargeProfile, ChargingBounds}};
 
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const SEND_ATTEMPTS: u32 = 3;                          // tries before giving up on a profile
+const BACKOFF_BASE: Duration = Duration::from_secs(1);  // doubled after every failed try
+pub const RETRY_BUDGET: Duration = Duration::from_secs(15);  // backing off allowed across every send of one recalculation
 
 pub async fn create_charge_profile(
-    client: &Client, 
-    req_url: &str, 
-    connector_id: &i32, 
-    charger_id: &String, 
-    charge_rate: &mut f32,
+    hub: &impl ChargerHubApi,
+    profile: &OwnedProfile,
+    start_periods: Vec<i32>,
+    mut charge_rates: Vec<f32>,
     valid_to: DateTime<Utc>,
-    verbose_mode: &bool,
     crg_bounds: ChargingBounds,
-    auth_key: &String) -> ChargeProfile
+    retry_until: Instant) -> Result<ChargeProfile, Error>
 {
     /*
      * Create and send a charge profile to chargerhub which will
@@ -36,58 +38,47 @@ pub async fn create_charge_profile(
     start_schedule: Option<DateTime<Utc>>
     */
     
-    // clamp charge rate between upper and lower bound
-    if *charge_rate < crg_bounds.lower_bnd as f32 {
-        *charge_rate = crg_bounds.lower_bnd as f32;
-    }
+    // clamp charge rates between upper and lower bound
+    for charge_rate in charge_rates.iter_mut() {
+        if *charge_rate < crg_bounds.lower_bnd as f32 {
+            *charge_rate = crg_bounds.lower_bnd as f32;
+        }
 
-    if *charge_rate > crg_bounds.upper_bnd as f32{
-        *charge_rate = crg_bounds.upper_bnd as f32;
+        if *charge_rate > crg_bounds.upper_bnd as f32{
+            *charge_rate = crg_bounds.upper_bnd as f32;
+        }
     }
 
 
-    // We need to get the connector id from the transaction
-
-    let mut url: String = req_url.to_owned();
-        url.push_str(&format!("/command/{}/set-charge-profile", charger_id));
-
-    let charge_profile = &json!({
-                "connector_id": connector_id,
-                "start_periods": [0],
-                "stack_level": 0,
-                "charge_rates": [charge_rate],
-                "purpose": "TxDefaultProfile",
-                "valid_to": valid_to,
-                "start_schedule": Utc::now(),
-            });
+    let charge_profile = ChargeProfile {
+        charger_id: profile.charger_id.clone(),
+        connector_id: profile.connector_id,
+        transaction_id: profile.transaction_id,
+        start_periods,
+        stack_level: profile.stack_level,
+        charge_rates,
+        purpose: String::from("TxProfile"),
+        start_schedule: Utc::now(),
+    };
 
-    if *verbose_mode{
-        println!("charge profile created: {}", charge_profile);
+    // Retry with backoff while the failure is chargerhub's rather than the charger's answer. The
+    // profiles are sent one after another, so retries stop at retry_until rather than letting one
+    // flaky charger hold up the rest of the recalculation
+    let mut attempt = 1;
+    loop {
+        let result = hub.set_charge_profile(&charge_profile, valid_to).await;
+        let backoff = BACKOFF_BASE * 2u32.pow(attempt - 1);
+        match result {
+            Ok(()) => break,
+            Err(err) if err.is_retryable() && attempt < SEND_ATTEMPTS && Instant::now() + backoff <= retry_until => {
+                eprintln!("Sending the profile to {} - {} failed ({}), retrying in {}s",
+                    profile.charger_id, profile.connector_id, err, backoff.as_secs());
+                sleep(backoff).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
     }
 
-    // Create a HeaderValue for the Authorization header
-    let auth_header_value = HeaderValue::from_str(&format!("Bearer {}", auth_key))
-        .map_err(|err| {
-            eprintln!("Invalid header value: {}", err);
-        }).unwrap();
-
-
-
-    let _res = client
-        .post(url)
-        .header(AUTHORIZATION, auth_header_value)
-        .json(charge_profile)
-        .send()
-        .await;
-
-    ChargeProfile {
-        charger_id: charger_id.to_owned(),
-        connector_id: connector_id.to_owned(),
-        start_periods: [0],
-        stack_level: 0,
-        charge_rates: [charge_rate.to_owned()],
-        purpose: String::from("TxDefaultProfile"),
-        start_schedule: Utc::now(),
-    }
+    Ok(charge_profile)
 }
-
@@ -0,0 +1,134 @@
+use chrono::{DateTime, Utc};
+use crate::{roster::{Roster, Vehicle}, types::{ChargingPolicy, MeterValue, Transaction}};
+
+// An active charging session: a transaction joined with the newest meter
+// value reported for it and the bus it was started by
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub charger_id:     String,
+    pub connector_id:   i32,
+    pub transaction_id: i32,
+    pub id_tag:         String,
+    pub vehicle:        Option<Vehicle>,   // None if the id tag is not in the roster
+    pub started_at:     DateTime<Utc>,
+    pub meter_start:    i32,               // energy register at the start of the transaction in Wh
+    pub meter_value:    MeterValue,        // newest meter value of the transaction
+    pub latest_soc:     Option<f32>,       // SOC reported in the newest meter value
+}
+
+impl Session {
+    pub fn new(meter_value: MeterValue, transaction: Transaction, roster: &Roster) -> Session {
+        Session {
+            charger_id:     meter_value.charger_id.clone(),
+            connector_id:   meter_value.connector_id,
+            transaction_id: meter_value.transaction_id,
+            vehicle:        roster.vehicle(&transaction.id_tag).cloned(),
+            id_tag:         transaction.id_tag,
+            started_at:     transaction.timestamp_start,
+            meter_start:    transaction.meter_start,
+            latest_soc:     meter_value.soc(),
+            meter_value,
+        }
+    }
+
+    pub fn battery_capacity(&self, roster: &Roster, default_capacity: i32) -> i32 {
+        /*
+         * Battery capacity of the bus in KwH, from its vehicle type in the
+         * roster or the configured default.
+         */
+        self.vehicle
+            .as_ref()
+            .and_then(|vehicle| roster.vehicle_type(vehicle))
+            .map(|vehicle_type| vehicle_type.battery_capacity)
+            .unwrap_or(default_capacity)
+    }
+
+    pub fn delivered_wh(&self) -> Option<f32> {
+        /*
+         * Energy delivered since the transaction started, the register reading
+         * less the transaction's meter_start.
+         */
+        self.meter_value
+            .energy_register_wh()
+            .map(|register_wh| register_wh - self.meter_start as f32)
+    }
+
+    pub fn energy_needed_wh(&self, policy: &ChargingPolicy) -> Option<f32> {
+        /*
+         * Energy still to deliver to a bus which does not report SOC. The target
+         * is the bus' own from the roster where the policy uses roster targets,
+         * otherwise the policy's typical energy, less what has been delivered.
+         */
+        let target_kwh = match self.vehicle.as_ref().and_then(|vehicle| vehicle.target_kwh) {
+            Some(target_kwh) if policy.roster_targets => Some(target_kwh),
+            _ => policy.typical_kwh,
+        }?;
+        Some((target_kwh * 1000.0 - self.delivered_wh()?).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{roster::VehicleType, types::ChargingBounds};
+
+    fn roster() -> Roster {
+        Roster {
+            vehicle_types: vec![VehicleType { name: String::from("40ft"), battery_capacity: 440, precondition_minutes: 0, precondition_kw: 0.0 }],
+            vehicles:      vec![Vehicle { id_tag: String::from("BUS1"), vehicle_type: String::from("40ft"), departure: None, target_kwh: Some(200.0) }],
+        }
+    }
+
+    fn policy(typical_kwh: Option<f32>, roster_targets: bool) -> ChargingPolicy {
+        ChargingPolicy {
+            name:           String::from("overnight"),
+            start_hour:     20,
+            stop_hour:      6,
+            desired_soc:    100,
+            bounds:         ChargingBounds { lower_bnd: 2000, upper_bnd: 150_000 },
+            typical_kwh,
+            roster_targets,
+        }
+    }
+
+    fn session(id_tag: &str, sampled_value: serde_json::Value) -> Session {
+        let meter_value: MeterValue = serde_json::from_value(serde_json::json!({
+            "connector_id": 1,
+            "charger_id": "CH1",
+            "transaction_id": 7,
+            "time_stamp": "2026-10-18T22:00:00Z",
+            "sampled_value": sampled_value,
+        })).unwrap();
+        let transaction: Transaction = serde_json::from_value(serde_json::json!({
+            "connector_id": 1,
+            "id_tag": id_tag,
+            "meter_start": 10_000,
+            "timestamp_start": "2026-10-18T20:00:00Z",
+            "transaction_id": 7,
+        })).unwrap();
+        Session::new(meter_value, transaction, &roster())
+    }
+
+    #[test]
+    fn buses_without_soc_are_charged_towards_an_energy_target() {
+        let register = serde_json::json!([{ "value": "50", "measurand": "Energy.Active.Import.Register", "unit": "kWh" }]);
+        let known = session("BUS1", register.clone());
+        assert_eq!(known.delivered_wh(), Some(40_000.0));
+        assert_eq!(known.battery_capacity(&roster(), 588), 440);
+
+        //The roster's target wins where the policy uses it, otherwise the typical energy applies
+        assert_eq!(known.energy_needed_wh(&policy(Some(150.0), true)), Some(160_000.0));
+        assert_eq!(known.energy_needed_wh(&policy(Some(150.0), false)), Some(110_000.0));
+        assert_eq!(known.energy_needed_wh(&policy(Some(30.0), false)), Some(0.0));
+        assert_eq!(known.energy_needed_wh(&policy(None, false)), None);
+
+        let unknown = session("BUS9", register);
+        assert_eq!(unknown.battery_capacity(&roster(), 588), 588);
+        assert_eq!(unknown.energy_needed_wh(&policy(Some(150.0), true)), Some(110_000.0));
+
+        //Without an energy register nothing can be counted
+        let unmetered = session("BUS1", serde_json::json!([{ "value": "40000", "measurand": "Power.Active.Import", "unit": "W" }]));
+        assert_eq!(unmetered.delivered_wh(), None);
+        assert_eq!(unmetered.energy_needed_wh(&policy(Some(150.0), true)), None);
+    }
+}
@@ -0,0 +1,210 @@
+use reqwest::Client;
+use std::time::Duration;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, time::timeout};
+use crate::error::Error;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Where the site main meter is read from. A simulator can stand in for either
+// source by serving the same JSON document or Modbus holding registers.
+#[derive(Debug, Clone)]
+pub enum SiteMeterReader {
+    HttpJson {
+        client:  Client,   // the meter's own client, chargerhub's authorization header is never sent to it
+        url:     String,
+        pointer: String,   // JSON pointer to the reading, IE /power_w
+        scale:   f32,      // watts per unit of the reading
+    },
+    ModbusTcp {
+        addr:     String,  // host:port of the meter or gateway
+        unit_id:  u8,
+        register: u16,     // first holding register of the reading
+        count:    u16,     // 1 for a 16 bit reading, 2 for a 32 bit reading (high word first)
+        scale:    f32,     // watts per count
+    },
+}
+
+impl SiteMeterReader {
+    pub async fn read_w(&self) -> Result<f32, Error> {
+        /*
+         * Read the meter's current active power in watts.
+         */
+        match self {
+            SiteMeterReader::HttpJson { client, url, pointer, scale } => {
+                let context = format!("unable to read site meter at {url}");
+                let body: serde_json::Value = timeout(READ_TIMEOUT, async {
+                    client.get(url).send().await?.error_for_status()?.json().await
+                })
+                .await
+                .map_err(|_| timed_out(&context))?
+                .map_err(|err| Error::from_reqwest(&context, err))?;
+
+                let reading = body
+                    .pointer(pointer)
+                    .and_then(|value| value.as_f64().or_else(|| value.as_str().and_then(|s| s.parse().ok())))
+                    .ok_or_else(|| Error::NoData(format!("site meter response has no number at {pointer}: {body}")))?;
+                Ok(reading as f32 * scale)
+            }
+            SiteMeterReader::ModbusTcp { addr, unit_id, register, count, scale } => {
+                let context = format!("unable to read site meter at {addr}");
+                let registers = timeout(READ_TIMEOUT, read_holding_registers(addr, *unit_id, *register, *count))
+                    .await
+                    .map_err(|_| timed_out(&context))?
+                    .map_err(|err| Error::Transport { context, message: err.to_string(), timed_out: false })?;
+
+                let reading = match registers.as_slice() {
+                    [value] => *value as i16 as f32,
+                    [high, low] => (((*high as u32) << 16) | *low as u32) as i32 as f32,
+                    _ => return Err(Error::Config(format!("unsupported register count {count} for site meter at {addr}"))),
+                };
+                Ok(reading * scale)
+            }
+        }
+    }
+}
+
+fn timed_out(context: &str) -> Error {
+    Error::Transport {
+        context:   context.to_owned(),
+        message:   format!("no answer after {}s", READ_TIMEOUT.as_secs()),
+        timed_out: true,
+    }
+}
+
+
+async fn read_holding_registers(addr: &str, unit_id: u8, register: u16, count: u16) -> std::io::Result<Vec<u16>> {
+    /*
+     * Minimal Modbus TCP client for function 0x03 (read holding registers).
+     *
+     * Request:  transaction id (2) | protocol id (2) | length (2) | unit id (1) | function (1) | address (2) | count (2)
+     * Response: transaction id (2) | protocol id (2) | length (2) | unit id (1) | function (1) | byte count (1) | registers
+     */
+    let mut stream = TcpStream::connect(addr).await?;
+
+    let mut request = Vec::with_capacity(12);
+    request.extend_from_slice(&1u16.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&6u16.to_be_bytes());
+    request.push(unit_id);
+    request.push(0x03);
+    request.extend_from_slice(&register.to_be_bytes());
+    request.extend_from_slice(&count.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 9];
+    stream.read_exact(&mut header).await?;
+    if header[7] & 0x80 != 0 {
+        return Err(std::io::Error::other(format!("modbus exception code {}", header[8])));
+    }
+
+    let mut data = vec![0u8; header[8] as usize];
+    stream.read_exact(&mut data).await?;
+    if data.len() != count as usize * 2 {
+        return Err(std::io::Error::other(format!("expected {} registers, got {} bytes", count, data.len())));
+    }
+
+    Ok(data
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect())
+}
+
+// Settings for limiting charging to whatever the building leaves of the utility service
+pub struct SiteMeterConfig {
+    pub reader:            SiteMeterReader,
+    pub service_limit_w:   f32,
+    pub margin_w:          f32,
+    pub includes_chargers: bool,     // the meter reads the whole service, chargers included
+    pub poll_interval:     Duration,
+    pub spike_w:           f32,      // rise in building load which triggers a re-plan right away
+}
+
+impl SiteMeterConfig {
+    pub fn headroom_w(&self, building_load_w: f32) -> f32 {
+        (self.service_limit_w - building_load_w - self.margin_w).max(0.0)
+    }
+
+    pub fn building_load_w(&self, reading_w: f32, charger_load_w: f32) -> f32 {
+        /*
+         * Separate the building's load out of a meter reading. When the meter
+         * reads the whole service the chargers' last measured draw is removed.
+         */
+        if self.includes_chargers {
+            (reading_w - charger_load_w).max(0.0)
+        } else {
+            reading_w
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::test_http;
+
+    fn site_meter(includes_chargers: bool) -> SiteMeterConfig {
+        SiteMeterConfig {
+            reader:            SiteMeterReader::ModbusTcp { addr: String::new(), unit_id: 1, register: 0, count: 1, scale: 1.0 },
+            service_limit_w:   500_000.0,
+            margin_w:          20_000.0,
+            includes_chargers,
+            poll_interval:     Duration::from_secs(30),
+            spike_w:           50_000.0,
+        }
+    }
+
+    async fn modbus_meter(answer: &'static [u8]) -> String {
+        // a meter answering one read of holding registers 40-41 on unit 3 with the given function code and data
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 12];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[6..], [3, 0x03, 0, 40, 0, 2]);
+            let mut response = vec![0, 1, 0, 0, 0, 1 + answer.len() as u8, 3];
+            response.extend_from_slice(answer);
+            stream.write_all(&response).await.unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn headroom_is_what_the_building_leaves() {
+        let meter = site_meter(true);
+        assert_eq!(meter.headroom_w(300_000.0), 180_000.0);
+        assert_eq!(meter.headroom_w(490_000.0), 0.0);
+
+        //A meter reading the whole service has the chargers' draw taken back out of it
+        assert_eq!(meter.building_load_w(400_000.0, 150_000.0), 250_000.0);
+        assert_eq!(meter.building_load_w(100_000.0, 150_000.0), 0.0);
+        assert_eq!(site_meter(false).building_load_w(400_000.0, 150_000.0), 400_000.0);
+    }
+
+    #[tokio::test]
+    async fn http_meters_are_read_at_their_pointer() {
+        let (url, received) = test_http::serve(|_| (200, String::from(r#"{"site": {"power_kw": "12.5", "status": "ok"}}"#))).await;
+        let reader = |pointer: &str| SiteMeterReader::HttpJson { client: Client::new(), url: url.clone(), pointer: pointer.to_owned(), scale: 1000.0 };
+
+        assert_eq!(reader("/site/power_kw").read_w().await.unwrap(), 12_500.0);
+        assert!(matches!(reader("/site/status").read_w().await, Err(Error::NoData(_))));
+        assert!(matches!(reader("/site/missing").read_w().await, Err(Error::NoData(_))));
+        assert!(received.lock().unwrap().iter().all(|request| request.method == "GET" && request.target == "/" && request.body.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn modbus_meters_are_read_from_holding_registers() {
+        let reader = |addr: String| SiteMeterReader::ModbusTcp { addr, unit_id: 3, register: 40, count: 2, scale: 10.0 };
+
+        //32 bit readings are signed with the high word first
+        let addr = modbus_meter(&[0x03, 4, 0x00, 0x01, 0x00, 0x02]).await;
+        assert_eq!(reader(addr).read_w().await.unwrap(), 655_380.0);
+        let addr = modbus_meter(&[0x03, 4, 0xFF, 0xFF, 0xFF, 0xFE]).await;
+        assert_eq!(reader(addr).read_w().await.unwrap(), -20.0);
+
+        //Modbus exceptions are errors
+        let addr = modbus_meter(&[0x83, 2]).await;
+        assert!(reader(addr).read_w().await.is_err());
+    }
+}
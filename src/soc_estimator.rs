@@ -0,0 +1,177 @@
+use chrono::{DateTime, Utc};
+use std::{collections::HashMap, fmt};
+
+const ENERGY_ERROR: f32 = 0.05; // fraction of the counted SOC added to an estimate's uncertainty
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocSource {
+    Measured,
+    Estimated { uncertainty: f32 },  // +/- percentage points
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SocReading {
+    pub soc:    f32,
+    pub source: SocSource,
+}
+
+impl SocReading {
+    pub fn planning_soc(&self) -> i8 {
+        /*
+         * SOC handed to the planner. Estimates are taken at the low end of their
+         * uncertainty so a drifting estimate errs towards charging more.
+         */
+        let soc = match self.source {
+            SocSource::Measured => self.soc,
+            SocSource::Estimated { uncertainty } => self.soc - uncertainty,
+        };
+        soc.clamp(0.0, 100.0).round() as i8
+    }
+}
+
+impl fmt::Display for SocReading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            SocSource::Measured => write!(f, "{:.1}% (measured)", self.soc),
+            SocSource::Estimated { uncertainty } => write!(f, "{:.1}% (estimated +/-{:.1}%)", self.soc, uncertainty),
+        }
+    }
+}
+
+// Last measured SOC of a session and the energy register when it was measured
+struct SocAnchor {
+    soc:       f32,
+    energy_wh: f32,
+    at:        DateTime<Utc>,
+}
+
+// Tracks SOC between sparse readings by counting the energy delivered since the
+// last reading. The estimate's uncertainty grows with time since that reading
+// and with the energy counted, and it is dropped once it grows too wide.
+pub struct SocEstimator {
+    sessions:        HashMap<i32, SocAnchor>,  // keyed by transaction id
+    efficiency:      f32,                      // fraction of metered energy which reaches the battery
+    drift_per_hour:  f32,                      // uncertainty added per hour since the last reading
+    max_uncertainty: f32,                      // estimates wider than this are not used
+}
+
+impl SocEstimator {
+    pub fn new(efficiency: f32, drift_per_hour: f32, max_uncertainty: f32) -> SocEstimator {
+        SocEstimator {
+            sessions: HashMap::new(),
+            efficiency,
+            drift_per_hour,
+            max_uncertainty,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        transaction_id: i32,
+        measured_soc: Option<f32>,
+        energy_wh: Option<f32>,
+        battery_capacity: i32,
+        right_now: DateTime<Utc>) -> Option<SocReading>
+    {
+        /*
+         * Record a measured SOC, or estimate one from the last measured SOC and
+         * the energy delivered since.
+         *
+         * @Input:  transaction_id   - session the meter value belongs to
+         *          measured_soc     - SOC reported in the meter value, if any
+         *          energy_wh        - energy import register in watt hours, if any
+         *          battery_capacity - capacity of the bus' battery in KwH
+         *          right_now        - time the meter value was taken
+         *
+         * @Output: The measured or estimated SOC, None if neither is available
+         */
+        if let Some(soc) = measured_soc {
+            if let Some(energy_wh) = energy_wh {
+                self.sessions.insert(transaction_id, SocAnchor { soc, energy_wh, at: right_now });
+            }
+            return Some(SocReading { soc, source: SocSource::Measured });
+        }
+
+        let anchor = self.sessions.get(&transaction_id)?;
+        let energy_wh = energy_wh?;
+
+        let added_soc = ((energy_wh - anchor.energy_wh).max(0.0) * self.efficiency) / (battery_capacity as f32 * 1000.0) * 100.0;
+        let hours = (right_now - anchor.at).num_seconds().max(0) as f32 / 3600.0;
+        let uncertainty = hours * self.drift_per_hour + added_soc * ENERGY_ERROR;
+
+        if uncertainty > self.max_uncertainty {
+            return None;
+        }
+
+        Some(SocReading {
+            soc: (anchor.soc + added_soc).min(100.0),
+            source: SocSource::Estimated { uncertainty },
+        })
+    }
+
+    pub fn retain_sessions(&mut self, active_transactions: &[i32]) {
+        /*
+         * Forget sessions which are no longer active.
+         */
+        self.sessions.retain(|transaction_id, _| active_transactions.contains(transaction_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn soc_is_counted_forward_from_the_last_reading() {
+        let mut estimator = SocEstimator::new(0.9, 2.0, 10.0);
+        let measured_at = Utc::now();
+
+        //Nothing can be estimated before the first reading
+        assert!(estimator.update(7, None, Some(100_000.0), 400, measured_at).is_none());
+
+        let measured = estimator.update(7, Some(40.0), Some(100_000.0), 400, measured_at).unwrap();
+        assert_eq!(measured.source, SocSource::Measured);
+        assert_eq!(measured.planning_soc(), 40);
+
+        //40kWh later, 90% of it reaches the 400kWh battery: 9 points on top of 40. Two hours of drift
+        //and 5% of the counted SOC make it +/-4.45 points
+        let estimate = estimator.update(7, None, Some(140_000.0), 400, measured_at + Duration::hours(2)).unwrap();
+        assert!((estimate.soc - 49.0).abs() < 0.01);
+        let SocSource::Estimated { uncertainty } = estimate.source else { panic!("expected an estimate, got {estimate:?}") };
+        assert!((uncertainty - 4.45).abs() < 0.01);
+        //Planning takes the low end of the estimate
+        assert_eq!(estimate.planning_soc(), 45);
+
+        //A new reading moves the anchor
+        estimator.update(7, Some(60.0), Some(150_000.0), 400, measured_at + Duration::hours(3));
+        let estimate = estimator.update(7, None, Some(150_000.0), 400, measured_at + Duration::hours(3)).unwrap();
+        assert_eq!(estimate.soc, 60.0);
+    }
+
+    #[test]
+    fn estimates_are_dropped_once_they_grow_too_wide() {
+        let mut estimator = SocEstimator::new(0.9, 2.0, 10.0);
+        let measured_at = Utc::now();
+        estimator.update(7, Some(40.0), Some(100_000.0), 400, measured_at);
+
+        assert!(estimator.update(7, None, Some(100_000.0), 400, measured_at + Duration::hours(4)).is_some());
+        assert!(estimator.update(7, None, Some(100_000.0), 400, measured_at + Duration::hours(6)).is_none());
+        //A meter value without the energy register gives nothing to count
+        assert!(estimator.update(7, None, None, 400, measured_at).is_none());
+
+        //Sessions which ended are forgotten
+        estimator.retain_sessions(&[8]);
+        assert!(estimator.update(7, None, Some(100_000.0), 400, measured_at).is_none());
+    }
+
+    #[test]
+    fn estimates_never_pass_a_full_battery() {
+        let mut estimator = SocEstimator::new(1.0, 0.0, 50.0);
+        let measured_at = Utc::now();
+        estimator.update(7, Some(95.0), Some(0.0), 100, measured_at);
+
+        let estimate = estimator.update(7, None, Some(20_000.0), 100, measured_at).unwrap();
+        assert_eq!(estimate.soc, 100.0);
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+// A request the test server received
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub method: String,
+    pub target: String,   // path and query string
+    pub body:   String,
+}
+
+pub async fn serve(answer: fn(&Received) -> (u16, String)) -> (String, Arc<Mutex<Vec<Received>>>) {
+    /*
+     * An HTTP server on a local port for tests, answering every request with
+     * the status and body `answer` gives for it and recording what it received.
+     *
+     * @Output: the server's base URL and the requests received so far
+     */
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let recording = received.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            let (head_len, content_length) = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") else { continue };
+                let content_length = String::from_utf8_lossy(&request[..end])
+                    .to_lowercase()
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:").map(|length| length.trim().parse().unwrap()))
+                    .unwrap_or(0);
+                break (end + 4, content_length);
+            };
+            while request.len() < head_len + content_length {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+            let mut request_line = head.split_whitespace();
+            let received = Received {
+                method: request_line.next().unwrap_or_default().to_owned(),
+                target: request_line.next().unwrap_or_default().to_owned(),
+                body:   String::from_utf8_lossy(&request[head_len..]).into_owned(),
+            };
+            let (status, body) = answer(&received);
+            recording.lock().unwrap().push(received);
+            let response = format!(
+                "HTTP/1.1 {status} Test\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    (base_url, received)
+}
@@ -0,0 +1,220 @@
+use serde::Deserialize;
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use crate::{error::Error, planner::{fit_plans, ChargePlan}};
+
+// A transformer, panel, breaker or charger in the depot's electrical tree.
+// Chargers are nodes whose id is the charger's id.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TopologyNode {
+    pub id:         String,
+    pub parent:     Option<String>,
+    pub limit_kw:   Option<f32>,
+    pub limit_amps: Option<f32>,
+    pub voltage:    Option<f32>,   // line to line voltage for three phase nodes
+    #[serde(default = "default_phases")]
+    pub phases:     u8,
+}
+
+fn default_phases() -> u8 {
+    3
+}
+
+impl TopologyNode {
+    pub fn limit_w(&self) -> Option<f32> {
+        /*
+         * The tighter of the node's kW rating and its amp rating converted to
+         * watts (P = sqrt(3) * V * I for three phase, V * I for single phase).
+         */
+        let amp_limit_w = match (self.limit_amps, self.voltage) {
+            (Some(amps), Some(voltage)) if self.phases == 3 => Some(3f32.sqrt() * voltage * amps),
+            (Some(amps), Some(voltage)) => Some(voltage * amps),
+            _ => None,
+        };
+        let kw_limit_w = self.limit_kw.map(|limit_kw| limit_kw * 1000.0);
+
+        match (kw_limit_w, amp_limit_w) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (limit, None) | (None, limit) => limit,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Topology {
+    pub nodes: Vec<TopologyNode>,
+}
+
+impl Topology {
+    pub fn load(path: &str) -> Result<Topology, Error> {
+        /*
+         * Read the electrical tree from a JSON file and make sure it is actually
+         * a tree: ids are unique, every parent exists and there are no cycles.
+         */
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("Unable to read topology file {path}: {err}")))?;
+        let topology: Topology = serde_json::from_str(&contents)
+            .map_err(|err| Error::Config(format!("Unable to parse topology file {path}: {err}")))?;
+
+        let nodes: HashMap<&str, &TopologyNode> = topology.nodes.iter().map(|node| (node.id.as_str(), node)).collect();
+        if nodes.len() != topology.nodes.len() {
+            return Err(Error::Config(format!("Topology file {path} contains duplicate node ids")));
+        }
+        for node in &topology.nodes {
+            if let Some(parent) = &node.parent {
+                if !nodes.contains_key(parent.as_str()) {
+                    return Err(Error::Config(format!("Topology node {} in {path} references unknown parent {}", node.id, parent)));
+                }
+            }
+            if topology.path(&node.id).len() > topology.nodes.len() {
+                return Err(Error::Config(format!("Topology node {} in {path} is part of a cycle", node.id)));
+            }
+        }
+        Ok(topology)
+    }
+
+    fn node(&self, id: &str) -> Option<&TopologyNode> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    pub fn path(&self, charger_id: &str) -> Vec<&TopologyNode> {
+        /*
+         * Every node between the charger and the root of the tree, starting with
+         * the charger itself. Stops early if the walk exceeds the number of nodes
+         * so a cycle cannot hang the loop.
+         */
+        let mut path = Vec::new();
+        let mut next = self.node(charger_id);
+        while let Some(node) = next {
+            path.push(node);
+            if path.len() > self.nodes.len() {
+                break;
+            }
+            next = node.parent.as_deref().and_then(|parent| self.node(parent));
+        }
+        path
+    }
+
+    pub fn fit(&self, plans: &mut [ChargePlan], right_now: DateTime<Local>, stop_time: DateTime<Local>, floor: f32, verbose_mode: &bool) {
+        /*
+         * Make sure no node in the tree is overloaded. Nodes are visited from the
+         * deepest up so chargers and panels are brought under their own limits
+         * before their transformer is checked; scaling only ever lowers rates so
+         * a node which fits stays fitting as its ancestors are processed.
+         */
+        let paths: Vec<Vec<&str>> = plans
+            .iter()
+            .map(|plan| self.path(&plan.charger_id).iter().map(|node| node.id.as_str()).collect())
+            .collect();
+
+        for (plan, path) in plans.iter().zip(&paths) {
+            if path.is_empty() {
+                eprintln!("Charger {} is not in the topology, only the site limit applies to it", plan.charger_id);
+            }
+        }
+
+        let mut nodes: Vec<(&TopologyNode, usize)> = self.nodes.iter().map(|node| (node, self.path(&node.id).len())).collect();
+        nodes.sort_by_key(|(_, depth)| std::cmp::Reverse(*depth));
+
+        for (node, _) in nodes {
+            let Some(limit_w) = node.limit_w() else { continue };
+
+            let members: Vec<usize> = (0..plans.len())
+                .filter(|index| paths[*index].contains(&node.id.as_str()))
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+
+            let reserved_w: f32 = members.iter().map(|index| plans[*index].reserved_w(right_now, stop_time)).sum();
+            if fit_plans(plans, &members, limit_w - reserved_w, floor) && *verbose_mode {
+                println!("topology node {} limited to {}W ({}W reserved for pre-conditioning)", node.id, limit_w, reserved_w);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, parent: Option<&str>, limit_kw: f32) -> TopologyNode {
+        TopologyNode { id: id.to_owned(), parent: parent.map(str::to_owned), limit_kw: Some(limit_kw), limit_amps: None, voltage: None, phases: 3 }
+    }
+
+    fn plan(charger_id: &str, charge_rate: f32) -> ChargePlan {
+        ChargePlan { charger_id: charger_id.to_owned(), charge_rate, ..Default::default() }
+    }
+
+    fn depot() -> Topology {
+        // CH1 and CH2 share a 60kW panel, CH3 hangs off the 75kW transformer on its own
+        Topology {
+            nodes: vec![
+                node("transformer", None, 75.0),
+                node("panel", Some("transformer"), 60.0),
+                node("CH1", Some("panel"), 150.0),
+                node("CH2", Some("panel"), 150.0),
+                node("CH3", Some("transformer"), 30.0),
+            ],
+        }
+    }
+
+    fn rates(plans: &[ChargePlan]) -> Vec<f32> {
+        plans.iter().map(|plan| (plan.charge_rate / 10.0).round() * 10.0).collect()
+    }
+
+    #[test]
+    fn amp_ratings_are_converted_to_watts() {
+        let mut panel = TopologyNode { id: String::from("panel"), parent: None, limit_kw: None, limit_amps: Some(400.0), voltage: Some(480.0), phases: 3 };
+        assert!((panel.limit_w().unwrap() - 332_554.0).abs() < 1.0);
+        panel.phases = 1;
+        assert_eq!(panel.limit_w(), Some(192_000.0));
+        //The tighter of the two ratings applies
+        panel.limit_kw = Some(150.0);
+        assert_eq!(panel.limit_w(), Some(150_000.0));
+        panel.voltage = None;
+        assert_eq!(panel.limit_w(), Some(150_000.0));
+    }
+
+    #[test]
+    fn topology_files_must_be_trees() {
+        let example = Topology::load(concat!(env!("CARGO_MANIFEST_DIR"), "/exampleTopology.json")).unwrap();
+        let path: Vec<&str> = example.path("CH1").iter().map(|node| node.id.as_str()).collect();
+        assert_eq!(path, vec!["CH1", "panel-1a", "transformer-1"]);
+
+        let file = std::env::temp_dir().join(format!("busCurtailment-topology-{}.json", std::process::id()));
+        let load = |nodes: &str| {
+            std::fs::write(&file, format!("{{\"nodes\": [{nodes}]}}")).unwrap();
+            Topology::load(file.to_str().unwrap())
+        };
+        assert!(load(r#"{"id": "CH1", "parent": "missing"}"#).is_err());
+        assert!(load(r#"{"id": "CH1", "parent": null}, {"id": "CH1", "parent": null}"#).is_err());
+        assert!(load(r#"{"id": "a", "parent": "b"}, {"id": "b", "parent": "a"}"#).is_err());
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn every_node_is_kept_under_its_limit() {
+        let right_now = Local::now();
+        let stop_time = right_now + chrono::Duration::hours(6);
+        let mut plans = vec![plan("CH1", 50_000.0), plan("CH2", 50_000.0), plan("CH3", 50_000.0), plan("CH4", 50_000.0)];
+
+        //CH3 is held to its own 30kW and CH1 and CH2 to 30kW each under the panel, which leaves
+        //the transformer 15kW over. CH4 is not in the tree and keeps its rate
+        depot().fit(&mut plans, right_now, stop_time, 2000.0, &false);
+        assert_eq!(rates(&plans), vec![25_000.0, 25_000.0, 25_000.0, 50_000.0]);
+    }
+
+    #[test]
+    fn buses_short_of_their_deadline_keep_their_rate_while_others_make_room() {
+        let right_now = Local::now();
+        let stop_time = right_now + chrono::Duration::hours(6);
+        let mut plans = vec![plan("CH1", 50_000.0), plan("CH2", 50_000.0), plan("CH3", 50_000.0)];
+        plans[0].infeasible = true;
+
+        depot().fit(&mut plans, right_now, stop_time, 2000.0, &false);
+        assert_eq!(rates(&plans), vec![50_000.0, 6_670.0, 18_330.0]);
+        let total: f32 = plans.iter().map(|plan| plan.charge_rate).sum();
+        assert!(total <= 75_000.0 + 1.0);
+    }
+}
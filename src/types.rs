@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use crate::sampled_value::SampledValue;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct MeterValue {
@@ -7,10 +8,10 @@ pub struct MeterValue {
     pub charger_id: String,
     pub transaction_id: i32,
     pub time_stamp: DateTime<Utc>,
-    pub sampled_value: serde_json::Value
+    pub sampled_value: Vec<SampledValue>
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Charger {
     pub id: String,
     pub charger_name: String,
@@ -21,18 +22,31 @@ pub struct Charger {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum CommunicationType {
     RustDirectOcpp,
     OpenAdrMicrogrid
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct ChargingBounds {
     pub lower_bnd: i32,
     pub upper_bnd: i32
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// A window of the day during which charging is curtailed towards its own SoC target
+#[derive(Debug, Clone)]
+pub struct ChargingPolicy {
+    pub name:           String,
+    pub start_hour:     u32,
+    pub stop_hour:      u32,
+    pub desired_soc:    i8,
+    pub bounds:         ChargingBounds,
+    pub typical_kwh:    Option<f32>,  // energy target for buses which do not report SOC
+    pub roster_targets: bool          // per vehicle energy targets from the roster apply in this window
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Transaction {
     pub connector_id:    i32,
     pub id_tag:          String,
@@ -46,13 +60,41 @@ pub struct Transaction {
     pub voided:          Option<bool>
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Transaction {
+    pub fn is_active(&self) -> bool {
+        // whether the transaction has not ended, IE it has no stop and was not voided
+        self.stop_reason.is_none() && self.timestamp_stop.is_none() && self.voided != Some(true)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChargeProfile {
     pub charger_id:     String,
     pub connector_id:   i32,
-    pub start_periods:  [i32; 1],
+    pub transaction_id: i32,
+    pub start_periods:  Vec<i32>,
     pub stack_level:    i32,
-    pub charge_rates:   [f32; 1],
+    pub charge_rates:   Vec<f32>,
     pub purpose:        String,
     pub start_schedule:     DateTime<Utc>
 }
+
+
+// Response to GetCompositeSchedule, the schedule a connector will actually
+// follow once every profile installed on it is combined
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CompositeSchedule {
+    pub status:            String,
+    pub charging_schedule: Option<ChargingSchedule>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChargingSchedule {
+    pub charging_schedule_period: Vec<ChargingSchedulePeriod>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChargingSchedulePeriod {
+    pub start_period: i32,   // seconds from the start of the schedule
+    pub limit:        f32,   // watts, the schedule is requested in W
+}
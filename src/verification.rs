@@ -0,0 +1,67 @@
+use crate::{
+    chargerhub::ChargerHubApi,
+    error::{ChargerStatus, Error},
+    profile_registry::OwnedProfile,
+    types::{ChargeProfile, CompositeSchedule}
+};
+
+// Settings for reading back the composite schedule after sending a profile
+pub struct ScheduleVerification {
+    pub tolerance_w: f32,   // difference allowed between the intended and applied rate
+    pub retries:     u32,   // times a mismatched profile is resent before raising an alert
+}
+
+#[derive(Debug)]
+pub enum Verification {
+    Matches,
+    Mismatch {
+        start_period: i32,
+        expected_w:   f32,
+        applied_w:    Option<f32>,   // None if the composite schedule has no limit at that point
+    },
+    Unavailable(Error),              // the schedule could not be read back, so nothing was compared
+}
+
+pub async fn verify_profile(hub: &impl ChargerHubApi, owned_profile: &OwnedProfile, sent: &ChargeProfile, duration: i32, tolerance_w: f32) -> Verification {
+    /*
+     * Read back the connector's composite schedule and check the charger is
+     * following the profile we sent it.
+     */
+    let composite = hub.composite_schedule(&owned_profile.charger_id, owned_profile.connector_id, duration).await;
+
+    match composite {
+        Ok(composite) => compare_schedule(sent, &composite, tolerance_w),
+        Err(err) => Verification::Unavailable(err),
+    }
+}
+
+fn compare_schedule(sent: &ChargeProfile, composite: &CompositeSchedule, tolerance_w: f32) -> Verification {
+    /*
+     * Compare every period of the sent profile with the composite period in
+     * effect at its start, IE the last composite period starting at or before it.
+     */
+    if composite.status != "Accepted" {
+        return Verification::Unavailable(Error::ChargerRejected(ChargerStatus::from_ocpp(&composite.status)));
+    }
+    let Some(schedule) = &composite.charging_schedule else {
+        return Verification::Unavailable(Error::NoData(String::from("composite schedule has no charging schedule")));
+    };
+
+    for (start_period, expected_w) in sent.start_periods.iter().zip(&sent.charge_rates) {
+        let applied_w = schedule
+            .charging_schedule_period
+            .iter()
+            .filter(|period| period.start_period <= *start_period)
+            .max_by_key(|period| period.start_period)
+            .map(|period| period.limit);
+
+        if applied_w.is_none_or(|applied_w| (applied_w - expected_w).abs() > tolerance_w) {
+            return Verification::Mismatch {
+                start_period: *start_period,
+                expected_w: *expected_w,
+                applied_w,
+            };
+        }
+    }
+    Verification::Matches
+}